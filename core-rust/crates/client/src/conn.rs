@@ -0,0 +1,254 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use crossbeam::channel::{Receiver, Sender, TryRecvError};
+use rsa::{RsaPrivateKey, traits::PublicKeyParts};
+use shared::{
+    config,
+    datatypes::{nodes::Node, treebuilder::TreeBuilder},
+    errors::Error,
+    remote::{
+        codec::FramedStream,
+        heartbeat::Heartbeat,
+        message::Message,
+        version::{Capabilities, REQUEST_VERSION},
+    },
+    security::{
+        exchange::ClientExchange,
+        handshake::{ClientHandshake, answer_challenge},
+    },
+};
+
+use crate::{
+    request::Reply,
+    tls::{self, Stream},
+};
+
+/// Messages from the [crate::Client] to its connection thread.
+pub(crate) enum Outgoing {
+    /// A request with its id, answered with the reply once the server answers it.
+    Request(u64, Message, Reply),
+    /// Proves the ownership of a key to the server, which then adds its permissions. Is a
+    /// request like [Outgoing::Request].
+    AddPermissions(Box<RsaPrivateKey>, u64, Reply),
+    Close,
+}
+
+/// What the server accepted the client with.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Accepted {
+    pub session: u64,
+    /// How long the session is valid in seconds.
+    pub validity: u16,
+    /// The highest version both speak, everything is sent in it.
+    pub version: u8,
+    /// The capabilities both support.
+    pub capabilities: Capabilities,
+}
+
+/// Does the key exchange with the server that has the identity key with [server_key]. Every
+/// frame after it is encrypted.
+pub(crate) fn exchange(stream: &mut FramedStream<Stream>, server_key: &str) -> Result<(), Error> {
+    let exchange = ClientExchange::new(server_key);
+    stream.send(&exchange.hello())?;
+    let channel = exchange.finish(stream.recv_timeout(config::HANDSHAKE_TIMEOUT)?)?;
+    stream.secure(channel);
+    Ok(())
+}
+
+/// Does the handshake with the server, in the highest version both speak.
+pub(crate) fn handshake(
+    stream: &mut FramedStream<Stream>,
+    key: Option<RsaPrivateKey>,
+) -> Result<Accepted, Error> {
+    let mut handshake = ClientHandshake::new(key, config::HANDSHAKE_TIMEOUT);
+    if let Some(binding) = stream.binding() {
+        handshake = handshake.bind(binding);
+    }
+    stream.send(&handshake.hello())?;
+    finish_handshake(stream, handshake)
+}
+
+/// Resumes [session] after the connection was lost, in the [version] the session was accepted
+/// with. Afterwards the server sends what the client missed since it had the tree with [hash].
+pub(crate) fn resume(
+    stream: &mut FramedStream<Stream>,
+    version: u8,
+    session: u64,
+    hash: u64,
+) -> Result<Accepted, Error> {
+    let mut handshake =
+        ClientHandshake::new(None, config::HANDSHAKE_TIMEOUT).versions(version, version);
+    stream.send(&handshake.resume(session, hash))?;
+    finish_handshake(stream, handshake)
+}
+
+fn finish_handshake(
+    stream: &mut FramedStream<Stream>,
+    mut handshake: ClientHandshake,
+) -> Result<Accepted, Error> {
+    loop {
+        let message = stream.recv_timeout(config::HANDSHAKE_TIMEOUT)?;
+        // Like saying hello again in an older version.
+        if let Some(answer) = handshake.handle(message)? {
+            stream.send(&answer)?;
+        }
+        if let Some((session, validity)) = handshake.accepted() {
+            stream.set_version(handshake.version());
+            return Ok(Accepted {
+                session,
+                validity,
+                version: handshake.version(),
+                capabilities: handshake.negotiated(),
+            });
+        }
+    }
+}
+
+/// Waits for the [Message::Snapshot] the server sends after accepting the client.
+pub(crate) fn initial_tree(stream: &mut FramedStream<Stream>) -> Result<Node, Error> {
+    match stream.recv_timeout(config::HANDSHAKE_TIMEOUT)? {
+        Message::Snapshot(snapshot) => Node::from_snapshot(&snapshot),
+        message => Err(Error::SimpleErrorStr(format!(
+            "Client: Expected a snapshot but got {:?}",
+            message
+        ))),
+    }
+}
+
+/// Passes messages between the [crate::Client] and the server until one of them stops.
+/// Changes of the server are applied to [tree].
+/// Returns an error if the connection is lost, like when the server closes it without a goodbye
+/// or misses its [heartbeat].
+pub(crate) fn run(
+    mut stream: FramedStream<Stream>,
+    tree: &Mutex<Node>,
+    from_client_r: &Receiver<Outgoing>,
+    logs_s: &Sender<String>,
+    mut heartbeat: Option<Heartbeat>,
+) -> Result<(), Error> {
+    // The key the server sends a challenge for next.
+    let mut pending_key: Option<RsaPrivateKey> = None;
+    // Set when a change could not be applied, so some were missed. Once the server is quiet the
+    // hash of the tree is sent and the server resends what is missing.
+    let mut out_of_sync = false;
+    // The requests the server did not answer yet.
+    let mut requests: HashMap<u64, Reply> = HashMap::new();
+
+    loop {
+        if let Some(heartbeat) = &mut heartbeat {
+            if heartbeat.is_dead() {
+                return Err(Error::SimpleError(
+                    "Heartbeat: Server missed its heartbeats",
+                ));
+            }
+            if let Some(ping) = heartbeat.ping() {
+                stream.send(&ping)?;
+            }
+        }
+
+        // first send everything the client wants to send.
+        loop {
+            match from_client_r.try_recv() {
+                Ok(Outgoing::Request(id, message, reply)) => {
+                    stream.send(&message)?;
+                    track(&mut requests, stream.version(), id, reply);
+                }
+                Ok(Outgoing::AddPermissions(key, id, reply)) => {
+                    stream.send(&Message::ClientAddPermissions(
+                        id,
+                        key.n().to_bytes_le(),
+                        key.e().to_bytes_le(),
+                    ))?;
+                    pending_key = Some(*key);
+                    track(&mut requests, stream.version(), id, reply);
+                }
+                Ok(Outgoing::Close) | Err(TryRecvError::Disconnected) => {
+                    tls::close(stream.get_mut());
+                    return Ok(());
+                }
+                Err(TryRecvError::Empty) => break,
+            }
+        }
+
+        // then recieve from the server.
+        let recieved = stream.recv();
+        if let (Ok(Some(_)), Some(heartbeat)) = (&recieved, &mut heartbeat) {
+            heartbeat.seen();
+        }
+        match recieved {
+            Ok(Some(Message::ServerChange(change))) => {
+                let mut tree = match tree.lock() {
+                    Ok(tree) => tree,
+                    Err(_) => return Err(Error::SimpleError("Client: Tree lock is poisoned")),
+                };
+                if TreeBuilder::change(&mut tree, change).is_err() {
+                    out_of_sync = true;
+                }
+            }
+            Ok(Some(Message::Snapshot(snapshot))) => {
+                let new_tree = Node::from_snapshot(&snapshot)?;
+                match tree.lock() {
+                    Ok(mut tree) => *tree = new_tree,
+                    Err(_) => return Err(Error::SimpleError("Client: Tree lock is poisoned")),
+                }
+                out_of_sync = false;
+            }
+            Ok(Some(Message::ServerAuth(challenge))) if pending_key.is_some() => {
+                let key = pending_key.take().unwrap();
+                let response = answer_challenge(&key, &challenge, stream.binding().as_ref())?;
+                stream.send(&Message::ClientAuth(response))?;
+            }
+            Ok(Some(Message::ServerRefreshPermissions)) => {
+                // The changes of the tree that come with the new permissions are sent separately.
+            }
+            Ok(Some(Message::ServerLog(log))) => {
+                // Nobody listens for logs anymore, which is fine.
+                let _ = logs_s.send(log);
+            }
+            Ok(Some(Message::ServerAck(id))) => {
+                // Nobody might wait for the answer anymore.
+                if let Some(reply) = requests.remove(&id) {
+                    let _ = reply.send(Ok(()));
+                }
+            }
+            Ok(Some(Message::ServerNack(id, code, log))) => {
+                if let Some(reply) = requests.remove(&id) {
+                    let _ = reply.send(Err(Error::Refused(code, log)));
+                }
+            }
+            Ok(Some(Message::Ping(counter))) => stream.send(&Message::Pong(counter))?,
+            Ok(Some(Message::Pong(_))) => {}
+            Ok(Some(Message::ServerGoodbye(reason))) => {
+                let _ = logs_s.send(reason);
+                tls::close(stream.get_mut());
+                return Ok(());
+            }
+            Ok(Some(message)) => {
+                return Err(Error::SimpleErrorStr(format!(
+                    "Client: Unexpected message {:?}",
+                    message
+                )));
+            }
+            Ok(None) if out_of_sync => {
+                let hash = match tree.lock() {
+                    Ok(tree) => tree.get_hash(),
+                    Err(_) => return Err(Error::SimpleError("Client: Tree lock is poisoned")),
+                };
+                stream.send(&Message::ClientHash(hash))?;
+                out_of_sync = false;
+            }
+            Ok(None) => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Keeps [reply] until the server answers request [id]. Servers that speak an older [version]
+/// never answer, so the request is done once it is sent.
+fn track(requests: &mut HashMap<u64, Reply>, version: u8, id: u64, reply: Reply) {
+    if version >= REQUEST_VERSION {
+        requests.insert(id, reply);
+    } else {
+        let _ = reply.send(Ok(()));
+    }
+}
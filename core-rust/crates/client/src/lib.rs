@@ -1,14 +1,440 @@
-pub fn add(left: u64, right: u64) -> u64 {
-    left + right
+mod conn;
+pub mod request;
+mod tls;
+
+use std::{
+    fmt::Display,
+    net::TcpStream,
+    path::Path,
+    sync::{
+        Arc, Mutex, MutexGuard,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crossbeam::channel::{Receiver, Sender};
+use rsa::RsaPrivateKey;
+use shared::{
+    config,
+    datatypes::{Data, nodes::Node},
+    errors::Error,
+    remote::{
+        codec::FramedStream,
+        heartbeat::Heartbeat,
+        message::Message,
+        version::{Capabilities, HEARTBEAT_VERSION},
+    },
+};
+use uuid::Uuid;
+
+use crate::{
+    conn::{Accepted, Outgoing},
+    request::{Pending, Reply},
+    tls::{Stream, TlsOptions},
+};
+
+/// Configures how a [Client] connects to a server.
+///
+/// # Example:
+///
+/// ```no_run
+/// use client::ClientBuilder;
+///
+/// let client = ClientBuilder::new("localhost:8001")
+///     .accept_invalid_certs(true)
+///     .connect()
+///     .unwrap();
+/// ```
+#[derive(Clone)]
+pub struct ClientBuilder {
+    address: String,
+    domain: Option<String>,
+    key: Option<RsaPrivateKey>,
+    server_key: Option<String>,
+    tls: TlsOptions,
+    heartbeat_interval: Duration,
+    missed_heartbeats: u32,
+    auto_reconnect: bool,
 }
 
-#[cfg(test)]
-pub mod tests {
-    use super::*;
+impl ClientBuilder {
+    /// Creates a builder for a connection to the server at [address] (host:port).
+    pub fn new(address: impl Display) -> Self {
+        Self {
+            address: address.to_string(),
+            domain: None,
+            key: None,
+            server_key: None,
+            tls: TlsOptions::default(),
+            heartbeat_interval: config::HEARTBEAT_INTERVAL,
+            missed_heartbeats: config::MISSED_HEARTBEATS,
+            auto_reconnect: true,
+        }
+    }
+
+    /// Sets the domain that the certificate of the server is checked against.
+    /// By default this is the host of the address.
+    pub fn domain(mut self, domain: impl Display) -> Self {
+        self.domain = Some(domain.to_string());
+        self
+    }
+
+    /// Sets the key the client authenticates itself with.
+    /// Without a key the client connects anonymously.
+    pub fn key(mut self, key: RsaPrivateKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// Does the key exchange of [shared::security::exchange] with the server, which has to sign
+    /// it with the identity key that has [fingerprint]. Every frame is then encrypted by the
+    /// protocol itself, in addition to TLS if it is used.
+    pub fn server_key(mut self, fingerprint: impl Display) -> Self {
+        self.server_key = Some(fingerprint.to_string());
+        self
+    }
+
+    /// Connects without TLS if [tls] is false. Needs [ClientBuilder::server_key], which then
+    /// protects the connection instead.
+    pub fn tls(mut self, tls: bool) -> Self {
+        self.tls.plain = !tls;
+        self
+    }
+
+    /// Accepts certificates of the server that cannot be verified, like self signed ones.
+    /// Should only be used for testing.
+    pub fn accept_invalid_certs(mut self, accept: bool) -> Self {
+        self.tls.accept_invalid_certs = accept;
+        self
+    }
+
+    /// Also trusts server certificates that are signed by the PEM certificate at [path].
+    pub fn ca_certificate(mut self, path: impl AsRef<Path>) -> Self {
+        self.tls.ca_certificate = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Presents the PEM certificate chain with its private key to servers that only accept
+    /// clients with a certificate. The key has to be in PKCS#8 format.
+    pub fn client_certificate(
+        mut self,
+        certificate: impl AsRef<Path>,
+        key: impl AsRef<Path>,
+    ) -> Self {
+        self.tls.client_certificate = Some((
+            certificate.as_ref().to_path_buf(),
+            key.as_ref().to_path_buf(),
+        ));
+        self
+    }
+
+    /// Pings the server every [interval] and considers the connection lost after [missed]
+    /// intervals without anything from the server. Only servers that speak
+    /// [shared::remote::version::HEARTBEAT_VERSION] are pinged.
+    pub fn heartbeat(mut self, interval: Duration, missed: u32) -> Self {
+        self.heartbeat_interval = interval;
+        self.missed_heartbeats = missed;
+        self
+    }
+
+    /// Reconnects like [Client::reconnect] when the connection is lost, which is the default.
+    /// A connection the server closes with a goodbye is not reconnected.
+    pub fn auto_reconnect(mut self, reconnect: bool) -> Self {
+        self.auto_reconnect = reconnect;
+        self
+    }
+
+    /// Connects to the server, does the handshake and starts mirroring the tree.
+    pub fn connect(self) -> Result<Client, Error> {
+        let mut stream = self.open()?;
+        let accepted = conn::handshake(&mut stream, self.key.clone())?;
+
+        let tree = Arc::new(Mutex::new(conn::initial_tree(&mut stream)?));
+        let accepted = Arc::new(Mutex::new(accepted));
+        let (logs_s, logs_r) = crossbeam::channel::unbounded();
+        let (to_conn_s, conn_thread) = self.start(stream, &tree, &accepted, &logs_s);
+
+        Ok(Client {
+            builder: self,
+            tree,
+            accepted,
+            to_conn_s,
+            logs_s,
+            logs_r,
+            next_request: AtomicU64::new(1),
+            conn_thread: Some(conn_thread),
+        })
+    }
+
+    /// Opens the connection to the server, with TLS and the key exchange as configured.
+    fn open(&self) -> Result<FramedStream<Stream>, Error> {
+        if self.tls.plain && self.server_key.is_none() {
+            return Err(Error::SimpleError(
+                "Client: Without TLS the server_key is needed",
+            ));
+        }
+        let domain = match &self.domain {
+            Some(domain) => domain.clone(),
+            None => match self.address.rsplit_once(':') {
+                Some((host, _)) => host.to_string(),
+                None => self.address.clone(),
+            },
+        };
+
+        let stream = Error::from(TcpStream::connect(&self.address))?;
+        let stream = match self.tls.plain {
+            true => Stream::Plain(stream),
+            false => Stream::Tls(Box::new(tls::connect(&self.tls, &domain, stream)?)),
+        };
+
+        // Reads time out regularly, so that messages of the client can be sent in between.
+        Error::from(tls::socket(&stream).set_read_timeout(Some(config::POLL_INTERVAL)))?;
+        let mut stream = FramedStream::new(stream);
+        if let Some(server_key) = &self.server_key {
+            conn::exchange(&mut stream, server_key)?;
+        }
+        Ok(stream)
+    }
+
+    /// Opens a new connection and resumes the session of [accepted]. If the server does not have
+    /// it anymore, a new session is started and [tree] is replaced.
+    fn reopen(
+        &self,
+        accepted: &Mutex<Accepted>,
+        tree: &Mutex<Node>,
+    ) -> Result<FramedStream<Stream>, Error> {
+        let mut stream = self.open()?;
+        let hash = lock(tree)?.get_hash();
+        let mut accepted = lock(accepted)?;
+        match conn::resume(&mut stream, accepted.version, accepted.session, hash) {
+            Ok(resumed) => {
+                *accepted = resumed;
+                Ok(stream)
+            }
+            Err(_) => {
+                // The server refused the session and closes that connection.
+                let mut stream = self.open()?;
+                *accepted = conn::handshake(&mut stream, self.key.clone())?;
+                *lock(tree)? = conn::initial_tree(&mut stream)?;
+                Ok(stream)
+            }
+        }
+    }
+
+    /// Starts the thread that mirrors the tree with [stream]. It reconnects when the connection
+    /// is lost, if [ClientBuilder::auto_reconnect] is set.
+    fn start(
+        &self,
+        stream: FramedStream<Stream>,
+        tree: &Arc<Mutex<Node>>,
+        accepted: &Arc<Mutex<Accepted>>,
+        logs_s: &Sender<String>,
+    ) -> (Sender<Outgoing>, JoinHandle<Result<(), Error>>) {
+        let (to_conn_s, to_conn_r) = crossbeam::channel::unbounded();
+        let builder = self.clone();
+        let tree = tree.clone();
+        let accepted = accepted.clone();
+        let logs_s = logs_s.clone();
+
+        let conn_thread = thread::spawn(move || {
+            let mut stream = stream;
+            loop {
+                let heartbeat = (stream.version() >= HEARTBEAT_VERSION)
+                    .then(|| Heartbeat::new(builder.heartbeat_interval, builder.missed_heartbeats));
+                let lost = match conn::run(stream, &tree, &to_conn_r, &logs_s, heartbeat) {
+                    Ok(()) => return Ok(()),
+                    Err(err) if builder.auto_reconnect => err,
+                    // Nobody reconnects, so a closed connection is just closed.
+                    Err(Error::ConnectionClosed) => return Ok(()),
+                    Err(err) => return Err(err),
+                };
+                stream = builder.reconnect(&accepted, &tree, lost)?;
+            }
+        });
+        (to_conn_s, conn_thread)
+    }
+
+    /// Tries [ClientBuilder::reopen] a few times, waiting longer before each try.
+    /// Returns [lost], the error the connection was lost with, if no try works.
+    fn reconnect(
+        &self,
+        accepted: &Mutex<Accepted>,
+        tree: &Mutex<Node>,
+        lost: Error,
+    ) -> Result<FramedStream<Stream>, Error> {
+        let mut delay = config::RECONNECT_DELAY;
+        for _ in 0..config::RECONNECT_ATTEMPTS {
+            thread::sleep(delay);
+            if let Ok(stream) = self.reopen(accepted, tree) {
+                return Ok(stream);
+            }
+            delay *= 2;
+        }
+        Err(lost)
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> Result<MutexGuard<'_, T>, Error> {
+    match mutex.lock() {
+        Ok(guard) => Ok(guard),
+        Err(_) => Err(Error::SimpleError("Client: Lock is poisoned")),
+    }
+}
+
+/// A connection to a server.
+/// Keeps a mirror of the tree of the server, that is updated in the background.
+pub struct Client {
+    /// To connect again.
+    builder: ClientBuilder,
+    tree: Arc<Mutex<Node>>,
+    /// Changes when the connection thread reconnects on its own.
+    accepted: Arc<Mutex<Accepted>>,
+    to_conn_s: Sender<Outgoing>,
+    logs_s: Sender<String>,
+    logs_r: Receiver<String>,
+    /// The id of the next request, unique for the whole life of the client.
+    next_request: AtomicU64,
+    conn_thread: Option<JoinHandle<Result<(), Error>>>,
+}
+
+impl Client {
+    /// Locks the mirrored tree. Changes of the server are not applied while the lock is held.
+    pub fn tree(&self) -> Result<MutexGuard<'_, Node>, Error> {
+        match self.tree.lock() {
+            Ok(tree) => Ok(tree),
+            Err(_) => Err(Error::SimpleError("Client: Tree lock is poisoned")),
+        }
+    }
+
+    /// Presses the button with [node_id] on the server.
+    /// The new data of the button arrives as a change from the server. [Pending::wait] tells
+    /// whether the server pressed it.
+    pub fn press(&self, node_id: &Uuid) -> Result<Pending, Error> {
+        {
+            let tree = self.tree()?;
+            match tree.find_node(node_id) {
+                Some(Node {
+                    data: Data::Button(_),
+                    ..
+                }) => {}
+                Some(_) => {
+                    return Err(Error::SimpleErrorStr(format!(
+                        "Press: Node is not a button ({:?})",
+                        node_id
+                    )));
+                }
+                None => {
+                    return Err(Error::SimpleErrorStr(format!(
+                        "Press: Cannot find node with id={:?}",
+                        node_id
+                    )));
+                }
+            }
+        }
+
+        let node_id = *node_id;
+        self.request(|id, reply| Outgoing::Request(id, Message::ClientTrigger(id, node_id), reply))
+    }
+
+    /// Proves to the server that this client owns [key].
+    /// The server adds the permissions of the key to the ones the client already has. Once
+    /// [Pending::wait] returns, the tree has the nodes of the key.
+    pub fn add_permissions(&self, key: RsaPrivateKey) -> Result<Pending, Error> {
+        self.request(|id, reply| Outgoing::AddPermissions(Box::new(key), id, reply))
+    }
+
+    /// Waits at most [timeout] for the next log message of the server.
+    pub fn next_log(&self, timeout: Duration) -> Option<String> {
+        self.logs_r.recv_timeout(timeout).ok()
+    }
+
+    /// The session number the server gave this client.
+    pub fn session(&self) -> u64 {
+        self.accepted().session
+    }
+
+    /// How long the session is valid in seconds.
+    pub fn validity(&self) -> u16 {
+        self.accepted().validity
+    }
+
+    /// The version of the protocol the client speaks with the server, the highest both speak.
+    pub fn version(&self) -> u8 {
+        self.accepted().version
+    }
+
+    /// The optional features of the protocol both the client and the server support.
+    pub fn capabilities(&self) -> Capabilities {
+        self.accepted().capabilities
+    }
+
+    fn accepted(&self) -> Accepted {
+        match self.accepted.lock() {
+            Ok(accepted) => *accepted,
+            // Is only poisoned while it is replaced as a whole.
+            Err(poisoned) => *poisoned.into_inner(),
+        }
+    }
+
+    /// Returns [true] as long as the connection to the server is open, or being reconnected.
+    pub fn is_connected(&self) -> bool {
+        self.conn_thread
+            .as_ref()
+            .is_some_and(|thread| !thread.is_finished())
+    }
+
+    /// Connects to the server again, for example after the connection was lost.
+    /// Resumes the session if the server still has it. Then the client is not authenticated
+    /// again and only gets the changes it missed. Otherwise it connects like
+    /// [ClientBuilder::connect] with a new session, which does not have the keys of
+    /// [Client::add_permissions].
+    pub fn reconnect(&mut self) -> Result<(), Error> {
+        // The server might still think the old connection is open, resuming takes over the
+        // session from it.
+        let _ = self.to_conn_s.send(Outgoing::Close);
+        if let Some(thread) = self.conn_thread.take() {
+            // Why it stopped does not matter anymore.
+            let _ = thread.join();
+        }
+
+        let stream = self.builder.reopen(&self.accepted, &self.tree)?;
+        let (to_conn_s, conn_thread) =
+            self.builder
+                .start(stream, &self.tree, &self.accepted, &self.logs_s);
+        self.to_conn_s = to_conn_s;
+        self.conn_thread = Some(conn_thread);
+        Ok(())
+    }
+
+    /// Closes the connection and waits until it is closed.
+    /// Returns the error that stopped the connection, if there was one.
+    pub fn disconnect(mut self) -> Result<(), Error> {
+        let _ = self.to_conn_s.send(Outgoing::Close);
+        match self.conn_thread.take() {
+            Some(thread) => match thread.join() {
+                Ok(result) => result,
+                Err(_) => Err(Error::SimpleError("Client: Connection thread panicked")),
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// Sends the request [outgoing] makes with the next request id.
+    fn request(&self, outgoing: impl FnOnce(u64, Reply) -> Outgoing) -> Result<Pending, Error> {
+        let id = self.next_request.fetch_add(1, Ordering::Relaxed);
+        let (pending, reply) = Pending::new(id);
+        match self.to_conn_s.send(outgoing(id, reply)) {
+            Ok(()) => Ok(pending),
+            Err(_) => Err(Error::SimpleError("Client: Connection is closed")),
+        }
+    }
+}
 
-    #[test]
-    fn it_works() {
-        let result = add(2, 2);
-        assert_eq!(result, 4);
+impl Drop for Client {
+    fn drop(&mut self) {
+        // The connection thread stops on its own.
+        let _ = self.to_conn_s.send(Outgoing::Close);
     }
 }
@@ -0,0 +1,80 @@
+// Requests of the client that the server answers, like pressing a button.
+// Every request gets an id, which the server answers with
+// [shared::remote::message::Message::ServerAck] or [shared::remote::message::Message::ServerNack].
+// The connection thread resolves the [Pending] request of the id with the answer.
+
+use std::time::Duration;
+
+use crossbeam::channel::{Receiver, RecvTimeoutError, Sender};
+use shared::errors::Error;
+
+/// How the connection thread answers a [Pending] request.
+pub(crate) type Reply = Sender<Result<(), Error>>;
+
+/// A request that was sent to the server, but might not be answered yet.
+#[derive(Debug)]
+pub struct Pending {
+    id: u64,
+    reply: Receiver<Result<(), Error>>,
+}
+
+impl Pending {
+    /// Creates the request with [id] and what the connection thread answers it with.
+    pub(crate) fn new(id: u64) -> (Pending, Reply) {
+        let (reply_s, reply) = crossbeam::channel::bounded(1);
+        (Pending { id, reply }, reply_s)
+    }
+
+    /// The id the request was sent with.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Waits at most [timeout] for the answer of the server.
+    /// Returns [Error::Refused] if the server refused the request. Servers that do not speak
+    /// [shared::remote::version::REQUEST_VERSION] never answer, their requests are done once they
+    /// are sent.
+    pub fn wait(self, timeout: Duration) -> Result<(), Error> {
+        match self.reply.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => Err(Error::SimpleErrorStr(format!(
+                "Request: {} timed out",
+                self.id
+            ))),
+            Err(RecvTimeoutError::Disconnected) => Err(Error::SimpleError(
+                "Request: Connection was lost before the answer",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use shared::{errors::Error, remote::message::NackCode};
+
+    use crate::request::Pending;
+
+    #[test]
+    fn answers() {
+        let (pending, reply) = Pending::new(1);
+        reply.send(Ok(())).unwrap();
+        assert!(pending.wait(Duration::from_millis(10)).is_ok());
+
+        let (pending, reply) = Pending::new(2);
+        reply
+            .send(Err(Error::Refused(NackCode::NotFound, "gone".to_string())))
+            .unwrap();
+        assert!(matches!(
+            pending.wait(Duration::from_millis(10)),
+            Err(Error::Refused(NackCode::NotFound, _))
+        ));
+
+        let (pending, _reply) = Pending::new(3);
+        assert!(pending.wait(Duration::from_millis(10)).is_err());
+        let (pending, reply) = Pending::new(4);
+        drop(reply);
+        assert!(pending.wait(Duration::from_millis(10)).is_err());
+    }
+}
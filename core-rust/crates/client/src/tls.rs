@@ -0,0 +1,286 @@
+// The TLS backend of the client, selected by cargo feature like the one of the server.
+// `native-tls` (default) uses the TLS library and the trusted certificates of the system.
+// `rustls` trusts the webpki roots and can present a client certificate from PEM files.
+// If both are enabled rustls is used.
+// Without TLS connections stay plain, the key exchange protects them instead.
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+compile_error!("Either the native-tls or the rustls feature of the client has to be enabled");
+
+#[cfg(feature = "rustls")]
+use std::sync::Arc;
+use std::{
+    fs,
+    io::{Read, Write},
+    net::{Shutdown, TcpStream},
+    path::{Path, PathBuf},
+};
+
+use shared::errors::Error;
+
+#[cfg(feature = "rustls")]
+pub(crate) type TlsStream = rustls::StreamOwned<rustls::ClientConnection, TcpStream>;
+#[cfg(not(feature = "rustls"))]
+pub(crate) type TlsStream = native_tls::TlsStream<TcpStream>;
+
+/// A connection to the server. It is only plain if the client connects without TLS.
+pub(crate) enum Stream {
+    Tls(Box<TlsStream>),
+    Plain(TcpStream),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Tls(stream) => stream.read(buf),
+            Stream::Plain(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Tls(stream) => stream.write(buf),
+            Stream::Plain(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Stream::Tls(stream) => stream.flush(),
+            Stream::Plain(stream) => stream.flush(),
+        }
+    }
+}
+
+/// How the client checks the server and proves who it is.
+#[derive(Default, Clone)]
+pub(crate) struct TlsOptions {
+    /// Connects without TLS.
+    pub plain: bool,
+    pub accept_invalid_certs: bool,
+    /// An additional PEM certificate the certificate of the server may be signed by.
+    pub ca_certificate: Option<PathBuf>,
+    /// PEM files with the certificate chain and the private key of the client.
+    pub client_certificate: Option<(PathBuf, PathBuf)>,
+}
+
+/// Does the client side of the TLS handshake on [stream].
+#[cfg(feature = "rustls")]
+pub(crate) fn connect(
+    options: &TlsOptions,
+    domain: &str,
+    mut stream: TcpStream,
+) -> Result<TlsStream, Error> {
+    let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+    let builder = Error::from(
+        rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions(),
+    )?;
+
+    let builder = if options.accept_invalid_certs {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(danger::AcceptAnyCertificate(provider)))
+    } else {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        if let Some(path) = &options.ca_certificate {
+            for certificate in load_certificates(path)? {
+                Error::from(roots.add(certificate))?;
+            }
+        }
+        builder.with_root_certificates(roots)
+    };
+
+    let config = match &options.client_certificate {
+        Some((certificate, key)) => Error::from(
+            builder.with_client_auth_cert(load_certificates(certificate)?, load_private_key(key)?),
+        )?,
+        None => builder.with_no_client_auth(),
+    };
+
+    let name = match rustls::pki_types::ServerName::try_from(domain.to_string()) {
+        Ok(name) => name,
+        Err(_) => {
+            return Err(Error::SimpleErrorStr(format!(
+                "TLS: {domain} is not a valid domain"
+            )));
+        }
+    };
+    let mut connection = Error::from(rustls::ClientConnection::new(Arc::new(config), name))?;
+    while connection.is_handshaking() {
+        if let Err(err) = connection.complete_io(&mut stream) {
+            return Err(Error::SimpleErrorStr(format!("TLS: Handshake: {:?}", err)));
+        }
+    }
+    Ok(rustls::StreamOwned::new(connection, stream))
+}
+
+/// Does the client side of the TLS handshake on [stream].
+#[cfg(not(feature = "rustls"))]
+pub(crate) fn connect(
+    options: &TlsOptions,
+    domain: &str,
+    stream: TcpStream,
+) -> Result<TlsStream, Error> {
+    let mut builder = native_tls::TlsConnector::builder();
+    builder.danger_accept_invalid_certs(options.accept_invalid_certs);
+    if let Some(path) = &options.ca_certificate {
+        let certificate = Error::from(native_tls::Certificate::from_pem(&read(path)?))?;
+        builder.add_root_certificate(certificate);
+    }
+    if let Some((certificate, key)) = &options.client_certificate {
+        let identity = Error::from(native_tls::Identity::from_pkcs8(
+            &read(certificate)?,
+            &read(key)?,
+        ))?;
+        builder.identity(identity);
+    }
+
+    let connector = Error::from(builder.build())?;
+    match connector.connect(domain, stream) {
+        Ok(stream) => Ok(stream),
+        Err(err) => Err(Error::SimpleErrorStr(format!("TLS: Handshake: {:?}", err))),
+    }
+}
+
+/// The connection below the TLS layer, to set timeouts on it.
+pub(crate) fn socket(stream: &Stream) -> &TcpStream {
+    match stream {
+        Stream::Tls(stream) => stream.get_ref(),
+        Stream::Plain(stream) => stream,
+    }
+}
+
+/// Tells the server that the connection is closed on purpose.
+pub(crate) fn close(stream: &mut Stream) {
+    match stream {
+        #[cfg(feature = "rustls")]
+        Stream::Tls(stream) => {
+            stream.conn.send_close_notify();
+            let _ = stream.flush();
+        }
+        #[cfg(not(feature = "rustls"))]
+        Stream::Tls(stream) => {
+            let _ = stream.shutdown();
+        }
+        Stream::Plain(stream) => {
+            let _ = stream.shutdown(Shutdown::Write);
+        }
+    }
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, Error> {
+    match fs::read(path) {
+        Ok(content) => Ok(content),
+        Err(err) => Err(Error::SimpleErrorStr(format!(
+            "TLS: Couldnt read {}: {:?}",
+            path.display(),
+            err
+        ))),
+    }
+}
+
+#[cfg(feature = "rustls")]
+fn load_certificates(
+    path: &Path,
+) -> Result<Vec<rustls::pki_types::CertificateDer<'static>>, Error> {
+    let content = read(path)?;
+    let certificates: Vec<_> = match rustls_pemfile::certs(&mut content.as_slice()).collect() {
+        Ok(certificates) => certificates,
+        Err(err) => {
+            return Err(Error::SimpleErrorStr(format!(
+                "TLS: Couldnt read certificates from {}: {:?}",
+                path.display(),
+                err
+            )));
+        }
+    };
+    if certificates.is_empty() {
+        return Err(Error::SimpleErrorStr(format!(
+            "TLS: No certificate in {}",
+            path.display()
+        )));
+    }
+    Ok(certificates)
+}
+
+#[cfg(feature = "rustls")]
+fn load_private_key(path: &Path) -> Result<rustls::pki_types::PrivateKeyDer<'static>, Error> {
+    match rustls_pemfile::private_key(&mut read(path)?.as_slice()) {
+        Ok(Some(key)) => Ok(key),
+        Ok(None) => Err(Error::SimpleErrorStr(format!(
+            "TLS: No private key in {}",
+            path.display()
+        ))),
+        Err(err) => Err(Error::SimpleErrorStr(format!(
+            "TLS: Couldnt read private key from {}: {:?}",
+            path.display(),
+            err
+        ))),
+    }
+}
+
+// Only for [TlsOptions::accept_invalid_certs], like for self signed certificates in tests.
+#[cfg(feature = "rustls")]
+mod danger {
+    use std::sync::Arc;
+
+    use rustls::{
+        DigitallySignedStruct, SignatureScheme,
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{CryptoProvider, verify_tls12_signature, verify_tls13_signature},
+        pki_types::{CertificateDer, ServerName, UnixTime},
+    };
+
+    /// Accepts every certificate, but still checks that the server owns it.
+    #[derive(Debug)]
+    pub(super) struct AcceptAnyCertificate(pub Arc<CryptoProvider>);
+
+    impl ServerCertVerifier for AcceptAnyCertificate {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            _server_name: &ServerName<'_>,
+            _ocsp_response: &[u8],
+            _now: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            certificate: &CertificateDer<'_>,
+            signature: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            verify_tls12_signature(
+                message,
+                certificate,
+                signature,
+                &self.0.signature_verification_algorithms,
+            )
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            certificate: &CertificateDer<'_>,
+            signature: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            verify_tls13_signature(
+                message,
+                certificate,
+                signature,
+                &self.0.signature_verification_algorithms,
+            )
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            self.0.signature_verification_algorithms.supported_schemes()
+        }
+    }
+}
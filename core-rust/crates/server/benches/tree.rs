@@ -0,0 +1,53 @@
+// Looks up nodes through [server::server_interface::TreeAccess] on a server with 100k nodes.
+// Run with `cargo bench -p server`.
+
+use std::hint::black_box;
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use server::{
+    Server,
+    server_interface::{ServerInterface, TreeAccess},
+};
+use shared::datatypes::{Data, nodes::Node};
+use uuid::Uuid;
+
+const FOLDERS: u128 = 100;
+const PER_FOLDER: u128 = 1000;
+
+// 100 folders with 1000 values each. The values have the ids 1..=100_000.
+fn server() -> Server {
+    let mut server = Server::new();
+    for folder in 0..FOLDERS {
+        let values = (0..PER_FOLDER)
+            .map(|value| {
+                Node::new()
+                    .id(Uuid::from_u128(folder * PER_FOLDER + value + 1))
+                    .data(Data::UInt64(0))
+            })
+            .collect();
+        server
+            .add_child(
+                Node::new()
+                    .id(Uuid::from_u128(u128::MAX - folder))
+                    .children(values),
+            )
+            .unwrap();
+    }
+    server
+}
+
+fn lookups(c: &mut Criterion) {
+    let mut server = server();
+    // The last value is the worst case for a search.
+    let id = Uuid::from_u128(FOLDERS * PER_FOLDER);
+
+    c.bench_function("get_node 100k", |b| {
+        b.iter(|| black_box(server.get_node(black_box(&id)).is_ok()))
+    });
+    c.bench_function("get_node_mut 100k", |b| {
+        b.iter(|| black_box(server.get_node_mut(black_box(&id)).is_ok()))
+    });
+}
+
+criterion_group!(benches, lookups);
+criterion_main!(benches);
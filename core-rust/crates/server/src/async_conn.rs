@@ -0,0 +1,407 @@
+// Serves the clients as tasks on a tokio runtime, see [crate::Server::serve_async].
+// Each connection has two tasks: one reads from the client and passes the messages to the
+// handler, the other writes everything the handler and the reading task have for the client.
+// The handler never waits for a connection, its queue to a slow connection is bounded instead.
+// Clients that speak heartbeats are pinged by the writing task. The reading task closes the
+// connection once a client is quiet for longer than its missed heartbeats.
+
+use std::sync::{Arc, Mutex};
+
+use crossbeam::channel::Sender;
+use shared::{
+    config,
+    errors::Error,
+    remote::{admission::Admission, codec, message::Message, version::HEARTBEAT_VERSION},
+    security::handshake::ServerHandshake,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task::JoinHandle,
+    time::{Instant, interval_at, timeout},
+};
+use tokio_rustls::{TlsAcceptor, server::TlsStream};
+
+use crate::{
+    conn,
+    helper::ServerHelper,
+    internal_message::{ClientSender, InternalMessage, Login},
+    server_config::ServerConfig,
+    shutdown::Connections,
+};
+
+/// Accepts clients on [listener] until [connections] stops or the runtime stops. Each client is
+/// served by its own tasks. Clients without a slot are rejected like in [conn::serve_server].
+pub(crate) async fn serve_server(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    server: ServerHelper,
+    admission: Admission,
+    connections: Connections,
+    config: Arc<ServerConfig>,
+) {
+    let server = Arc::new(Mutex::new(server));
+    let rejections = Admission::new(config::MAX_REJECTIONS);
+
+    while !connections.is_stopping() {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            // Wakes up regularly to notice when the server stops.
+            _ = tokio::time::sleep(config::POLL_INTERVAL) => continue,
+        };
+        let (stream, address) = match accepted {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                // Like running out of file descriptors, which takes a while to change. The error
+                // ends up in the report of the shutdown.
+                connections.accept_failed(&err);
+                tokio::time::sleep(config::POLL_INTERVAL).await;
+                continue;
+            }
+        };
+        let Ok((stream, socket)) = clone_socket(stream) else {
+            continue;
+        };
+        let slot = admission.try_admit();
+        let rejecting = match &slot {
+            Some(_) => None,
+            None => match rejections.try_admit() {
+                Some(rejecting) => Some(rejecting),
+                // Too many clients are told already, this one is just closed.
+                None => continue,
+            },
+        };
+        let tracked = connections.track(address, socket);
+
+        let acceptor = acceptor.clone();
+        let server = server.clone();
+        let admission = admission.clone();
+        let connections = connections.clone();
+        let config = config.clone();
+        tokio::spawn(async move {
+            // The slot is given back and the connection is no longer tracked when the task ends,
+            // however it ends.
+            let _tracked = tracked;
+            let _rejecting = rejecting;
+            let result = match slot {
+                Some(_slot) => handle(stream, acceptor, server, config).await,
+                None => reject(stream, acceptor, admission, config).await,
+            };
+            if let Err(err) = result {
+                connections.failed(&err);
+            }
+        });
+    }
+}
+
+/// Returns [stream] together with a clone of its socket, which can close it from outside the
+/// runtime.
+fn clone_socket(stream: TcpStream) -> std::io::Result<(TcpStream, std::net::TcpStream)> {
+    let stream = stream.into_std()?;
+    let socket = stream.try_clone()?;
+    Ok((TcpStream::from_std(stream)?, socket))
+}
+
+/// Tells a client that there is no slot for it, like [Admission::reject].
+async fn reject(
+    stream: TcpStream,
+    acceptor: TlsAcceptor,
+    admission: Admission,
+    config: Arc<ServerConfig>,
+) -> Result<(), Error> {
+    let rejection = async {
+        let mut stream = match acceptor.accept(stream).await {
+            Ok(stream) => stream,
+            Err(err) => return Err(Error::SimpleErrorStr(format!("TLS: Handshake: {:?}", err))),
+        };
+        write_frame(&mut stream, &admission.rejection(), config::CURRENT_VERSION).await?;
+        while read_frame(&mut stream).await.is_ok() {}
+        Ok(())
+    };
+    // The client may not close the connection, it is closed anyway after the timeout.
+    timeout(config.handshake_timeout, rejection)
+        .await
+        .unwrap_or(Ok(()))
+}
+
+async fn handle(
+    stream: TcpStream,
+    acceptor: TlsAcceptor,
+    server: Arc<Mutex<ServerHelper>>,
+    config: Arc<ServerConfig>,
+) -> Result<(), Error> {
+    // TLS and the handshake of the protocol together may take as long as the handshake alone
+    // in [crate::conn].
+    let handshake = async {
+        let stream = match acceptor.accept(stream).await {
+            Ok(stream) => stream,
+            Err(err) => return Err(Error::SimpleErrorStr(format!("TLS: Handshake: {:?}", err))),
+        };
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let (login, version) = client_hello(&mut reader, &mut writer, &config).await?;
+        Ok((reader, writer, login, version))
+    };
+    let (reader, mut writer, login, version) =
+        match timeout(config.handshake_timeout, handshake).await {
+            Ok(result) => result?,
+            Err(_) => return Err(Error::SimpleError("Handshake: Timed out")),
+        };
+
+    let (to_client_s, mut from_server) = mpsc::channel(config.queue_size);
+    let (id, to_server) = server
+        .lock()
+        .unwrap()
+        .attach(login, ClientSender::Task(to_client_s))?;
+    let (answers_s, mut answers) = mpsc::unbounded_channel();
+    let heartbeats = version >= HEARTBEAT_VERSION;
+    let quiet = heartbeats.then(|| {
+        config
+            .heartbeat_interval
+            .saturating_mul(config.missed_heartbeats)
+    });
+    let mut reading = tokio::spawn(read_client(
+        id,
+        reader,
+        to_server.clone(),
+        answers_s,
+        version,
+        quiet,
+        config.clone(),
+    ));
+    let mut pings = interval_at(
+        Instant::now() + config.heartbeat_interval,
+        config.heartbeat_interval,
+    );
+    let mut counter = 0;
+
+    let result = loop {
+        let message = tokio::select! {
+            message = from_server.recv() => match message {
+                Some(InternalMessage::TreeChange(change)) => Message::ServerChange(change),
+                Some(InternalMessage::Message(_, message)) => message,
+                // Everything sent before was sent already.
+                Some(InternalMessage::Quit) | None => {
+                    break goodbye(&mut writer, &mut reading, version, &config).await;
+                }
+                Some(_) => continue,
+            },
+            Some(answer) = answers.recv() => answer,
+            _ = pings.tick(), if heartbeats => {
+                counter += 1;
+                Message::Ping(counter)
+            }
+            result = &mut reading => match result {
+                Ok(result) => break result,
+                Err(err) => break Err(Error::SimpleErrorStr(format!("{:?}", err))),
+            },
+        };
+        if let Err(err) = write_frame(&mut writer, &message, version).await {
+            break Err(err);
+        }
+    };
+
+    reading.abort();
+    let _ = writer.shutdown().await;
+    // The handler should no longer send anything to this client.
+    let _ = to_server.send(InternalMessage::Unregister(id));
+    result
+}
+
+/// Tells the client that the server stops and waits for it to close the connection, like
+/// [conn::goodbye].
+async fn goodbye(
+    writer: &mut WriteHalf<TlsStream<TcpStream>>,
+    reading: &mut JoinHandle<Result<(), Error>>,
+    version: u8,
+    config: &ServerConfig,
+) -> Result<(), Error> {
+    let goodbye = Message::ServerGoodbye(conn::GOODBYE.to_string());
+    write_frame(writer, &goodbye, version).await?;
+    // The reading task ends once the client closed the connection.
+    match timeout(config.handshake_timeout, reading).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(err)) => Err(Error::SimpleErrorStr(format!("{:?}", err))),
+        Err(_) => Err(Error::SimpleError(
+            "Goodbye: Client did not close the connection",
+        )),
+    }
+}
+
+/// Does the handshake with a new client, like [conn::client_hello].
+/// Returns the version everything has to be sent to the client in, too.
+async fn client_hello<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    config: &ServerConfig,
+) -> Result<(Login, u8), Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut handshake = ServerHandshake::new(
+        rand::random(),
+        config.session_validity,
+        config.handshake_timeout,
+    );
+
+    let mut message = read_frame(reader).await?;
+    if let Message::ClientResume(version, session, hash) = message {
+        let resumed =
+            conn::check_resume(version).map(|_| (Login::Resume(session, hash, version), version));
+        if let Err(err) = &resumed {
+            let log = Message::ServerLog(format!("{:?}", err));
+            let _ = write_frame(writer, &log, config::CURRENT_VERSION).await;
+        }
+        return resumed;
+    }
+    loop {
+        match handshake.handle(&message) {
+            Ok(answer) => write_frame(writer, &answer, handshake.version()).await?,
+            Err(err) => {
+                // Let the client know why, it might not be able to read it though.
+                let log = Message::ServerLog(format!("{:?}", err));
+                let _ = write_frame(writer, &log, config::CURRENT_VERSION).await;
+                return Err(err);
+            }
+        }
+        if handshake.is_accepted() {
+            return Ok((conn::login(&handshake)?, handshake.version()));
+        }
+        message = read_frame(reader).await?;
+    }
+}
+
+/// Passes the messages of the client, which speaks [version], to the handler until the client
+/// disconnects, or is [quiet] for longer than it may be.
+/// Answers that do not need the handler are sent back with [answers].
+async fn read_client(
+    id: u64,
+    mut reader: ReadHalf<TlsStream<TcpStream>>,
+    to_server: Sender<InternalMessage>,
+    answers: mpsc::UnboundedSender<Message>,
+    version: u8,
+    quiet: Option<std::time::Duration>,
+    config: Arc<ServerConfig>,
+) -> Result<(), Error> {
+    // A key the client wants to add permissions with, but did not prove to own yet, with the id
+    // of the request.
+    let mut pending_key: Option<(u64, ServerHandshake)> = None;
+
+    loop {
+        let read = match quiet {
+            Some(quiet) => match timeout(quiet, read_frame(&mut reader)).await {
+                Ok(read) => read,
+                Err(_) => {
+                    return Err(Error::SimpleError(
+                        "Heartbeat: Client missed its heartbeats",
+                    ));
+                }
+            },
+            None => read_frame(&mut reader).await,
+        };
+        let message = match read {
+            Ok(message) => message,
+            Err(Error::ConnectionClosed) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        if let Some(answer) = conn::client_message(
+            id,
+            message,
+            &mut pending_key,
+            None,
+            version,
+            &to_server,
+            &config,
+        )? {
+            // The writing task stops only together with this one.
+            let _ = answers.send(answer);
+        }
+    }
+}
+
+/// Reads a single frame. Like [codec::read_message], but does not block the runtime.
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Message, Error> {
+    let mut len_buffer = [0u8; 4];
+    if let Err(err) = reader.read_exact(&mut len_buffer).await {
+        return Err(match err.kind() {
+            std::io::ErrorKind::UnexpectedEof => Error::ConnectionClosed,
+            _ => Error::SimpleErrorStr(format!("Read Message: {:?}", err)),
+        });
+    }
+
+    let len = u32::from_le_bytes(len_buffer) as usize;
+    if len > config::MAX_FRAME_SIZE {
+        return Err(Error::FrameTooLarge(len));
+    }
+
+    let mut payload = vec![0u8; len];
+    if let Err(err) = reader.read_exact(&mut payload).await {
+        return Err(match err.kind() {
+            std::io::ErrorKind::UnexpectedEof => Error::FrameTruncated,
+            _ => Error::SimpleErrorStr(format!("Read Message: {:?}", err)),
+        });
+    }
+
+    codec::decode(&payload)
+}
+
+/// Writes [message] in [version] as a single frame. Like [codec::write_message], but does not
+/// block the runtime.
+async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &Message,
+    version: u8,
+) -> Result<(), Error> {
+    let payload = codec::encode_version(message, version)?;
+    let mut frame = Vec::with_capacity(payload.len() + 4);
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&payload);
+    Error::from(writer.write_all(&frame).await)?;
+    Error::from(writer.flush().await)
+}
+
+#[cfg(test)]
+mod test {
+    use shared::{config, errors::Error, remote::message::Message};
+    use tokio::io::AsyncWriteExt;
+
+    use crate::async_conn::{read_frame, write_frame};
+
+    #[tokio::test]
+    async fn frames() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+
+        let messages = vec![
+            Message::ClientHash(42),
+            Message::ServerLog("x".repeat(1000)),
+        ];
+        let writer = tokio::spawn(async move {
+            for message in messages {
+                write_frame(&mut client, &message, config::CURRENT_VERSION)
+                    .await
+                    .unwrap();
+            }
+            // Only the length of a frame that never comes.
+            client.write_all(&[10, 0, 0, 0, 1]).await.unwrap();
+        });
+
+        assert!(matches!(
+            read_frame(&mut server).await,
+            Ok(Message::ClientHash(42))
+        ));
+        match read_frame(&mut server).await {
+            Ok(Message::ServerLog(log)) => assert_eq!(log.len(), 1000),
+            result => panic!("Expected ServerLog got {:?}", result),
+        }
+        writer.await.unwrap();
+        assert!(matches!(
+            read_frame(&mut server).await,
+            Err(Error::FrameTruncated)
+        ));
+        assert!(matches!(
+            read_frame(&mut server).await,
+            Err(Error::ConnectionClosed)
+        ));
+    }
+}
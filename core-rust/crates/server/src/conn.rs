@@ -1,59 +1,385 @@
-use native_tls::TlsStream;
-use shared::{config, errors::Error};
+use crossbeam::channel::{Receiver, Sender, TryRecvError};
+use shared::{
+    config::{self, CURRENT_VERSION},
+    errors::Error,
+    remote::{
+        admission::Admission,
+        codec::FramedStream,
+        heartbeat::Heartbeat,
+        message::{Message, NackCode, nack},
+        version::{Capabilities, HEARTBEAT_VERSION},
+    },
+    security::{exchange::Binding, fingerprint::fingerprint, handshake::ServerHandshake},
+};
 use std::{
-    io::Read,
-    net::TcpStream,
+    io::ErrorKind,
+    net::{TcpListener, TcpStream},
     sync::{Arc, Mutex},
-    thread,
+    thread::{self, JoinHandle},
+    time::Instant,
 };
 
-use crate::{Server, helper::ServerHelper, util};
+use crate::{
+    helper::ServerHelper,
+    internal_message::{InternalMessage, Login},
+    server_config::ServerConfig,
+    shutdown::Connections,
+    tls::{self, Acceptor, Stream},
+};
 
-pub fn serve_server(pfx_path: &str, address: &str, server: ServerHelper) -> Result<(), Error> {
-    // first pfx file.
-    let identity = util::load_identity(pfx_path)?;
-    // create listener and acceptor
-    let (listener, acceptor) = util::create_listener(address, identity)?;
+/// What a client is told when the server stops.
+pub(crate) const GOODBYE: &str = "Server is shutting down";
 
+/// Accepts clients on [listener] until [connections] stops. Each client is served on its own
+/// thread, as long as [admission] has a slot for it. At most [config::MAX_REJECTIONS] of the
+/// others are told that the server is full, the rest are closed right away.
+/// Returns the threads of the connections that are still open.
+pub fn serve_server(
+    listener: TcpListener,
+    acceptor: Arc<Acceptor>,
+    server: ServerHelper,
+    admission: Admission,
+    connections: Connections,
+    config: Arc<ServerConfig>,
+) -> Vec<JoinHandle<()>> {
     let server = Arc::new(Mutex::new(server));
+    let mut threads: Vec<JoinHandle<()>> = vec![];
+    let rejections = Admission::new(config::MAX_REJECTIONS);
 
-    let current_client_number = Arc::new(Mutex::new(0));
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                let acceptor = acceptor.clone();
-                let mut current_client_number = current_client_number.lock().unwrap();
-                if *current_client_number < config::MAX_CLIENTS {
-                    *current_client_number += 1;
-                    let server = server.clone();
-                    // Spawn new thread to handle stream.
-                    thread::spawn(move || {
-                        let stream = acceptor.accept(stream).unwrap();
-                        handle(stream, server);
-                    });
-                }
+    while !connections.is_stopping() {
+        let (stream, address) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(config::POLL_INTERVAL);
+                continue;
             }
-            Err(_) => {
-                // TODO log error.
+            Err(err) => {
+                // Like running out of file descriptors, which takes a while to change. The error
+                // ends up in the report of the shutdown.
+                connections.accept_failed(&err);
+                thread::sleep(config::POLL_INTERVAL);
+                continue;
             }
-        }
+        };
+        // The stream might inherit nonblocking from the listener.
+        let socket = match stream
+            .set_nonblocking(false)
+            .and_then(|_| stream.try_clone())
+        {
+            Ok(socket) => socket,
+            Err(_) => continue,
+        };
+
+        let acceptor = acceptor.clone();
+        let server = server.clone();
+        let admission = admission.clone();
+        let connections = connections.clone();
+        let config = config.clone();
+        let slot = admission.try_admit();
+        let rejecting = match &slot {
+            Some(_) => None,
+            None => match rejections.try_admit() {
+                Some(rejecting) => Some(rejecting),
+                // Too many clients are told already, this one is just closed.
+                None => continue,
+            },
+        };
+        let tracked = connections.track(address, socket);
+        threads.retain(|thread| !thread.is_finished());
+        // Spawn new thread to handle stream.
+        threads.push(thread::spawn(move || {
+            // The slot is given back and the connection is no longer tracked when the thread
+            // ends, however it ends.
+            let _tracked = tracked;
+            let _rejecting = rejecting;
+            let stream = accept(&acceptor, stream, &config);
+            let result = match slot {
+                Some(_slot) => stream.and_then(|stream| handle(stream, &acceptor, server, &config)),
+                None => stream.and_then(|stream| reject(stream, &admission, &config)),
+            };
+            if let Err(err) = result {
+                connections.failed(&err);
+            }
+        }));
     }
+    threads
+}
+
+/// Does the TLS handshake, which may take as long as the handshake of the protocol.
+fn accept(acceptor: &Acceptor, stream: TcpStream, config: &ServerConfig) -> Result<Stream, Error> {
+    Error::from(stream.set_read_timeout(Some(config.handshake_timeout)))?;
+    Error::from(stream.set_write_timeout(Some(config.handshake_timeout)))?;
+    let stream = acceptor.accept(stream)?;
+    Error::from(tls::socket(&stream).set_write_timeout(None))?;
+    Ok(stream)
+}
 
-    Ok(())
+/// Tells a client that there is no slot for it.
+fn reject(stream: Stream, admission: &Admission, config: &ServerConfig) -> Result<(), Error> {
+    Error::from(tls::socket(&stream).set_read_timeout(Some(config.read_timeout)))?;
+    admission.reject(&mut FramedStream::new(stream), config.handshake_timeout)
 }
 
-fn handle(mut stream: TlsStream<TcpStream>, server: Arc<Mutex<ServerHelper>>) -> Result<(), Error> {
+fn handle(
+    stream: Stream,
+    acceptor: &Acceptor,
+    server: Arc<Mutex<ServerHelper>>,
+    config: &ServerConfig,
+) -> Result<(), Error> {
+    // Reads time out regularly, so that messages of the handler can be sent in between.
+    Error::from(tls::socket(&stream).set_read_timeout(Some(config.read_timeout)))?;
+    let mut stream = FramedStream::new(stream);
+
+    let login = client_hello(&mut stream, acceptor, config)?;
+
     // First init and get the sending and recieving
-    let (to_server, from_server);
-    {
-        (to_server, from_server) = server.lock().unwrap().register()?;
+    let (id, to_server, from_server) = server.lock().unwrap().register(login)?;
+
+    let result = serve_client(id, &mut stream, &to_server, &from_server, config);
+
+    // The handler should no longer send anything to this client.
+    let _ = to_server.send(InternalMessage::Unregister(id));
+    result
+}
+
+/// Does the key exchange, if the client starts with it, and the handshake with a new client.
+/// A client that resumes its session is accepted by the handler instead, which knows its session.
+fn client_hello(
+    stream: &mut FramedStream<Stream>,
+    acceptor: &Acceptor,
+    config: &ServerConfig,
+) -> Result<Login, Error> {
+    let mut message = stream.recv_timeout(config.handshake_timeout)?;
+    if let Message::ClientKeyExchange(_) = message {
+        let exchanged = match acceptor.exchange() {
+            Some(exchange) => exchange.answer(&message),
+            None => Err(Error::SimpleError("Key exchange: Server has no key for it")),
+        };
+        let (answer, channel) = match exchanged {
+            Ok(exchanged) => exchanged,
+            Err(err) => return refuse(stream, err),
+        };
+        stream.send(&answer)?;
+        stream.secure(channel);
+        message = stream.recv_timeout(config.handshake_timeout)?;
+    } else if acceptor.needs_exchange() {
+        let err = Error::SimpleError("Key exchange: Server needs it without TLS");
+        return refuse(stream, err);
     }
 
+    let mut handshake = ServerHandshake::new(
+        rand::random(),
+        config.session_validity,
+        config.handshake_timeout,
+    );
+    if let Some(binding) = stream.binding() {
+        handshake = handshake.bind(binding);
+    }
+
+    if let Message::ClientResume(version, session, hash) = message {
+        if let Err(err) = check_resume(version) {
+            return refuse(stream, err);
+        }
+        // The handler answers in the version the client resumes with.
+        stream.set_version(version);
+        return Ok(Login::Resume(session, hash, version));
+    }
+    loop {
+        match handshake.handle(&message) {
+            Ok(answer) => {
+                stream.set_version(handshake.version());
+                stream.send(&answer)?
+            }
+            Err(err) => return refuse(stream, err),
+        }
+        if handshake.is_accepted() {
+            return login(&handshake);
+        }
+        message = stream.recv_timeout(config.handshake_timeout)?;
+    }
+}
+
+/// A client resumes with the version it negotiated before, which has to be one the server
+/// speaks.
+pub(crate) fn check_resume(version: u8) -> Result<(), Error> {
+    if (config::MIN_VERSION..=CURRENT_VERSION).contains(&version) {
+        Ok(())
+    } else {
+        Err(Error::SimpleErrorStr(format!(
+            "Handshake: Server does not speak version {version} to resume"
+        )))
+    }
+}
+
+/// Lets the client know why the handshake failed, it might not be able to read it though.
+fn refuse<T>(stream: &mut FramedStream<Stream>, err: Error) -> Result<T, Error> {
+    let _ = stream.send(&Message::ServerLog(format!("{:?}", err)));
+    Err(err)
+}
+
+/// The new session of a client that finished [handshake].
+pub(crate) fn login(handshake: &ServerHandshake) -> Result<Login, Error> {
+    let fingerprint = match handshake.public_key() {
+        Some(key) => Some(fingerprint(key)?),
+        None => None,
+    };
+    Ok(Login::New(
+        handshake.session(),
+        fingerprint,
+        handshake.version(),
+        handshake.negotiated(),
+    ))
+}
+
+/// Passes messages between the client and the handler until one of them stops, or the client
+/// misses its heartbeats.
+fn serve_client(
+    id: u64,
+    stream: &mut FramedStream<Stream>,
+    to_server: &Sender<InternalMessage>,
+    from_server: &Receiver<InternalMessage>,
+    config: &ServerConfig,
+) -> Result<(), Error> {
+    // A key the client wants to add permissions with, but did not prove to own yet, with the id
+    // of the request.
+    let mut pending_key: Option<(u64, ServerHandshake)> = None;
+    let mut heartbeat = (stream.version() >= HEARTBEAT_VERSION)
+        .then(|| Heartbeat::new(config.heartbeat_interval, config.missed_heartbeats));
+
     loop {
-        // first recieve the length.
-        let mut len_buffer = [0u8; 4];
-        stream
-            .read_exact(&mut len_buffer)
-            .expect("Should be able to read from stream.");
+        if let Some(heartbeat) = &mut heartbeat {
+            if heartbeat.is_dead() {
+                return Err(Error::SimpleError(
+                    "Heartbeat: Client missed its heartbeats",
+                ));
+            }
+            if let Some(ping) = heartbeat.ping() {
+                stream.send(&ping)?;
+            }
+        }
+
+        // first send everything the handler has for this client.
+        loop {
+            match from_server.try_recv() {
+                Ok(InternalMessage::TreeChange(change)) => {
+                    stream.send(&Message::ServerChange(change))?
+                }
+                Ok(InternalMessage::Message(_, message)) => stream.send(&message)?,
+                // Everything sent before was sent already.
+                Ok(InternalMessage::Quit) | Err(TryRecvError::Disconnected) => {
+                    return goodbye(stream, config);
+                }
+                Ok(_) => {}
+                Err(TryRecvError::Empty) => break,
+            }
+        }
+
+        // then recieve from the client.
+        match stream.recv() {
+            Ok(Some(message)) => {
+                if let Some(heartbeat) = &mut heartbeat {
+                    heartbeat.seen();
+                }
+                let (binding, version) = (stream.binding(), stream.version());
+                let answer = client_message(
+                    id,
+                    message,
+                    &mut pending_key,
+                    binding,
+                    version,
+                    to_server,
+                    config,
+                )?;
+                if let Some(answer) = answer {
+                    stream.send(&answer)?;
+                }
+            }
+            Ok(None) => {}
+            Err(Error::ConnectionClosed) => return Ok(()),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Tells the client that the server stops and waits for it to close the connection.
+fn goodbye(stream: &mut FramedStream<Stream>, config: &ServerConfig) -> Result<(), Error> {
+    stream.send(&Message::ServerGoodbye(GOODBYE.to_string()))?;
+    let deadline = Instant::now() + config.handshake_timeout;
+    while Instant::now() < deadline {
+        match stream.recv() {
+            Err(Error::ConnectionClosed) => return Ok(()),
+            Err(err) => return Err(err),
+            // The client sent this before it got the goodbye.
+            Ok(_) => {}
+        }
+    }
+    Err(Error::SimpleError(
+        "Goodbye: Client did not close the connection",
+    ))
+}
+
+/// Handles a message of an accepted client, which speaks [version]. Keys the client wants to add
+/// permissions with are checked here, bound to the channel of the key exchange if there was one.
+/// The handler acknowledges them once it added their permissions. Everything else is passed to
+/// the handler.
+/// Returns the answer for the client, if there is one.
+pub(crate) fn client_message(
+    id: u64,
+    message: Message,
+    pending_key: &mut Option<(u64, ServerHandshake)>,
+    binding: Option<Binding>,
+    version: u8,
+    to_server: &Sender<InternalMessage>,
+    config: &ServerConfig,
+) -> Result<Option<Message>, Error> {
+    let refuse_key = |request, err: Error| {
+        let log = format!("{:?}", err);
+        Ok(Some(nack(
+            version,
+            request,
+            NackCode::AuthenticationFailed,
+            log,
+        )))
+    };
+    match message {
+        Message::ClientAddPermissions(request, n, e) => {
+            // The key is checked like in the handshake.
+            let mut handshake = ServerHandshake::new(0, 0, config.handshake_timeout);
+            if let Some(binding) = binding {
+                handshake = handshake.bind(binding);
+            }
+            match handshake.handle(&Message::ClientHello(
+                CURRENT_VERSION,
+                Some((n, e)),
+                Capabilities::NONE,
+            )) {
+                Ok(challenge) => {
+                    *pending_key = Some((request, handshake));
+                    Ok(Some(challenge))
+                }
+                Err(err) => refuse_key(request, err),
+            }
+        }
+        Message::ClientAuth(nonce) if pending_key.is_some() => {
+            let (request, mut handshake) = pending_key.take().unwrap();
+            let result = handshake.handle(&Message::ClientAuth(nonce));
+            match (result, handshake.public_key()) {
+                (Ok(_), Some(key)) => {
+                    let fingerprint = fingerprint(key)?;
+                    let added = InternalMessage::AddKey(id, fingerprint, request);
+                    Error::from(to_server.send(added))?;
+                    Ok(None)
+                }
+                (Err(err), _) => refuse_key(request, err),
+                (Ok(_), None) => Ok(None),
+            }
+        }
+        Message::Ping(counter) => Ok(Some(Message::Pong(counter))),
+        // Only shows that the client is alive.
+        Message::Pong(_) => Ok(None),
+        message => {
+            Error::from(to_server.send(InternalMessage::Message(id, message)))?;
+            Ok(None)
+        }
     }
 }
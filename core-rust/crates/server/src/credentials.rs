@@ -0,0 +1,228 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use rsa::RsaPublicKey;
+use shared::{
+    errors::Error,
+    security::{fingerprint::fingerprint, permissions::Permissions},
+};
+
+/// Maps the fingerprints of client keys to the permissions the clients get.
+/// Clients whose key is unknown or who have no key get [Permissions::Public].
+///
+/// The file format has one key per line, the fingerprint followed by the permissions:
+///
+/// ```text
+/// # Comments start with #
+/// 3f1c...e2 admin
+/// 9a0b...41 user operators,maintenance
+/// 77d2...0c user
+/// ```
+#[derive(Default)]
+pub struct CredentialStore {
+    path: Option<PathBuf>,
+    entries: HashMap<String, Permissions>,
+}
+
+impl CredentialStore {
+    /// Creates an empty store that is not backed by a file.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads the store from a file. [CredentialStore::save] writes back to this file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) => {
+                return Err(Error::SimpleErrorStr(format!(
+                    "Couldnt read credentials file {}: {:?}",
+                    path.display(),
+                    err
+                )));
+            }
+        };
+
+        let mut entries = HashMap::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (fingerprint, permissions) = match line.split_once(char::is_whitespace) {
+                Some(entry) => entry,
+                None => {
+                    return Err(Error::SimpleErrorStr(format!(
+                        "{}:{}: Missing permissions",
+                        path.display(),
+                        number + 1
+                    )));
+                }
+            };
+            let permissions = match permissions.parse() {
+                Ok(permissions) => permissions,
+                Err(err) => {
+                    return Err(Error::SimpleErrorStr(format!(
+                        "{}:{}: {:?}",
+                        path.display(),
+                        number + 1,
+                        err
+                    )));
+                }
+            };
+            entries.insert(fingerprint.to_lowercase(), permissions);
+        }
+
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            entries,
+        })
+    }
+
+    /// Reads the file the store was loaded from again.
+    pub fn reload(&mut self) -> Result<(), Error> {
+        match &self.path {
+            Some(path) => {
+                *self = Self::load(path.clone())?;
+                Ok(())
+            }
+            None => Err(Error::SimpleError(
+                "Credentials: Store was not loaded from a file",
+            )),
+        }
+    }
+
+    /// Writes the store back to the file it was loaded from.
+    pub fn save(&self) -> Result<(), Error> {
+        match &self.path {
+            Some(path) => self.save_to(path),
+            None => Err(Error::SimpleError(
+                "Credentials: Store was not loaded from a file",
+            )),
+        }
+    }
+
+    /// Writes the store to [path].
+    pub fn save_to(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        // Sorted, so that the file does not change without a reason.
+        let mut entries: Vec<_> = self.entries.iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+
+        let mut content = String::new();
+        for (fingerprint, permissions) in entries {
+            content.push_str(&format!("{fingerprint} {permissions}\n"));
+        }
+        Error::from(fs::write(path, content))
+    }
+
+    /// Sets the permissions for the key with [fingerprint]. Returns the previous permissions.
+    pub fn insert(
+        &mut self,
+        fingerprint: impl Display,
+        permissions: Permissions,
+    ) -> Option<Permissions> {
+        self.entries
+            .insert(fingerprint.to_string().to_lowercase(), permissions)
+    }
+
+    /// Sets the permissions for [key]. Returns the previous permissions.
+    pub fn insert_key(
+        &mut self,
+        key: &RsaPublicKey,
+        permissions: Permissions,
+    ) -> Result<Option<Permissions>, Error> {
+        Ok(self.insert(fingerprint(key)?, permissions))
+    }
+
+    /// Removes the key with [fingerprint]. Returns its permissions.
+    pub fn remove(&mut self, fingerprint: &str) -> Option<Permissions> {
+        self.entries.remove(&fingerprint.to_lowercase())
+    }
+
+    pub fn get(&self, fingerprint: &str) -> Option<&Permissions> {
+        self.entries.get(&fingerprint.to_lowercase())
+    }
+
+    /// Returns the permissions of a client with the keys [fingerprints].
+    /// The permissions of all known keys are merged, without any known key the client is public.
+    pub fn permissions_for(&self, fingerprints: &[String]) -> Permissions {
+        fingerprints
+            .iter()
+            .filter_map(|fingerprint| self.get(fingerprint))
+            .fold(Permissions::Public, |acc, permissions| {
+                acc.merge(permissions)
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use shared::security::permissions::Permissions;
+
+    use crate::credentials::CredentialStore;
+
+    #[test]
+    fn load_and_save() {
+        let path = std::env::temp_dir().join(format!("fscp-credentials-{}", std::process::id()));
+        fs::write(
+            &path,
+            "# Test\n\nAAAA admin\nbbbb user a,b\ncccc user\n\ndddd public\n",
+        )
+        .unwrap();
+
+        let mut store = CredentialStore::load(&path).unwrap();
+        assert_eq!(store.get("aaaa"), Some(&Permissions::Admin));
+        assert_eq!(
+            store.get("bbbb"),
+            Some(&Permissions::User(Some(vec![
+                "a".to_string(),
+                "b".to_string()
+            ])))
+        );
+
+        store.remove("aaaa");
+        store.insert("eeee", Permissions::User(None));
+        store.save().unwrap();
+
+        let store = CredentialStore::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(store.get("aaaa"), None);
+        assert_eq!(store.get("eeee"), Some(&Permissions::User(None)));
+        assert_eq!(store.get("dddd"), Some(&Permissions::Public));
+    }
+
+    #[test]
+    fn invalid_file() {
+        let path = std::env::temp_dir().join(format!("fscp-invalid-{}", std::process::id()));
+        fs::write(&path, "aaaa superuser\n").unwrap();
+        let result = CredentialStore::load(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn permissions_for() {
+        let mut store = CredentialStore::new();
+        store.insert("aaaa", Permissions::User(Some(vec!["a".to_string()])));
+        store.insert("bbbb", Permissions::User(Some(vec!["b".to_string()])));
+
+        // Anonymous and unknown clients are public.
+        assert_eq!(store.permissions_for(&[]), Permissions::Public);
+        assert_eq!(
+            store.permissions_for(&["ffff".to_string()]),
+            Permissions::Public
+        );
+        assert_eq!(
+            store.permissions_for(&["aaaa".to_string(), "bbbb".to_string()]),
+            Permissions::User(Some(vec!["a".to_string(), "b".to_string()]))
+        );
+    }
+}
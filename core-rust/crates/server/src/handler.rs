@@ -1,15 +1,342 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
 
 use crossbeam::channel::{Receiver, Sender, select_biased};
-use shared::{datatypes::nodes::Node, errors::Error, remote::message::Message};
+use shared::{
+    config,
+    datatypes::{
+        Data,
+        nodes::Node,
+        snapshot::Snapshot,
+        treebuilder::{TreeBuilder, TreeChange},
+    },
+    errors::Error,
+    remote::{
+        message::{Message, NackCode, nack},
+        version::{Capabilities, MOVE_VERSION, REQUEST_VERSION},
+    },
+    security::permissions::Permissions,
+};
+use uuid::Uuid;
 
-use crate::internal_message::InternalMessage;
+use crate::{
+    credentials::CredentialStore,
+    internal_message::{ClientSender, Command, InternalMessage, Login, SendError},
+    log::ErrorLog,
+    press::{Press, PressCallbacks},
+};
+
+/// Everything the handler knows about a client.
+struct Client {
+    /// [None] while the connection is lost. The view and history are still kept up to date, so
+    /// the client can resume its session.
+    sender: Option<ClientSender>,
+    session: u64,
+    /// What the client and the server both support, it is accepted with them again when it
+    /// resumes.
+    capabilities: Capabilities,
+    /// The version of the protocol the client speaks. Older clients get logs instead of
+    /// [Message::ServerAck] and [Message::ServerNack] and cannot move nodes.
+    version: u8,
+    /// When the connection was lost.
+    detached: Option<Instant>,
+    /// Fingerprints of all keys the client proved to own.
+    fingerprints: Vec<String>,
+    permissions: Permissions,
+    /// The part of the tree the client can see. This is what the client mirrors.
+    view: Node,
+    /// The last changes sent to the client. Their hashes are only needed when the client tells
+    /// its hash, so they are not kept and the view is not hashed for every change.
+    history: VecDeque<TreeChange>,
+    /// The view before the oldest change in [Client::history].
+    history_base: Node,
+    /// The queue of the client was full or its view fell out of sync, so it missed changes. It
+    /// gets a [Snapshot] once it read everything in its queue.
+    lagging: bool,
+}
+
+impl Client {
+    fn new(
+        sender: ClientSender,
+        session: u64,
+        version: u8,
+        capabilities: Capabilities,
+        fingerprints: Vec<String>,
+        permissions: Permissions,
+    ) -> Self {
+        Self {
+            sender: Some(sender),
+            session,
+            capabilities,
+            version,
+            detached: None,
+            fingerprints,
+            permissions,
+            view: Node::root(),
+            history: VecDeque::new(),
+            history_base: Node::root(),
+            lagging: false,
+        }
+    }
+
+    /// Queues [message] for the client. Returns [false] if the client is gone.
+    /// A full queue makes the client lagging instead of blocking the handler.
+    fn push(&mut self, message: InternalMessage) -> bool {
+        let Some(sender) = &self.sender else {
+            // Whatever it missed is sent when it resumes.
+            return true;
+        };
+        match sender.try_send(message) {
+            Ok(()) => true,
+            Err(SendError::Full) => {
+                self.lagging = true;
+                true
+            }
+            Err(SendError::Gone) => false,
+        }
+    }
+
+    /// Sends a lagging client a [Snapshot] once it read its queue.
+    /// Returns [false] if the client is gone.
+    fn catch_up(&mut self, root: &Node) -> bool {
+        if self.lagging && self.sender.as_ref().is_some_and(|sender| sender.is_empty()) {
+            self.send_snapshot(view(root, &self.permissions))
+        } else {
+            true
+        }
+    }
+
+    /// Returns [true] if the connection was lost longer than [validity] ago, then the session
+    /// cannot be resumed anymore.
+    fn is_expired(&self, validity: Duration) -> bool {
+        self.detached
+            .is_some_and(|since| since.elapsed() > validity)
+    }
+
+    /// Returns the changes the client has to see after [change] was applied to [root].
+    fn changes_for(&self, root: &Node, change: &TreeChange) -> Vec<TreeChange> {
+        match change {
+            TreeChange::NodeAdded(_, _, id, parent) => {
+                if self.view.find_node(parent).is_none() {
+                    return vec![];
+                }
+                match root
+                    .find_node(id)
+                    .and_then(|node| node.filter(&self.permissions))
+                {
+                    Some(node) => TreeBuilder::added(&node, *parent),
+                    None => vec![],
+                }
+            }
+            TreeChange::NodeRemoved(id)
+            | TreeChange::NodeChangedName(id, _)
+            | TreeChange::NodeChangedData(id, _) => {
+                if self.view.find_node(id).is_some() {
+                    vec![change.clone()]
+                } else {
+                    vec![]
+                }
+            }
+            TreeChange::NodeMoved(id, parent, _) => self.moved(root, id, parent),
+            // Permissions are not part of the view, but they decide what is in it.
+            TreeChange::NodeChangedPermissions(_, _) => self.resync(root),
+        }
+    }
+
+    /// Returns the changes the client has to see after the node with [id] moved to [parent].
+    /// The client might not have seen the node before or might not see it afterwards. Clients
+    /// that cannot move nodes get it removed and added again at the end of its new parent.
+    fn moved(&self, root: &Node, id: &Uuid, parent: &Uuid) -> Vec<TreeChange> {
+        let seen = self.view.find_node(id).is_some();
+        let visible = match self.view.find_node(parent) {
+            Some(_) => root
+                .find_node(id)
+                .and_then(|node| node.filter(&self.permissions)),
+            None => None,
+        };
+        let node = match visible {
+            Some(node) => node,
+            None if seen => return vec![TreeChange::NodeRemoved(*id)],
+            None => return vec![],
+        };
+
+        // Only the siblings the client can see count for its position.
+        let position = root
+            .find_node(parent)
+            .and_then(|parent| parent.children.as_ref())
+            .map_or(0, |children| {
+                children
+                    .iter()
+                    .take_while(|child| child.id != *id)
+                    .filter(|child| self.view.find_node(&child.id).is_some())
+                    .count()
+            });
+        let moved = TreeChange::NodeMoved(*id, *parent, position);
+        if seen && self.version >= MOVE_VERSION {
+            return vec![moved];
+        }
+
+        let mut changes = vec![];
+        if seen {
+            changes.push(TreeChange::NodeRemoved(*id));
+        }
+        changes.extend(TreeBuilder::added(&node, *parent));
+        if self.version >= MOVE_VERSION {
+            changes.push(moved);
+        }
+        changes
+    }
+
+    /// Returns the changes that turn the current view into the one of [root].
+    fn resync(&self, root: &Node) -> Vec<TreeChange> {
+        view_changes(&self.view, &view(root, &self.permissions))
+    }
+
+    /// Returns the changes a client with the tree [hash] is missing.
+    /// Returns [None] if the hash is not in the history, then only a [Snapshot] helps.
+    /// Replays the history to hash the view after each change, which only happens when a client
+    /// resumes or tells its hash.
+    fn missing_changes(&self, hash: u64) -> Option<Vec<TreeChange>> {
+        if hash == self.view.get_hash() {
+            return Some(vec![]);
+        }
+
+        // The newest match is the right one, a tree can have the same hash more than once.
+        let mut tree = self.history_base.clone();
+        let mut skip = (tree.get_hash() == hash).then_some(0);
+        for (index, change) in self.history.iter().enumerate() {
+            // Was applied to the view like this before.
+            TreeBuilder::change(&mut tree, change.clone()).ok()?;
+            if tree.get_hash() == hash {
+                skip = Some(index + 1);
+            }
+        }
+        Some(self.history.iter().skip(skip?).cloned().collect())
+    }
+
+    /// Sets the view and sends all of it to the client. Returns [false] if the client is gone.
+    fn send_snapshot(&mut self, view: Node) -> bool {
+        let snapshot = Snapshot::new(&view);
+        self.history.clear();
+        self.history_base = view.clone();
+        self.view = view;
+        self.lagging = false;
+        self.push(InternalMessage::Message(0, Message::Snapshot(snapshot)))
+    }
+
+    /// Sends [changes] the view already contains. Returns [false] if the client is gone.
+    fn resend(&mut self, changes: Vec<TreeChange>) -> bool {
+        changes
+            .into_iter()
+            .all(|change| self.lagging || self.push(InternalMessage::TreeChange(change)))
+    }
+
+    /// Applies [changes] to the view and sends them to the client.
+    /// Returns [false] if the client is gone.
+    fn send_changes(&mut self, changes: Vec<TreeChange>) -> bool {
+        for change in changes {
+            // The snapshot it gets later contains the change.
+            if self.lagging {
+                return true;
+            }
+
+            match TreeBuilder::change(&mut self.view, change.clone()) {
+                Ok(()) => {
+                    if self.history.len() == config::HASH_HISTORY
+                        && let Some(oldest) = self.history.pop_front()
+                        && TreeBuilder::change(&mut self.history_base, oldest).is_err()
+                    {
+                        // Cannot happen, as the change was applied to the view like this.
+                        self.lagging = true;
+                        return true;
+                    }
+                    self.history.push_back(change.clone());
+                }
+                Err(_) => {
+                    // The view cannot follow the tree anymore. Like a lagging client, the client
+                    // gets a snapshot instead of the change.
+                    self.lagging = true;
+                    return true;
+                }
+            }
+            if !self.push(InternalMessage::TreeChange(change)) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Returns the part of the tree below [root] that a client with [permissions] can see.
+/// The root itself is always there, as every client mirrors the tree into its own root.
+fn view(root: &Node, permissions: &Permissions) -> Node {
+    match root.filter(permissions) {
+        Some(view) => view,
+        None => {
+            let mut view = Node::root()
+                .id(root.id)
+                .data(root.data.clone())
+                .permissions(root.permissions.clone());
+            view.name = root.name.clone();
+            view
+        }
+    }
+}
+
+/// Returns the changes that turn the view [old] into [new].
+/// Subtrees that disappear are removed first, then the ones that appear are added.
+fn view_changes(old: &Node, new: &Node) -> Vec<TreeChange> {
+    // Only the topmost node of a subtree is needed, its children go with it.
+    fn missing(node: &Node, other: &HashSet<Uuid>, found: &mut Vec<(Uuid, Uuid)>) {
+        if let Some(children) = &node.children {
+            for child in children {
+                if other.contains(&child.id) {
+                    missing(child, other, found);
+                } else {
+                    found.push((child.id, node.id));
+                }
+            }
+        }
+    }
+
+    let old_ids: HashSet<Uuid> = old.iter().map(|node| node.id).collect();
+    let new_ids: HashSet<Uuid> = new.iter().map(|node| node.id).collect();
+
+    let mut removed = vec![];
+    let mut added = vec![];
+    missing(old, &new_ids, &mut removed);
+    missing(new, &old_ids, &mut added);
+
+    let mut changes: Vec<TreeChange> = removed
+        .into_iter()
+        .map(|(id, _)| TreeChange::NodeRemoved(id))
+        .collect();
+    for (id, parent) in added {
+        if let Some(node) = new.find_node(&id) {
+            changes.extend(TreeBuilder::added(node, parent));
+        }
+    }
+    changes
+}
 
 // Handles the managment of the tree.
 pub(crate) struct ServerHandler {
     root: Node,
-    /// Map that connects an client id to a sending channel
-    clients: HashMap<u64, Sender<InternalMessage>>,
+    /// Map that connects an client id to its sending channel and permissions
+    clients: HashMap<u64, Client>,
+    credentials: Arc<RwLock<CredentialStore>>,
+    callbacks: PressCallbacks,
+    /// Where the errors go that nobody waits for.
+    errors: ErrorLog,
+    /// How many messages can wait for a client before it is lagging.
+    queue_size: usize,
+    /// How long a session can be resumed after the connection was lost.
+    session_validity: u16,
+    /// How many clients that lost their connection are kept at most.
+    max_detached: usize,
     to_server_s: Sender<InternalMessage>,
     from_server_r: Receiver<InternalMessage>,
     from_clients_r: Receiver<InternalMessage>,
@@ -17,40 +344,1310 @@ pub(crate) struct ServerHandler {
 
 impl ServerHandler {
     pub fn new(
+        root: Node,
+        credentials: Arc<RwLock<CredentialStore>>,
         to_server_s: Sender<InternalMessage>,
         from_server_r: Receiver<InternalMessage>,
         from_clients_r: Receiver<InternalMessage>,
     ) -> Self {
         Self {
-            root: Node::new().permissions(shared::security::permissions::Permissions::Public),
+            root,
             clients: HashMap::new(),
-            to_server_s: to_server_s,
-            from_server_r: from_server_r,
+            credentials,
+            callbacks: PressCallbacks::new(),
+            errors: ErrorLog::default(),
+            queue_size: config::CLIENT_QUEUE_SIZE,
+            session_validity: config::SESSION_VALIDITY,
+            max_detached: config::MAX_CLIENTS as usize,
+            to_server_s,
+            from_server_r,
             from_clients_r,
         }
     }
 
+    /// Sets the callbacks that run when a client presses a button.
+    pub fn callbacks(mut self, callbacks: PressCallbacks) -> Self {
+        self.callbacks = callbacks;
+        self
+    }
+
+    /// Sets where errors are reported that do not stop the handler.
+    pub fn errors(mut self, errors: ErrorLog) -> Self {
+        self.errors = errors;
+        self
+    }
+
+    /// Sets how many messages can wait for a client. A client that does not read fast enough
+    /// skips changes and gets a [Snapshot] later instead.
+    pub fn queue_size(mut self, queue_size: usize) -> Self {
+        self.queue_size = queue_size;
+        self
+    }
+
+    /// Sets how long a session can be resumed in seconds and how many sessions without a
+    /// connection are kept at most. The oldest ones are dropped first.
+    pub fn sessions(mut self, validity: u16, max_detached: usize) -> Self {
+        self.session_validity = validity;
+        self.max_detached = max_detached;
+        self
+    }
+
+    /// Runs the handler until a [InternalMessage::Quit] is recieved or every sender is gone.
+    /// Messages of the server are always handled before messages of clients.
     pub fn run(mut self) -> Result<(), Error> {
         loop {
-            select_biased! {
-                recv(self.from_server_r) -> msg => {
-                    // let quit = self.handle_server_message(Error::from(msg)?)?;
-                    //
-                    // if quit {
-                    //     self.quit();
-                    //     return Ok(())
-                    // }
-                },
-                recv(self.from_clients_r) -> msg => {
-                   // self.handle_client_message(Error::from(msg)?)?;
+            // Wakes up regularly to check on lagging clients.
+            let msg = select_biased! {
+                recv(self.from_server_r) -> msg => msg,
+                recv(self.from_clients_r) -> msg => msg,
+                default(config::POLL_INTERVAL) => {
+                    self.catch_up();
+                    continue;
+                }
+            };
+
+            let msg = match msg {
+                Ok(msg) => msg,
+                Err(_) => {
+                    // All senders are gone, so nobody can talk to the handler anymore.
+                    self.quit();
+                    return Ok(());
                 }
             };
+
+            if self.handle_msg(msg)? {
+                self.quit();
+                return Ok(());
+            }
+            self.catch_up();
         }
     }
 
+    /// Sends lagging clients a [Snapshot] and forgets sessions that expired.
+    fn catch_up(&mut self) {
+        let root = &self.root;
+        let validity = Duration::from_secs(self.session_validity as u64);
+        self.clients
+            .retain(|_, client| !client.is_expired(validity) && client.catch_up(root));
+    }
+
+    /// Tells every client to stop after the messages already queued for it and forgets about them.
     fn quit(&mut self) {
-        // Quit
+        for (_, client) in self.clients.drain() {
+            // A client that is already gone does not need to be told. One with a full queue
+            // notices that its channel is closed once it read the queue.
+            if let Some(sender) = client.sender {
+                let _ = sender.try_send(InternalMessage::Quit);
+            }
+        }
     }
 
-    fn handle_msg(&mut self, msg: InternalMessage) {}
+    /// Handles a single message. Returns [true] if the handler should quit.
+    fn handle_msg(&mut self, msg: InternalMessage) -> Result<bool, Error> {
+        match msg {
+            InternalMessage::Register(id, login) => self.register(id, login)?,
+            #[cfg(feature = "tokio")]
+            InternalMessage::Attach(id, login, sender) => {
+                // A connection that is already closed again is just not added.
+                let _ = self.add_client(id, login, sender);
+            }
+            InternalMessage::AddKey(id, fingerprint, request) => {
+                self.add_key(id, fingerprint, request)
+            }
+            InternalMessage::RefreshPermissions => self.refresh_permissions(),
+            InternalMessage::Unregister(id) => self.detach(id),
+            InternalMessage::TreeChange(change) => {
+                if let Err(err) = self.apply(change) {
+                    self.errors.report(&err);
+                }
+            }
+            InternalMessage::Message(id, msg) => self.handle_client_message(id, msg),
+            InternalMessage::Command(command, reply) => {
+                // The server might have stopped waiting, which is fine.
+                let _ = reply.send(self.command(command));
+            }
+            InternalMessage::Quit => return Ok(true),
+            // Only the handler sends these. Whoever sent it should not stop the other clients.
+            msg => {
+                let err = Error::SimpleErrorStr(format!("Handler: Cannot handle message {msg:?}"));
+                self.errors.report(&err);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Creates the channel handler -> client and sends the reciever back to the server.
+    /// The new client recieves a [Snapshot] of the part of the tree it can see.
+    fn register(&mut self, id: u64, login: Login) -> Result<(), Error> {
+        let (to_client_s, to_client_r) = crossbeam::channel::bounded(self.queue_size);
+        self.add_client(id, login, ClientSender::Thread(to_client_s))?;
+        Error::from(
+            self.to_server_s
+                .send(InternalMessage::RegisterResponse(id, to_client_r)),
+        )?;
+        Ok(())
+    }
+
+    /// Adds a client that recieves with [sender]. A new client gets a [Snapshot], one that
+    /// resumes its session only what it missed.
+    fn add_client(&mut self, id: u64, login: Login, sender: ClientSender) -> Result<(), Error> {
+        let (session, fingerprint, version, capabilities) = match login {
+            Login::New(session, fingerprint, version, capabilities) => {
+                (session, fingerprint, version, capabilities)
+            }
+            Login::Resume(session, hash, version) => {
+                return self.resume(id, session, hash, version, sender);
+            }
+        };
+        let fingerprints: Vec<String> = fingerprint.into_iter().collect();
+        let permissions = self.permissions_for(&fingerprints);
+        let mut client = Client::new(
+            sender,
+            session,
+            version,
+            capabilities,
+            fingerprints,
+            permissions,
+        );
+        if !client.send_snapshot(view(&self.root, &client.permissions)) {
+            return Err(Error::SimpleError("Handler: Client is gone"));
+        }
+        self.clients.insert(id, client);
+        Ok(())
+    }
+
+    /// Moves the client of [session] to the connection [id]. It is accepted and gets the
+    /// changes since it had the tree with [hash], or a [Snapshot] if they are not in its history.
+    /// A connection that still has the session loses it.
+    /// Sessions that are unknown or expired are refused, the connection closes after that.
+    fn resume(
+        &mut self,
+        id: u64,
+        session: u64,
+        hash: u64,
+        version: u8,
+        sender: ClientSender,
+    ) -> Result<(), Error> {
+        let validity = Duration::from_secs(self.session_validity as u64);
+        let previous = self
+            .clients
+            .iter()
+            .find(|(_, client)| client.session == session)
+            .map(|(id, _)| *id);
+        let mut client = match previous.and_then(|previous| self.clients.remove(&previous)) {
+            Some(client) if !client.is_expired(validity) => client,
+            _ => {
+                let refused = Message::ServerLog("Session is unknown or expired".to_string());
+                let _ = sender.try_send(InternalMessage::Message(0, refused));
+                return Ok(());
+            }
+        };
+
+        client.sender = Some(sender);
+        client.detached = None;
+        client.version = version;
+        let accept = Message::ServerAccept(session, self.session_validity, client.capabilities);
+        let sent = client.push(InternalMessage::Message(0, accept))
+            && match client.missing_changes(hash) {
+                Some(changes) if !client.lagging => client.resend(changes),
+                _ => client.send_snapshot(view(&self.root, &client.permissions)),
+            };
+        if !sent {
+            return Err(Error::SimpleError("Handler: Client is gone"));
+        }
+        self.clients.insert(id, client);
+        Ok(())
+    }
+
+    /// Keeps the client of a lost connection, so it can resume its session.
+    /// Only the [ServerHandler::max_detached] newest ones are kept.
+    fn detach(&mut self, id: u64) {
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.sender = None;
+            client.detached = Some(Instant::now());
+        }
+
+        let mut detached: Vec<(Instant, u64)> = self
+            .clients
+            .iter()
+            .filter_map(|(id, client)| client.detached.map(|since| (since, *id)))
+            .collect();
+        if detached.len() > self.max_detached {
+            detached.sort();
+            for (_, id) in &detached[..detached.len() - self.max_detached] {
+                self.clients.remove(id);
+            }
+        }
+    }
+
+    /// Adds the permissions of another key to a client.
+    fn add_key(&mut self, id: u64, fingerprint: String, request: u64) {
+        if let Some(client) = self.clients.get_mut(&id)
+            && !client.fingerprints.contains(&fingerprint)
+        {
+            client.fingerprints.push(fingerprint);
+        }
+        self.refresh_permissions();
+        // The client has the nodes of the key by the time it is acknowledged.
+        if self.version_of(id) >= REQUEST_VERSION {
+            self.send(id, Message::ServerAck(request));
+        }
+    }
+
+    /// Looks up the permissions of every client again, for example after the credentials changed.
+    /// Clients whose permissions changed are told so and get the nodes that appear or
+    /// disappear for them.
+    fn refresh_permissions(&mut self) {
+        let mut changed = vec![];
+        let store = match self.credentials.read() {
+            Ok(store) => store,
+            Err(_) => {
+                let err = Error::SimpleError("Handler: Credentials lock is poisoned");
+                self.errors.report(&err);
+                return;
+            }
+        };
+        for (id, client) in self.clients.iter_mut() {
+            let permissions = store.permissions_for(&client.fingerprints);
+            if permissions != client.permissions {
+                client.permissions = permissions;
+                changed.push(*id);
+            }
+        }
+        drop(store);
+
+        for id in changed {
+            self.send(id, Message::ServerRefreshPermissions);
+            if let Some(client) = self.clients.get_mut(&id) {
+                let changes = client.resync(&self.root);
+                if !client.send_changes(changes) {
+                    self.clients.remove(&id);
+                }
+            }
+        }
+    }
+
+    fn permissions_for(&self, fingerprints: &[String]) -> Permissions {
+        match self.credentials.read() {
+            Ok(store) => store.permissions_for(fingerprints),
+            Err(_) => Permissions::Public,
+        }
+    }
+
+    fn handle_client_message(&mut self, id: u64, msg: Message) {
+        match msg {
+            Message::ClientTrigger(request, node_id) => match self.press(id, &node_id) {
+                Ok(answers) => {
+                    if self.version_of(id) >= REQUEST_VERSION {
+                        self.send(id, Message::ServerAck(request));
+                    } else if answers.is_empty() {
+                        self.send_log(id, format!("Pressed {node_id}"));
+                    }
+                    for answer in answers {
+                        self.send_log(id, answer);
+                    }
+                }
+                Err((code, log)) => {
+                    let refused = nack(self.version_of(id), request, code, log);
+                    self.send(id, refused);
+                }
+            },
+            Message::ClientHash(hash) => {
+                let root = &self.root;
+                let gone = match self.clients.get_mut(&id) {
+                    Some(client) => match client.missing_changes(hash) {
+                        Some(changes) => !client.resend(changes),
+                        None => !client.send_snapshot(view(root, &client.permissions)),
+                    },
+                    None => false,
+                };
+                if gone {
+                    self.clients.remove(&id);
+                }
+            }
+            msg => self.send_log(id, format!("Cannot handle message {:?}", msg)),
+        }
+    }
+
+    /// Runs a command of the hosting server. The server can see and change everything.
+    fn command(&mut self, command: Command) -> Result<Option<Node>, Error> {
+        match command {
+            Command::AddChild(node, parent_id) => {
+                self.check_new(&node)?;
+                let change = self.add_child(node, parent_id)?;
+                self.broadcast(&change);
+            }
+            Command::AddChildAtPath(node, path) => {
+                // Checked first, so that no folders are left behind.
+                self.check_new(&node)?;
+                let (parent_id, folders) = self.root.create_path(&path)?;
+                let added = self.add_child(node, parent_id)?;
+                // Clients get everything below the first new folder together with it.
+                let change = folders.into_iter().next().unwrap_or(added);
+                self.broadcast(&change);
+            }
+            Command::ChangeData(id, data) => self.apply(TreeChange::NodeChangedData(id, data))?,
+            Command::ChangeName(id, name) => self.apply(TreeChange::NodeChangedName(id, name))?,
+            Command::ChangePermissions(id, permissions) => {
+                self.apply(TreeChange::NodeChangedPermissions(id, permissions))?
+            }
+            Command::Move(id, parent, position) => {
+                self.apply(TreeChange::NodeMoved(id, parent, position))?
+            }
+            Command::Remove(id) => {
+                if id == self.root.id {
+                    return Err(Error::SimpleError("Remove: The root cannot be removed"));
+                }
+                self.apply(TreeChange::NodeRemoved(id))?
+            }
+            Command::GetNode(id) => {
+                return match self.root.find_node(&id) {
+                    Some(node) => Ok(Some(node.clone())),
+                    None => Err(Error::SimpleErrorStr(format!(
+                        "Get: Cannot find node with id={:?}",
+                        id
+                    ))),
+                };
+            }
+        }
+        Ok(None)
+    }
+
+    // Ids are unique in the whole tree, including everything below the new node.
+    fn check_new(&self, node: &Node) -> Result<(), Error> {
+        let mut new = HashSet::new();
+        for node in node.iter() {
+            if !new.insert(node.id) || self.root.find_node(&node.id).is_some() {
+                return Err(Error::SimpleErrorStr(format!(
+                    "Add: Node with id={:?} already exists",
+                    node.id
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds [node] to the node with [parent_id]. Returns the change to broadcast.
+    fn add_child(&mut self, node: Node, parent_id: Uuid) -> Result<TreeChange, Error> {
+        let change =
+            TreeChange::NodeAdded(node.data.clone(), node.name.clone(), node.id, parent_id);
+        match self.root.find_node_mut(&parent_id) {
+            Some(parent) => {
+                parent.add_child(node);
+            }
+            None => {
+                return Err(Error::SimpleErrorStr(format!(
+                    "Add: Cannot find node with id={:?}",
+                    parent_id
+                )));
+            }
+        }
+        Ok(change)
+    }
+
+    /// Presses the button with [id] for [client] and lets everybody know.
+    /// Returns what the callbacks of the button answered, or why the client cannot press it.
+    fn press(&mut self, client: u64, id: &Uuid) -> Result<Vec<String>, (NackCode, String)> {
+        let node = match self.root.find_node(id) {
+            Some(node) => node,
+            None => {
+                return Err((
+                    NackCode::NotFound,
+                    format!("Press: Cannot find node with id={:?}", id),
+                ));
+            }
+        };
+
+        // A client can only press what it can see.
+        let visible = self
+            .clients
+            .get(&client)
+            .is_some_and(|client| client.view.find_node(id).is_some());
+        if !visible {
+            return Err((
+                NackCode::PermissionDenied,
+                "Press: Permission denied".to_string(),
+            ));
+        }
+
+        match node.data {
+            Data::Button(n) => {
+                let pressed = TreeChange::NodeChangedData(*id, Data::Button(n + 1));
+                if let Err(err) = self.apply(pressed) {
+                    return Err((NackCode::Failed, format!("{err:?}")));
+                }
+            }
+            _ => {
+                return Err((
+                    NackCode::NotAButton,
+                    format!("Press: Node is not a button ({:?})", id),
+                ));
+            }
+        }
+
+        // The client might be gone after the broadcast.
+        match (self.clients.get(&client), self.root.find_node(id)) {
+            (Some(c), Some(node)) => {
+                let press = Press {
+                    node,
+                    client,
+                    fingerprints: &c.fingerprints,
+                    permissions: &c.permissions,
+                };
+                Ok(self.callbacks.run(&self.root, &press))
+            }
+            _ => Ok(vec![]),
+        }
+    }
+
+    /// Changes the tree and sends the change to every client that can see it.
+    fn apply(&mut self, change: TreeChange) -> Result<(), Error> {
+        TreeBuilder::change(&mut self.root, change.clone())?;
+        self.broadcast(&change);
+        Ok(())
+    }
+
+    fn broadcast(&mut self, change: &TreeChange) {
+        let root = &self.root;
+        // Clients whose channel is closed are gone and are removed.
+        self.clients.retain(|_, client| {
+            let changes = client.changes_for(root, change);
+            client.send_changes(changes)
+        });
+    }
+
+    fn version_of(&self, id: u64) -> u8 {
+        self.clients
+            .get(&id)
+            .map_or(config::MIN_VERSION, |client| client.version)
+    }
+
+    fn send_log(&mut self, id: u64, log: String) {
+        self.send(id, Message::ServerLog(log));
+    }
+
+    fn send(&mut self, id: u64, message: Message) {
+        if let Some(client) = self.clients.get_mut(&id)
+            && !client.push(InternalMessage::Message(id, message))
+        {
+            self.clients.remove(&id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{Arc, RwLock},
+        thread::{self, JoinHandle},
+        time::Duration,
+    };
+
+    use crossbeam::channel::{Receiver, Sender};
+    use shared::{
+        config,
+        datatypes::{
+            Data,
+            nodes::Node,
+            treebuilder::{TreeBuilder, TreeChange},
+        },
+        errors::Error,
+        remote::{
+            message::{Message, NackCode},
+            version::{Capabilities, MOVE_VERSION, REQUEST_VERSION},
+        },
+        security::permissions::Permissions,
+    };
+    use uuid::Uuid;
+
+    use crate::{
+        credentials::CredentialStore,
+        handler::{Client, ServerHandler},
+        internal_message::{ClientSender, Command, InternalMessage, Login},
+        log::ErrorLog,
+        press::PressCallbacks,
+    };
+
+    // The channels to a handler running on its own thread.
+    struct Handler {
+        to_handler_s: Sender<InternalMessage>,
+        from_clients_s: Sender<InternalMessage>,
+        to_server_r: Receiver<InternalMessage>,
+        handle: JoinHandle<Result<(), Error>>,
+    }
+
+    impl Handler {
+        // Registers the client with [id] and returns what the handler sends to it.
+        fn register(&self, id: u64, login: Login) -> Receiver<InternalMessage> {
+            self.to_handler_s
+                .send(InternalMessage::Register(id, login))
+                .unwrap();
+            match self.to_server_r.recv().unwrap() {
+                InternalMessage::RegisterResponse(_, r) => r,
+                msg => panic!("Expected RegisterResponse got {:?}", msg),
+            }
+        }
+
+        fn command(&self, command: Command) -> Result<Option<Node>, Error> {
+            let (reply_s, reply_r) = crossbeam::channel::bounded(1);
+            self.to_handler_s
+                .send(InternalMessage::Command(command, reply_s))
+                .unwrap();
+            reply_r.recv().unwrap()
+        }
+
+        fn quit(self) {
+            self.to_handler_s.send(InternalMessage::Quit).unwrap();
+            self.handle.join().unwrap().unwrap();
+        }
+    }
+
+    fn start(root: Node, queue_size: usize) -> Handler {
+        start_with(root, queue_size, PressCallbacks::new(), ErrorLog::default())
+    }
+
+    fn start_with(
+        root: Node,
+        queue_size: usize,
+        callbacks: PressCallbacks,
+        errors: ErrorLog,
+    ) -> Handler {
+        let (to_handler_s, to_handler_r) = crossbeam::channel::unbounded();
+        let (to_server_s, to_server_r) = crossbeam::channel::unbounded();
+        let (from_clients_s, from_clients_r) = crossbeam::channel::unbounded();
+
+        let handle = thread::spawn(move || {
+            ServerHandler::new(
+                root,
+                Arc::new(RwLock::new(CredentialStore::new())),
+                to_server_s,
+                to_handler_r,
+                from_clients_r,
+            )
+            .queue_size(queue_size)
+            .callbacks(callbacks)
+            .errors(errors)
+            .run()
+        });
+        Handler {
+            to_handler_s,
+            from_clients_s,
+            to_server_r,
+            handle,
+        }
+    }
+
+    // A client that speaks the first version of the protocol.
+    fn first(session: u64) -> Login {
+        Login::New(session, None, 1, Capabilities::NONE)
+    }
+
+    // Applies every change the client gets to [mirror] until a log message arrives.
+    fn sync(mirror: &mut Node, r: &Receiver<InternalMessage>) {
+        loop {
+            match r.recv().unwrap() {
+                InternalMessage::TreeChange(change) => {
+                    TreeBuilder::change(mirror, change).unwrap();
+                }
+                InternalMessage::Message(_, Message::Snapshot(snapshot)) => {
+                    *mirror = Node::from_snapshot(&snapshot).unwrap();
+                }
+                InternalMessage::Message(_, Message::ServerLog(_)) => return,
+                msg => panic!("Unexpected message {:?}", msg),
+            }
+        }
+    }
+
+    #[test]
+    fn register_and_broadcast() {
+        let root = Node::root().name("root").children(vec![
+            Node::new()
+                .name("button")
+                .data(Data::Button(0))
+                .id(Uuid::from_u128(1)),
+        ]);
+
+        let handler = start(root, config::CLIENT_QUEUE_SIZE);
+        let client = handler.register(1, first(1));
+
+        handler
+            .to_handler_s
+            .send(InternalMessage::TreeChange(TreeChange::NodeAdded(
+                Data::Int32(3),
+                Some("value".to_string()),
+                Uuid::from_u128(2),
+                Uuid::nil(),
+            )))
+            .unwrap();
+        handler
+            .from_clients_s
+            .send(InternalMessage::Message(
+                1,
+                Message::ClientTrigger(0, Uuid::from_u128(1)),
+            ))
+            .unwrap();
+        let mut mirror = Node::root();
+        sync(&mut mirror, &client);
+
+        handler.quit();
+
+        let expected = Node::root().name("root").children(vec![
+            Node::new()
+                .name("button")
+                .data(Data::Button(1))
+                .id(Uuid::from_u128(1)),
+            Node::new()
+                .name("value")
+                .data(Data::Int32(3))
+                .id(Uuid::from_u128(2)),
+        ]);
+        assert_eq!(mirror.get_hash(), expected.get_hash());
+    }
+
+    // A client that does not read gets no more changes once its queue is full, but the whole
+    // tree as soon as it read its queue.
+    #[test]
+    fn backpressure() {
+        let root = Node::root().children(vec![
+            Node::new().data(Data::Int32(0)).id(Uuid::from_u128(1)),
+        ]);
+
+        let handler = start(root, 4);
+        let client = handler.register(1, first(1));
+
+        for i in 1..=20 {
+            handler
+                .to_handler_s
+                .send(InternalMessage::TreeChange(TreeChange::NodeChangedData(
+                    Uuid::from_u128(1),
+                    Data::Int32(i),
+                )))
+                .unwrap();
+        }
+        // Once the command is answered every change was handled.
+        let expected = handler
+            .command(Command::GetNode(Uuid::nil()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(client.len(), 4);
+
+        // The snapshot it gets later replaces whatever the client missed.
+        let mut mirror = Node::root();
+        loop {
+            match client.recv_timeout(Duration::from_secs(2)).unwrap() {
+                InternalMessage::TreeChange(change) => {
+                    TreeBuilder::change(&mut mirror, change).unwrap();
+                }
+                InternalMessage::Message(_, Message::Snapshot(snapshot)) => {
+                    mirror = Node::from_snapshot(&snapshot).unwrap();
+                }
+                msg => panic!("Unexpected message {:?}", msg),
+            }
+            if mirror.get_hash() == expected.get_hash() {
+                break;
+            }
+        }
+        assert!(matches!(
+            mirror.find_node(&Uuid::from_u128(1)).unwrap().data,
+            Data::Int32(20)
+        ));
+
+        handler.quit();
+    }
+
+    // A client that lost its connection gets only the changes it missed when it resumes.
+    #[test]
+    fn resume() {
+        let root = Node::root().children(vec![
+            Node::new().data(Data::Int32(0)).id(Uuid::from_u128(1)),
+        ]);
+
+        let handler = start(root, config::CLIENT_QUEUE_SIZE);
+        let change = |i| {
+            handler
+                .to_handler_s
+                .send(InternalMessage::TreeChange(TreeChange::NodeChangedData(
+                    Uuid::from_u128(1),
+                    Data::Int32(i),
+                )))
+                .unwrap();
+        };
+
+        let client = handler.register(1, Login::New(7, None, 1, Capabilities::BATCHING));
+        change(1);
+        let mut mirror = Node::root();
+        for _ in 0..2 {
+            match client.recv().unwrap() {
+                InternalMessage::TreeChange(change) => {
+                    TreeBuilder::change(&mut mirror, change).unwrap();
+                }
+                InternalMessage::Message(_, Message::Snapshot(snapshot)) => {
+                    mirror = Node::from_snapshot(&snapshot).unwrap();
+                }
+                msg => panic!("Unexpected message {:?}", msg),
+            }
+        }
+
+        handler
+            .to_handler_s
+            .send(InternalMessage::Unregister(1))
+            .unwrap();
+        change(2);
+        change(3);
+
+        let client = handler.register(2, Login::Resume(7, mirror.get_hash(), 1));
+        assert!(matches!(
+            client.recv().unwrap(),
+            InternalMessage::Message(_, Message::ServerAccept(7, _, Capabilities::BATCHING))
+        ));
+        for _ in 0..2 {
+            match client.recv().unwrap() {
+                InternalMessage::TreeChange(change) => {
+                    TreeBuilder::change(&mut mirror, change).unwrap();
+                }
+                msg => panic!("Expected only the missed changes got {:?}", msg),
+            }
+        }
+        assert!(matches!(
+            mirror.find_node(&Uuid::from_u128(1)).unwrap().data,
+            Data::Int32(3)
+        ));
+
+        // A tree the handler does not know needs the whole tree.
+        let resumed = handler.register(3, Login::Resume(7, 1234, 1));
+        assert!(matches!(
+            resumed.recv().unwrap(),
+            InternalMessage::Message(_, Message::ServerAccept(7, _, _))
+        ));
+        assert!(matches!(
+            resumed.recv().unwrap(),
+            InternalMessage::Message(_, Message::Snapshot(_))
+        ));
+        // The connection that had the session before lost it.
+        assert!(client.recv().is_err());
+
+        let unknown = handler.register(4, Login::Resume(8, mirror.get_hash(), 1));
+        assert!(matches!(
+            unknown.recv().unwrap(),
+            InternalMessage::Message(_, Message::ServerLog(_))
+        ));
+        assert!(unknown.recv().is_err());
+
+        handler.quit();
+    }
+
+    #[test]
+    fn commands() {
+        let handler = start(Node::root().name("root"), config::CLIENT_QUEUE_SIZE);
+        let command = |command| handler.command(command);
+        let client = handler.register(1, first(1));
+
+        let folder = Uuid::from_u128(1);
+        command(Command::AddChild(
+            Node::new().name("folder").id(folder).children(vec![
+                Node::new().name("public").id(Uuid::from_u128(2)),
+                Node::new()
+                    .name("admin")
+                    .id(Uuid::from_u128(3))
+                    .permissions(Permissions::Admin),
+            ]),
+            Uuid::nil(),
+        ))
+        .unwrap();
+        command(Command::ChangeName(
+            Uuid::from_u128(2),
+            "renamed".to_string(),
+        ))
+        .unwrap();
+        command(Command::ChangeData(folder, Data::Int32(5))).unwrap();
+
+        // Wrong commands are refused and change nothing.
+        assert!(command(Command::Remove(Uuid::nil())).is_err());
+        assert!(command(Command::ChangeData(Uuid::max(), Data::Int32(1))).is_err());
+        assert!(command(Command::AddChild(Node::new().id(folder), Uuid::nil())).is_err());
+        assert!(
+            command(Command::AddChild(
+                Node::new().children(vec![Node::new().id(Uuid::from_u128(2))]),
+                Uuid::nil(),
+            ))
+            .is_err()
+        );
+        assert!(
+            command(Command::AddChildAtPath(
+                Node::new().children(vec![Node::new().id(folder)]),
+                "/other".to_string(),
+            ))
+            .is_err()
+        );
+        // The existing node can still be found where it was.
+        let public = command(Command::GetNode(Uuid::from_u128(2)))
+            .unwrap()
+            .unwrap();
+        assert_eq!(public.parent_id, Some(folder));
+
+        let node = command(Command::GetNode(folder)).unwrap().unwrap();
+        assert_eq!(node.get_children_count(), 2);
+
+        handler
+            .from_clients_s
+            .send(InternalMessage::Message(
+                1,
+                Message::ClientTrigger(0, Uuid::max()),
+            ))
+            .unwrap();
+        let mut mirror = Node::root();
+        sync(&mut mirror, &client);
+        let expected = Node::root().name("root").children(vec![
+            Node::new()
+                .name("folder")
+                .id(folder)
+                .data(Data::Int32(5))
+                .children(vec![Node::new().name("renamed").id(Uuid::from_u128(2))]),
+        ]);
+        assert_eq!(mirror.get_hash(), expected.get_hash());
+
+        command(Command::Remove(folder)).unwrap();
+        assert!(command(Command::GetNode(Uuid::from_u128(3))).is_err());
+
+        // Missing folders on the way are created for everybody.
+        let sensor = Uuid::from_u128(4);
+        command(Command::AddChildAtPath(
+            Node::new().name("temperature").id(sensor),
+            "/plant/line1".to_string(),
+        ))
+        .unwrap();
+        assert!(
+            command(Command::AddChildAtPath(
+                Node::new().id(sensor),
+                "/other".to_string()
+            ))
+            .is_err()
+        );
+        handler
+            .from_clients_s
+            .send(InternalMessage::Message(
+                1,
+                Message::ClientTrigger(0, Uuid::max()),
+            ))
+            .unwrap();
+        sync(&mut mirror, &client);
+        let root = command(Command::GetNode(Uuid::nil())).unwrap().unwrap();
+        assert_eq!(mirror.get_hash(), root.get_hash());
+        assert_eq!(
+            mirror.find_by_path("/plant/line1/temperature").unwrap().id,
+            sensor
+        );
+        assert!(mirror.find_by_path("/other").is_err());
+
+        handler.quit();
+    }
+
+    #[test]
+    fn press_callbacks() {
+        let folder = Uuid::from_u128(1);
+        let button = Uuid::from_u128(2);
+        let hidden = Uuid::from_u128(3);
+        let root = Node::root().children(vec![Node::new().id(folder).children(vec![
+            Node::new().id(button).data(Data::Button(0)),
+            Node::new()
+                .id(hidden)
+                .data(Data::Button(0))
+                .permissions(Permissions::Admin),
+        ])]);
+
+        let (pressed_s, pressed_r) = crossbeam::channel::unbounded();
+        let mut callbacks = PressCallbacks::new();
+        callbacks.on_press(button, |press| {
+            Some(format!("{} {}", press.client, press.permissions))
+        });
+        callbacks.on_press_subtree(folder, move |press| {
+            pressed_s.send(press.node.id).unwrap();
+            None
+        });
+
+        let handler = start_with(
+            root,
+            config::CLIENT_QUEUE_SIZE,
+            callbacks,
+            ErrorLog::default(),
+        );
+        let client = handler.register(1, first(1));
+        let press = |id| {
+            handler
+                .from_clients_s
+                .send(InternalMessage::Message(1, Message::ClientTrigger(0, id)))
+                .unwrap();
+            loop {
+                match client.recv().unwrap() {
+                    InternalMessage::Message(_, Message::ServerLog(log)) => return log,
+                    _ => continue,
+                }
+            }
+        };
+
+        assert_eq!(press(button), "1 public");
+        assert_eq!(pressed_r.try_recv().unwrap(), button);
+
+        // Nothing runs for a button the client cannot see.
+        assert!(press(hidden).contains("Permission denied"));
+        assert!(pressed_r.try_recv().is_err());
+
+        handler.quit();
+    }
+
+    // Clients that speak request ids get an answer for each request, older ones only logs.
+    #[test]
+    fn requests() {
+        let button = Uuid::from_u128(1);
+        let value = Uuid::from_u128(2);
+        let root = Node::root().children(vec![
+            Node::new().id(button).data(Data::Button(0)),
+            Node::new().id(value).data(Data::Int32(0)),
+        ]);
+
+        let handler = start(root, config::CLIENT_QUEUE_SIZE);
+        let register =
+            |id, version| handler.register(id, Login::New(id, None, version, Capabilities::NONE));
+        // Returns the first answer that is not a change of the tree.
+        let request = |client: &Receiver<InternalMessage>, id, message| {
+            handler
+                .from_clients_s
+                .send(InternalMessage::Message(id, message))
+                .unwrap();
+            loop {
+                match client.recv().unwrap() {
+                    InternalMessage::Message(_, Message::Snapshot(_))
+                    | InternalMessage::TreeChange(_) => continue,
+                    InternalMessage::Message(_, message) => return message,
+                    msg => panic!("Unexpected message {:?}", msg),
+                }
+            }
+        };
+
+        let client = register(1, REQUEST_VERSION);
+        assert!(matches!(
+            request(&client, 1, Message::ClientTrigger(5, button)),
+            Message::ServerAck(5)
+        ));
+        assert!(matches!(
+            request(&client, 1, Message::ClientTrigger(6, Uuid::max())),
+            Message::ServerNack(6, NackCode::NotFound, _)
+        ));
+        assert!(matches!(
+            request(&client, 1, Message::ClientTrigger(7, value)),
+            Message::ServerNack(7, NackCode::NotAButton, _)
+        ));
+        handler
+            .from_clients_s
+            .send(InternalMessage::AddKey(1, "key".to_string(), 8))
+            .unwrap();
+        assert!(matches!(
+            client.recv().unwrap(),
+            InternalMessage::Message(_, Message::ServerAck(8))
+        ));
+
+        let old = register(2, REQUEST_VERSION - 1);
+        assert!(matches!(
+            request(&old, 2, Message::ClientTrigger(0, button)),
+            Message::ServerLog(_)
+        ));
+        assert!(matches!(
+            request(&old, 2, Message::ClientTrigger(0, Uuid::max())),
+            Message::ServerLog(_)
+        ));
+
+        handler.quit();
+    }
+
+    #[test]
+    fn moves() {
+        let id = Uuid::from_u128;
+        let root = Node::root().name("root").children(vec![
+            Node::new().id(id(1)).children(vec![
+                Node::new().id(id(3)),
+                Node::new().id(id(4)).permissions(Permissions::Admin),
+                Node::new().id(id(5)),
+                Node::new().id(id(8)),
+            ]),
+            Node::new().id(id(2)).children(vec![Node::new().id(id(6))]),
+            Node::new().id(id(7)).permissions(Permissions::Admin),
+        ]);
+
+        let handler = start(root, config::CLIENT_QUEUE_SIZE);
+        let command = |command| handler.command(command);
+        let register = |client, version| {
+            handler.register(
+                client,
+                Login::New(client, None, version, Capabilities::NONE),
+            )
+        };
+        // Applies the changes until the answer to a request arrives.
+        let sync = |mirror: &mut Node, receiver: &Receiver<_>, client| {
+            handler
+                .from_clients_s
+                .send(InternalMessage::Message(
+                    client,
+                    Message::ClientTrigger(1, Uuid::max()),
+                ))
+                .unwrap();
+            loop {
+                match receiver.recv().unwrap() {
+                    InternalMessage::TreeChange(change) => {
+                        TreeBuilder::change(mirror, change).unwrap();
+                    }
+                    InternalMessage::Message(_, Message::Snapshot(snapshot)) => {
+                        *mirror = Node::from_snapshot(&snapshot).unwrap();
+                    }
+                    InternalMessage::Message(_, Message::ServerNack(..)) => return,
+                    msg => panic!("Unexpected message {:?}", msg),
+                }
+            }
+        };
+
+        let client = register(1, MOVE_VERSION);
+        let old = register(2, MOVE_VERSION - 1);
+        let mut mirror = Node::root();
+        let mut old_mirror = Node::root();
+        sync(&mut mirror, &client, 1);
+        sync(&mut old_mirror, &old, 2);
+
+        // Among siblings, to another parent, out of sight and while out of sight.
+        command(Command::Move(id(5), id(1), 0)).unwrap();
+        command(Command::Move(id(3), id(2), 1)).unwrap();
+        command(Command::Move(id(6), id(7), 0)).unwrap();
+        command(Command::Move(id(4), id(2), 0)).unwrap();
+        assert!(command(Command::Move(id(1), id(1), 0)).is_err());
+        assert!(command(Command::Move(id(2), id(3), 0)).is_err());
+        assert!(command(Command::Move(Uuid::nil(), id(1), 0)).is_err());
+
+        sync(&mut mirror, &client, 1);
+        sync(&mut old_mirror, &old, 2);
+        let root = command(Command::GetNode(Uuid::nil())).unwrap().unwrap();
+        let expected = root.filter(&Permissions::Public).unwrap();
+        assert_eq!(mirror.get_hash(), expected.get_hash());
+
+        // Older clients have the same nodes, the moved ones last among their siblings.
+        for (node, parent) in [(3, 2), (5, 1)] {
+            assert_eq!(
+                old_mirror.find_node(&id(node)).unwrap().parent_id,
+                Some(id(parent))
+            );
+        }
+        assert!(old_mirror.find_node(&id(6)).is_none());
+        assert!(old_mirror.find_node(&id(4)).is_none());
+
+        handler.quit();
+    }
+
+    #[test]
+    fn resync() {
+        let handler = start(Node::root().name("root"), config::CLIENT_QUEUE_SIZE);
+        let client = handler.register(1, first(1));
+        let send = |message| {
+            handler
+                .from_clients_s
+                .send(InternalMessage::Message(1, message))
+                .unwrap();
+        };
+        let marker = || send(Message::ClientTrigger(0, Uuid::max()));
+
+        for i in 1..=3 {
+            handler
+                .to_handler_s
+                .send(InternalMessage::TreeChange(TreeChange::NodeAdded(
+                    Data::Int32(i),
+                    Some(format!("node {i}")),
+                    Uuid::from_u128(i as u128),
+                    Uuid::nil(),
+                )))
+                .unwrap();
+        }
+        marker();
+        let mut expected = Node::root();
+        sync(&mut expected, &client);
+
+        // The last two changes got lost.
+        let mut mirror = Node::root().name("root");
+        mirror.add_child(
+            Node::new()
+                .name("node 1")
+                .data(Data::Int32(1))
+                .id(Uuid::from_u128(1)),
+        );
+        send(Message::ClientHash(mirror.get_hash()));
+        marker();
+        sync(&mut mirror, &client);
+        assert_eq!(mirror.get_hash(), expected.get_hash());
+
+        // A tree the server does not know gets everything again.
+        mirror.get_child(1).unwrap().change_name("wrong");
+        send(Message::ClientHash(mirror.get_hash()));
+        marker();
+        sync(&mut mirror, &client);
+        assert_eq!(mirror.get_hash(), expected.get_hash());
+
+        handler.quit();
+    }
+
+    // A view that cannot apply a change is replaced by a snapshot instead of going on without it.
+    #[test]
+    fn out_of_sync() {
+        let (sender, client) = crossbeam::channel::unbounded();
+        let mut state = Client::new(
+            ClientSender::Thread(sender),
+            1,
+            1,
+            Capabilities::NONE,
+            vec![],
+            Permissions::Public,
+        );
+        let root = Node::root().children(vec![Node::new().id(Uuid::from_u128(1))]);
+
+        assert!(state.send_changes(vec![TreeChange::NodeRemoved(Uuid::from_u128(1))]));
+        assert!(client.is_empty());
+        assert!(state.catch_up(&root));
+        let mirror = match client.recv().unwrap() {
+            InternalMessage::Message(_, Message::Snapshot(snapshot)) => {
+                Node::from_snapshot(&snapshot).unwrap()
+            }
+            msg => panic!("Expected a snapshot got {:?}", msg),
+        };
+        assert_eq!(mirror.get_hash(), root.get_hash());
+        assert_eq!(state.view.get_hash(), root.get_hash());
+    }
+
+    #[test]
+    fn history_overflow() {
+        let (sender, _client) = crossbeam::channel::unbounded();
+        let mut state = Client::new(
+            ClientSender::Thread(sender),
+            1,
+            1,
+            Capabilities::NONE,
+            vec![],
+            Permissions::Public,
+        );
+        let id = Uuid::from_u128(1);
+        let mut mirror = Node::root().children(vec![Node::new().id(id)]);
+        assert!(state.send_snapshot(mirror.clone()));
+
+        // The hash of the mirror after each change, the oldest two fall out of the history.
+        let mut hashes = vec![];
+        for i in 0..config::HASH_HISTORY + 2 {
+            let change = TreeChange::NodeChangedData(id, Data::Int32(i as i32));
+            TreeBuilder::change(&mut mirror, change.clone()).unwrap();
+            hashes.push(mirror.get_hash());
+            assert!(state.send_changes(vec![change]));
+        }
+
+        assert_eq!(state.history.len(), config::HASH_HISTORY);
+        assert!(state.missing_changes(hashes[0]).is_none());
+        assert_eq!(
+            state.missing_changes(hashes[1]).unwrap().len(),
+            config::HASH_HISTORY
+        );
+        assert_eq!(
+            state
+                .missing_changes(hashes[hashes.len() - 2])
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(state.missing_changes(mirror.get_hash()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn permission_views() {
+        let secret_id = Uuid::from_u128(2);
+        let root = Node::root().name("root").children(vec![
+            Node::new()
+                .name("folder")
+                .id(Uuid::from_u128(1))
+                .children(vec![
+                    Node::new()
+                        .name("secret")
+                        .data(Data::Int32(1))
+                        .id(secret_id)
+                        .permissions(Permissions::Admin),
+                ]),
+        ]);
+
+        let handler = start(root, config::CLIENT_QUEUE_SIZE);
+        let client = handler.register(1, first(1));
+
+        // Pressing something that does not exist gets a log, which marks the end of the changes.
+        let mut mirror = Node::root();
+        let change_and_sync = |mirror: &mut Node, change| {
+            handler
+                .to_handler_s
+                .send(InternalMessage::TreeChange(change))
+                .unwrap();
+            handler
+                .from_clients_s
+                .send(InternalMessage::Message(
+                    1,
+                    Message::ClientTrigger(0, Uuid::max()),
+                ))
+                .unwrap();
+            sync(mirror, &client);
+        };
+
+        // The secret is hidden and so are its changes.
+        change_and_sync(
+            &mut mirror,
+            TreeChange::NodeChangedData(secret_id, Data::Int32(2)),
+        );
+        assert!(mirror.find_node(&secret_id).is_none());
+
+        // It appears once it is public.
+        change_and_sync(
+            &mut mirror,
+            TreeChange::NodeChangedPermissions(secret_id, Permissions::Public),
+        );
+        assert!(matches!(
+            mirror.find_node(&secret_id).unwrap().data,
+            Data::Int32(2)
+        ));
+
+        // Hiding the folder hides everything below it.
+        change_and_sync(
+            &mut mirror,
+            TreeChange::NodeChangedPermissions(Uuid::from_u128(1), Permissions::User(None)),
+        );
+        assert_eq!(mirror.get_hash(), Node::root().name("root").get_hash());
+
+        handler.quit();
+    }
+
+    #[test]
+    fn skipped_messages() {
+        let errors = ErrorLog::default();
+        let handler = start_with(
+            Node::root(),
+            config::CLIENT_QUEUE_SIZE,
+            PressCallbacks::new(),
+            errors.clone(),
+        );
+
+        // Only the handler sends a RegisterResponse, and the node of the change does not exist.
+        let (_, r) = crossbeam::channel::unbounded();
+        handler
+            .to_handler_s
+            .send(InternalMessage::RegisterResponse(1, r))
+            .unwrap();
+        handler
+            .to_handler_s
+            .send(InternalMessage::TreeChange(TreeChange::NodeRemoved(
+                Uuid::from_u128(1),
+            )))
+            .unwrap();
+
+        // The handler goes on with the messages after them, which are sent on the same channel.
+        let node = handler.command(Command::GetNode(Uuid::nil())).unwrap();
+        assert!(node.is_some());
+        let (count, last) = errors.errors();
+        assert_eq!(count, 2);
+        assert!(last.is_some());
+        handler.quit();
+    }
 }
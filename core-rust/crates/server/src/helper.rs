@@ -1,7 +1,9 @@
 use crossbeam::channel::{Receiver, Sender};
 use shared::errors::Error;
 
-use crate::internal_message::InternalMessage;
+#[cfg(feature = "tokio")]
+use crate::internal_message::ClientSender;
+use crate::internal_message::{InternalMessage, Login};
 
 pub(crate) struct ServerHelper {
     pub to_handler_s: Sender<InternalMessage>,
@@ -29,14 +31,20 @@ impl ServerHelper {
     // Then we send with to_handler_s has higher priority than to_handler_from_clients_s.
     // With response registering is done and we get a reciever, that connects to the handler
     // directily.
+    // Returns the id of the new client together with both channel ends.
+    // [login] is the session the client started or resumes.
     pub fn register(
         &mut self,
-    ) -> Result<(Sender<InternalMessage>, Receiver<InternalMessage>), Error> {
+        login: Login,
+    ) -> Result<(u64, Sender<InternalMessage>, Receiver<InternalMessage>), Error> {
         let new_id = self.client_id;
         self.client_id += 1;
 
         // Send a register to the handler
-        Error::from(self.to_handler_s.send(InternalMessage::Register(new_id)))?;
+        Error::from(
+            self.to_handler_s
+                .send(InternalMessage::Register(new_id, login)),
+        )?;
         let register_response = Error::from(self.from_handler_r.recv())?;
 
         match register_response {
@@ -45,7 +53,7 @@ impl ServerHelper {
                     return Err(Error::SimpleError("Tried registering but wrong id"));
                 }
 
-                Ok((self.to_handler_from_clients_s.clone(), reciever))
+                Ok((id, self.to_handler_from_clients_s.clone(), reciever))
             }
             e => Err(Error::SimpleErrorStr(format!(
                 "Tried registering but didnt get a response got {:?}",
@@ -53,4 +61,24 @@ impl ServerHelper {
             ))),
         }
     }
+
+    /// Registers a client whose connection created the channel from the handler itself.
+    /// Unlike [ServerHelper::register] this does not wait for the handler.
+    /// Returns the id of the new client and the channel to the handler.
+    #[cfg(feature = "tokio")]
+    pub fn attach(
+        &mut self,
+        login: Login,
+        sender: ClientSender,
+    ) -> Result<(u64, Sender<InternalMessage>), Error> {
+        let new_id = self.client_id;
+        self.client_id += 1;
+
+        // Sent on the channel of the clients, so it arrives before any message of this client.
+        Error::from(
+            self.to_handler_from_clients_s
+                .send(InternalMessage::Attach(new_id, login, sender)),
+        )?;
+        Ok((new_id, self.to_handler_from_clients_s.clone()))
+    }
 }
@@ -1,14 +1,92 @@
-use crossbeam::channel::Receiver;
-use shared::{datatypes::treebuilder::TreeChange, remote::message::Message};
+use crossbeam::channel::{Receiver, Sender};
+use shared::{
+    datatypes::{Data, nodes::Node, treebuilder::TreeChange},
+    errors::Error,
+    remote::{message::Message, version::Capabilities},
+    security::permissions::Permissions,
+};
 use uuid::Uuid;
 
 #[derive(Debug)]
 pub(crate) enum InternalMessage {
     TreeChange(TreeChange),
     Message(u64, Message),
-    Register(u64),
+    Register(u64, Login), // client id, how the client logged in.
+    Unregister(u64),
+    AddKey(u64, String, u64), // client id, fingerprint of another key the client proved to own,
+    // request id of the client to acknowledge.
+    RefreshPermissions,
     RegisterResponse(u64, Receiver<InternalMessage>),
+    #[cfg(feature = "tokio")]
+    Attach(u64, Login, ClientSender), // Like Register, but the connection created the
+    // channel to itself, so there is no response.
+    Command(Command, Sender<Result<Option<Node>, Error>>), // Sent by the hosting server (client 0),
+    // the result is sent back with the sender.
     Quit,
+}
+
+/// How a new connection logged in.
+#[derive(Debug)]
+pub(crate) enum Login {
+    New(u64, Option<String>, u8, Capabilities), // session, fingerprint of the key the client
+    // authenticated with, version it speaks, capabilities both support.
+    Resume(u64, u64, u8), // session, hash of the tree of the client, version it speaks.
+}
+
+/// Changes the hosting server makes to the tree. Only [Command::GetNode] returns a node.
+#[derive(Debug)]
+pub(crate) enum Command {
+    AddChild(Node, Uuid),         // node, parent id
+    AddChildAtPath(Node, String), // node, path of the parent
+    ChangeData(Uuid, Data),
+    ChangeName(Uuid, String),
+    ChangePermissions(Uuid, Permissions),
+    Remove(Uuid),
+    Move(Uuid, Uuid, usize), // node, new parent id, position
+    GetNode(Uuid),
+}
+
+/// The channel from the handler to a single connection.
+/// It is bounded, so a client that cannot keep up does not make the handler use more and more
+/// memory. Sending never blocks the handler.
+#[derive(Debug)]
+pub(crate) enum ClientSender {
+    Thread(Sender<InternalMessage>),
+    #[cfg(feature = "tokio")]
+    Task(tokio::sync::mpsc::Sender<InternalMessage>),
+}
+
+/// Why a message could not be sent to a client.
+pub(crate) enum SendError {
+    /// The client did not read the messages sent before.
+    Full,
+    /// The connection is closed.
+    Gone,
+}
+
+impl ClientSender {
+    pub fn try_send(&self, message: InternalMessage) -> Result<(), SendError> {
+        match self {
+            ClientSender::Thread(sender) => match sender.try_send(message) {
+                Ok(()) => Ok(()),
+                Err(crossbeam::channel::TrySendError::Full(_)) => Err(SendError::Full),
+                Err(crossbeam::channel::TrySendError::Disconnected(_)) => Err(SendError::Gone),
+            },
+            #[cfg(feature = "tokio")]
+            ClientSender::Task(sender) => match sender.try_send(message) {
+                Ok(()) => Ok(()),
+                Err(tokio::sync::mpsc::error::TrySendError::Full(_)) => Err(SendError::Full),
+                Err(tokio::sync::mpsc::error::TrySendError::Closed(_)) => Err(SendError::Gone),
+            },
+        }
+    }
 
-    IdResponse(Uuid),
+    /// Returns [true] once the client read everything that was sent to it.
+    pub fn is_empty(&self) -> bool {
+        match self {
+            ClientSender::Thread(sender) => sender.is_empty(),
+            #[cfg(feature = "tokio")]
+            ClientSender::Task(sender) => sender.capacity() == sender.max_capacity(),
+        }
+    }
 }
@@ -1,71 +1,252 @@
+#[cfg(feature = "tokio")]
+mod async_conn;
 mod conn;
+pub mod credentials;
 mod handler;
 mod helper;
 mod internal_message;
+mod log;
+pub mod press;
+pub mod server_config;
 pub mod server_interface;
+pub mod shutdown;
+mod tls;
 mod util;
 
-use std::thread;
+use std::{
+    fmt::Display,
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, RwLock},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
 
-use crossbeam::channel::{Receiver, RecvError, SendError, Sender, select_biased};
+use crossbeam::channel::Sender;
 use shared::{
-    datatypes::{nodes::Node, treebuilder::TreeChange},
+    datatypes::{
+        Data,
+        nodes::Node,
+        treebuilder::{TreeBuilder, TreeChange},
+    },
     errors::Error,
+    remote::admission::{Admission, ConnectionStats},
     security::permissions::Permissions,
 };
 use uuid::Uuid;
 
 use crate::{
-    handler::ServerHandler, helper::ServerHelper, internal_message::InternalMessage,
-    server_interface::ServerInterface,
+    credentials::CredentialStore,
+    handler::ServerHandler,
+    helper::ServerHelper,
+    internal_message::{Command, InternalMessage},
+    log::{ErrorCallback, ErrorLog},
+    press::{Press, PressCallbacks},
+    server_config::ServerConfig,
+    server_interface::{ServerInterface, TreeAccess},
+    shutdown::{Connections, ShutdownReport},
+    tls::Acceptor,
 };
 
 // Server gives out channel pairs for each client connection. These channels connect to.
 // Server gives out channel pairs for each client connection. These channels connect to.
 pub struct Server {
-    address: String,
+    config: ServerConfig,
     root: Node,
+    credentials: CredentialStore,
+    callbacks: PressCallbacks,
+    on_error: Option<ErrorCallback>,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Server {
     pub fn new() -> Self {
+        Self::from_config(ServerConfig::default())
+    }
+
+    /// Creates a server that is served with [config].
+    pub fn from_config(config: ServerConfig) -> Self {
         Self {
-            address: "localhost:8001".to_string(),
-            root: Node::new().name("root").permissions(Permissions::Public),
+            config,
+            root: Node::root().name("root").permissions(Permissions::Public),
+            credentials: CredentialStore::new(),
+            callbacks: PressCallbacks::new(),
+            on_error: None,
         }
     }
 
+    /// Runs [callback] on the server whenever a client that can see the button with [id]
+    /// presses it. What the callback returns is sent to only this client.
+    ///
+    /// # Example:
+    ///
+    /// ```no_run
+    /// use server::Server;
+    /// use uuid::Uuid;
+    ///
+    /// let mut server = Server::new();
+    /// server.on_press(Uuid::nil(), |press| {
+    ///     Some(format!("Hello client {}", press.client))
+    /// });
+    /// ```
+    pub fn on_press(
+        &mut self,
+        id: Uuid,
+        callback: impl Fn(&Press) -> Option<String> + Send + 'static,
+    ) {
+        self.callbacks.on_press(id, callback);
+    }
+
+    /// Runs [callback] whenever a client presses any button below the node with [id].
+    /// See [Server::on_press].
+    pub fn on_press_subtree(
+        &mut self,
+        id: Uuid,
+        callback: impl Fn(&Press) -> Option<String> + Send + 'static,
+    ) {
+        self.callbacks.on_press_subtree(id, callback);
+    }
+
+    /// Runs [callback] with every error the server cannot return, like one a connection ends
+    /// with or a message the handler skips. They are counted in the [ShutdownReport] either way.
+    /// Runs on the thread or task of the connection or on the handler, so it should not block.
+    pub fn on_error(&mut self, callback: impl Fn(&Error) + Send + Sync + 'static) {
+        self.on_error = Some(Arc::new(callback));
+    }
+
+    /// Replaces the configuration the server is served with.
+    pub fn set_config(&mut self, config: ServerConfig) {
+        self.config = config;
+    }
+
+    /// Sets the store that decides which permissions an authenticated client gets.
+    pub fn set_credentials(&mut self, credentials: CredentialStore) {
+        self.credentials = credentials;
+    }
+
+    /// Loads the credentials from a file. See [CredentialStore] for the format.
+    pub fn load_credentials(&mut self, path: impl AsRef<Path>) -> Result<(), Error> {
+        self.credentials = CredentialStore::load(path)?;
+        Ok(())
+    }
+
     /// Serve the configured server and get a [RunningServer] struct. This has most of the
     /// functionality of the not running [Server], but acts more as another client with higher
     /// priotity.
+    ///
+    /// Fails if the configuration is invalid, the identity cannot be loaded or the address
+    /// cannot be bound. Nothing is running in that case.
     pub fn serve(self) -> Result<RunningServer, Error> {
-        // first set up the handler.
+        self.config.validate()?;
+        let acceptor = Arc::new(Acceptor::new(&self.config)?);
+        let listener = util::create_listener(&self.config.address)?;
+        // The listener does not block, so that it notices when the server stops.
+        Error::from(listener.set_nonblocking(true))?;
+        let address = Error::from(listener.local_addr())?;
+
+        let (mut running, helper, admission, config) = self.start_handler();
+        let connections = running.connections.clone();
+        running.address = Some(address);
+        running.listener = Some(Listener::Thread(thread::spawn(move || {
+            conn::serve_server(listener, acceptor, helper, admission, connections, config)
+        })));
+        Ok(running)
+    }
+
+    /// Like [Server::serve], but every connection is a task on the tokio runtime this is called
+    /// on instead of a thread. The tree is still changed by a single thread, which never waits
+    /// for a connection.
+    ///
+    /// Needs the `tokio` feature, which uses rustls for TLS. Does not do the key exchange of
+    /// [ServerConfig::exchange_key], so it always needs TLS.
+    #[cfg(feature = "tokio")]
+    pub async fn serve_async(self) -> Result<RunningServer, Error> {
+        self.config.validate()?;
+        if !self.config.tls || self.config.exchange_key.is_some() {
+            return Err(Error::SimpleError(
+                "Config: serve_async does not do the key exchange, use serve",
+            ));
+        }
+        let acceptor = tokio_rustls::TlsAcceptor::from(tls::rustls_config(&self.config)?);
+        let listener = match tokio::net::TcpListener::bind(&self.config.address).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                return Err(Error::SimpleErrorStr(format!(
+                    "Couldnt bind with address {}: {:?}",
+                    self.config.address, err
+                )));
+            }
+        };
+        let address = Error::from(listener.local_addr())?;
+
+        let (mut running, helper, admission, config) = self.start_handler();
+        let connections = running.connections.clone();
+        running.address = Some(address);
+        let (stopped_s, stopped_r) = crossbeam::channel::bounded(0);
+        tokio::spawn(async move {
+            let _stopped = stopped_s;
+            async_conn::serve_server(listener, acceptor, helper, admission, connections, config)
+                .await
+        });
+        running.listener = Some(Listener::Task(stopped_r));
+        Ok(running)
+    }
+
+    // Starts the thread of the handler. Returns what the hosting server and the connections need
+    // to talk to it.
+    fn start_handler(self) -> (RunningServer, ServerHelper, Admission, Arc<ServerConfig>) {
         let (to_handler_s, to_handler_r) = crossbeam::channel::unbounded::<InternalMessage>();
         let (to_server_s, to_server_r) = crossbeam::channel::unbounded::<InternalMessage>();
         let (from_clients_to_handler_s, from_clients_to_handler_r) =
             crossbeam::channel::unbounded::<InternalMessage>();
 
         // start the handler thread
-        let _server_thread = thread::spawn(move || {
-            match ServerHandler::new(to_server_s, to_handler_r, from_clients_to_handler_r).run() {
-                Err(err) => println!("{err:?}"),
-                _ => {}
-            };
+        let root = self.root;
+        let credentials = Arc::new(RwLock::new(self.credentials));
+        let handler_credentials = credentials.clone();
+        let callbacks = self.callbacks;
+        let queue_size = self.config.queue_size;
+        let session_validity = self.config.session_validity;
+        // At most as many sessions without a connection are kept as clients can be connected.
+        let max_detached = self.config.max_clients as usize;
+        let errors = ErrorLog::new(self.on_error);
+        let handler_errors = errors.clone();
+        let handler = thread::spawn(move || {
+            let result = ServerHandler::new(
+                root,
+                handler_credentials,
+                to_server_s,
+                to_handler_r,
+                from_clients_to_handler_r,
+            )
+            .callbacks(callbacks)
+            .queue_size(queue_size)
+            .sessions(session_validity, max_detached)
+            .errors(handler_errors.clone())
+            .run();
+            // Reported right away, [RunningServer::shutdown] returns it later.
+            if let Err(err) = &result {
+                handler_errors.report(err);
+            }
+            result
         });
 
         // create the helper struct to contain the channel end and start points.
-        let mut helper = ServerHelper::new(to_handler_s, to_server_r, from_clients_to_handler_s);
-
-        let (s, r) = helper.register()?;
-        thread::spawn(move || {
-            match conn::serve_server("test.pfx", &self.address, helper) {
-                Err(err) => {
-                    println!("{err:?}")
-                }
-                _ => {}
-            };
-        });
-        Ok(RunningServer::new(s, r))
+        let admission = Admission::new(self.config.max_clients);
+        let running = RunningServer::new(
+            to_handler_s.clone(),
+            credentials,
+            admission.clone(),
+            Connections::new(errors),
+            handler,
+        );
+        let helper = ServerHelper::new(to_handler_s, to_server_r, from_clients_to_handler_s);
+        (running, helper, admission, Arc::new(self.config))
     }
 }
 
@@ -88,6 +269,40 @@ impl ServerInterface for Server {
         }
     }
 
+    fn add_child_at_path(&mut self, node: Node, path: &str) -> Result<(), Error> {
+        let (parent_id, _) = self.root.create_path(path)?;
+        self.add_child_to_node(node, &parent_id)
+    }
+
+    fn change_data(&mut self, id: &Uuid, data: Data) -> Result<(), Error> {
+        self.get_node_mut(id)?.change_data(data);
+        Ok(())
+    }
+
+    fn change_name(&mut self, id: &Uuid, name: &str) -> Result<(), Error> {
+        self.get_node_mut(id)?.change_name(name);
+        Ok(())
+    }
+
+    fn change_permissions(&mut self, id: &Uuid, permissions: Permissions) -> Result<(), Error> {
+        self.get_node_mut(id)?.change_permissions(permissions);
+        Ok(())
+    }
+
+    fn remove(&mut self, id: &Uuid) -> Result<(), Error> {
+        if *id == self.root.id {
+            return Err(Error::SimpleError("Remove: The root cannot be removed"));
+        }
+        TreeBuilder::change(&mut self.root, TreeChange::NodeRemoved(*id))?;
+        Ok(())
+    }
+
+    fn move_node(&mut self, id: &Uuid, new_parent: &Uuid, position: usize) -> Result<(), Error> {
+        self.root.move_node(id, new_parent, position)
+    }
+}
+
+impl TreeAccess for Server {
     fn get_node(&self, id: &Uuid) -> Result<&Node, Error> {
         if let Some(n) = self.root.find_node(id) {
             Ok(n)
@@ -109,21 +324,297 @@ impl ServerInterface for Server {
     }
 }
 
+/// The server after [Server::serve]. The tree now belongs to the handler thread, so every change
+/// is sent to it as client 0. These changes are handled before the ones of the clients and are
+/// sent to every client that can see them.
 pub struct RunningServer {
     to_handler_s: Sender<InternalMessage>,
-    from_handler_r: Receiver<InternalMessage>,
+    credentials: Arc<RwLock<CredentialStore>>,
+    admission: Admission,
+    connections: Connections,
+    handler: JoinHandle<Result<(), Error>>,
+    listener: Option<Listener>,
+    /// Where the listener accepts clients.
+    address: Option<SocketAddr>,
+}
+
+/// What accepts the clients of a [RunningServer].
+enum Listener {
+    /// Returns the threads of the connections once it stopped accepting.
+    Thread(JoinHandle<Vec<JoinHandle<()>>>),
+    /// A task on a tokio runtime, the connections are tasks too. Drops the sender once it stopped
+    /// accepting.
+    #[cfg(feature = "tokio")]
+    Task(crossbeam::channel::Receiver<()>),
 }
 
-// When the server starts the helper is moved therefore a new struct is needed that also implements
-// the ServerInterface trait (todo at this point lol)
 impl RunningServer {
     fn new(
         to_handler_s: Sender<InternalMessage>,
-        from_handler_r: Receiver<InternalMessage>,
+        credentials: Arc<RwLock<CredentialStore>>,
+        admission: Admission,
+        connections: Connections,
+        handler: JoinHandle<Result<(), Error>>,
     ) -> Self {
         Self {
             to_handler_s,
-            from_handler_r,
+            credentials,
+            admission,
+            connections,
+            handler,
+            listener: None,
+            address: None,
+        }
+    }
+
+    /// Stops the server. No new clients are accepted. Every client gets the changes that were
+    /// already queued for it and then a goodbye, after which it should close the connection.
+    /// Connections that are still open after [timeout] are closed by the server and reported.
+    /// Returns once every thread of the server ended. Fails with the error the handler stopped
+    /// with, if it stopped before, once the connections are closed.
+    ///
+    /// Blocks the calling thread. From async code use `tokio::task::spawn_blocking`, the
+    /// connections need the runtime to close.
+    pub fn shutdown(self, timeout: Duration) -> Result<ShutdownReport, Error> {
+        let deadline = Instant::now() + timeout;
+
+        self.connections.stop();
+        let threads = match self.listener {
+            Some(Listener::Thread(listener)) => match listener.join() {
+                Ok(threads) => threads,
+                Err(_) => return Err(Error::SimpleError("Shutdown: Listener thread panicked")),
+            },
+            #[cfg(feature = "tokio")]
+            Some(Listener::Task(stopped)) => {
+                // Only fails once the task ended.
+                let _ = stopped.recv();
+                vec![]
+            }
+            None => vec![],
+        };
+
+        // The handler tells every client to quit after everything queued for it.
+        // It might have stopped already, which is fine.
+        let _ = self.to_handler_s.send(InternalMessage::Quit);
+        let handled = match self.handler.join() {
+            Ok(handled) => handled,
+            Err(_) => return Err(Error::SimpleError("Shutdown: Handler thread panicked")),
+        };
+
+        let open = self.connections.open();
+        self.connections.wait(Some(deadline));
+        let timed_out = self.connections.close_all();
+        // The connections end right away once their sockets are shut down.
+        for thread in threads {
+            // A panic of a connection was already printed.
+            let _ = thread.join();
+        }
+        self.connections.wait(None);
+
+        handled?;
+        let (accept_errors, last_accept_error) = self.connections.accept_errors();
+        let (errors, last_error) = self.connections.errors();
+        Ok(ShutdownReport {
+            closed: open.saturating_sub(timed_out.len()),
+            timed_out,
+            accept_errors,
+            last_accept_error,
+            errors,
+            last_error,
+        })
+    }
+
+    /// The address clients connect to. Has the port the system chose if the configured address
+    /// has port 0.
+    pub fn address(&self) -> Option<SocketAddr> {
+        self.address
+    }
+
+    /// How many clients are connected right now and how many were at most.
+    /// Clients that are still in the handshake count as connected.
+    pub fn connections(&self) -> ConnectionStats {
+        self.admission.stats()
+    }
+
+    /// Returns a copy of the node with this id, including its children. The tree lives on the
+    /// handler thread, so unlike [TreeAccess::get_node] there is no reference to it.
+    pub fn node(&self, id: &Uuid) -> Result<Node, Error> {
+        match self.command(Command::GetNode(*id))? {
+            Some(node) => Ok(node),
+            None => Err(Error::SimpleErrorStr(format!(
+                "[node]: Couldnt find node with {id}"
+            ))),
+        }
+    }
+
+    /// Sends [command] to the handler and waits for its result.
+    fn command(&self, command: Command) -> Result<Option<Node>, Error> {
+        let (reply_s, reply_r) = crossbeam::channel::bounded(1);
+        Error::from(
+            self.to_handler_s
+                .send(InternalMessage::Command(command, reply_s)),
+        )?;
+        match reply_r.recv() {
+            Ok(result) => result,
+            Err(_) => Err(Error::SimpleError("Server: Handler stopped")),
+        }
+    }
+
+    /// Sets the permissions for the key with [fingerprint].
+    /// Connected clients with this key get the new permissions immediately.
+    pub fn add_credential(
+        &self,
+        fingerprint: impl Display,
+        permissions: Permissions,
+    ) -> Result<Option<Permissions>, Error> {
+        let previous = self.edit_credentials(|store| Ok(store.insert(fingerprint, permissions)))?;
+        self.refresh_permissions()?;
+        Ok(previous)
+    }
+
+    /// Removes the key with [fingerprint]. Connected clients with this key lose its permissions.
+    pub fn remove_credential(&self, fingerprint: &str) -> Result<Option<Permissions>, Error> {
+        let previous = self.edit_credentials(|store| Ok(store.remove(fingerprint)))?;
+        self.refresh_permissions()?;
+        Ok(previous)
+    }
+
+    /// Reads the credentials file again and updates the permissions of connected clients.
+    pub fn reload_credentials(&self) -> Result<(), Error> {
+        self.edit_credentials(|store| store.reload())?;
+        self.refresh_permissions()
+    }
+
+    /// Writes the credentials back to the file they were loaded from.
+    pub fn save_credentials(&self) -> Result<(), Error> {
+        self.edit_credentials(|store| store.save())
+    }
+
+    fn edit_credentials<T>(
+        &self,
+        edit: impl FnOnce(&mut CredentialStore) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        match self.credentials.write() {
+            Ok(mut store) => edit(&mut store),
+            Err(_) => Err(Error::SimpleError("Credentials lock is poisoned")),
         }
     }
+
+    fn refresh_permissions(&self) -> Result<(), Error> {
+        Error::from(self.to_handler_s.send(InternalMessage::RefreshPermissions))
+    }
+}
+
+// The tree lives on the handler thread, so the changes are sent to it.
+impl ServerInterface for RunningServer {
+    /// Add a child to the root node of the tree.
+    fn add_child(&mut self, node: Node) -> Result<(), Error> {
+        self.command(Command::AddChild(node, Uuid::nil()))?;
+        Ok(())
+    }
+
+    /// Add a child to the the node with the corresponding id.
+    fn add_child_to_node(&mut self, node: Node, parent_id: &Uuid) -> Result<(), Error> {
+        self.command(Command::AddChild(node, *parent_id))?;
+        Ok(())
+    }
+
+    /// Add a child to the node at [path], creating the folders on the way that do not exist yet.
+    fn add_child_at_path(&mut self, node: Node, path: &str) -> Result<(), Error> {
+        self.command(Command::AddChildAtPath(node, path.to_string()))?;
+        Ok(())
+    }
+
+    /// Changes the data of the node with this id.
+    fn change_data(&mut self, id: &Uuid, data: Data) -> Result<(), Error> {
+        self.command(Command::ChangeData(*id, data))?;
+        Ok(())
+    }
+
+    /// Changes the name of the node with this id.
+    fn change_name(&mut self, id: &Uuid, name: &str) -> Result<(), Error> {
+        self.command(Command::ChangeName(*id, name.to_string()))?;
+        Ok(())
+    }
+
+    /// Changes the permissions of the node with this id.
+    fn change_permissions(&mut self, id: &Uuid, permissions: Permissions) -> Result<(), Error> {
+        self.command(Command::ChangePermissions(*id, permissions))?;
+        Ok(())
+    }
+
+    /// Removes the node with this id and everything below it. The root cannot be removed.
+    fn remove(&mut self, id: &Uuid) -> Result<(), Error> {
+        self.command(Command::Remove(*id))?;
+        Ok(())
+    }
+
+    /// Moves the node with this id and everything below it to the node with [new_parent], where
+    /// it is the child at [position].
+    fn move_node(&mut self, id: &Uuid, new_parent: &Uuid, position: usize) -> Result<(), Error> {
+        self.command(Command::Move(*id, *new_parent, position))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use shared::{
+        datatypes::{Data, nodes::Node},
+        security::permissions::Permissions,
+    };
+    use uuid::Uuid;
+
+    use crate::{
+        Server,
+        server_interface::{ServerInterface, TreeAccess},
+    };
+
+    // Builds the same tree on anything that implements the changes.
+    fn build(tree: &mut impl ServerInterface) {
+        let id = Uuid::from_u128;
+        tree.add_child(Node::new().id(id(1)).name("folder"))
+            .unwrap();
+        tree.add_child_to_node(Node::new().id(id(2)).data(Data::Button(0)), &id(1))
+            .unwrap();
+        tree.add_child_at_path(Node::new().id(id(3)), "/plant/line1")
+            .unwrap();
+        tree.change_data(&id(3), Data::Int32(7)).unwrap();
+        tree.change_name(&id(2), "button").unwrap();
+        tree.change_permissions(&id(1), Permissions::Admin).unwrap();
+        tree.move_node(&id(3), &id(1), 0).unwrap();
+        tree.add_child(Node::new().id(id(4))).unwrap();
+        tree.remove(&id(4)).unwrap();
+
+        assert!(tree.remove(&Uuid::nil()).is_err());
+        assert!(tree.change_data(&id(4), Data::Int32(1)).is_err());
+        assert!(tree.move_node(&id(1), &id(3), 0).is_err());
+    }
+
+    #[test]
+    fn interface() {
+        let mut server = Server::new();
+        build(&mut server);
+
+        // Without a listener, the helper keeps the channel of the clients open.
+        let (mut running, _helper, ..) = Server::new().start_handler();
+        build(&mut running);
+
+        // The folders of the path get random ids, so only the names and data are compared.
+        let shape = |root: &Node| -> Vec<_> {
+            root.iter()
+                .map(|node| (node.name.clone(), format!("{:?}", node.data)))
+                .collect()
+        };
+        let root = running.node(&Uuid::nil()).unwrap();
+        assert_eq!(shape(&root), shape(server.get_node(&Uuid::nil()).unwrap()));
+        let folder = server.get_node_mut(&Uuid::from_u128(1)).unwrap();
+        assert_eq!(folder.permissions, Permissions::Admin);
+        assert_eq!(folder.get_children_count(), 2);
+
+        let report = running.shutdown(Duration::from_secs(1)).unwrap();
+        assert_eq!(report.closed, 0);
+    }
 }
@@ -0,0 +1,83 @@
+// Collects the errors of a running server that nobody waits for, like a connection that ends with
+// an error or a message the handler cannot handle. Each one is passed to the callback of
+// [crate::Server::on_error] and counted for the [crate::shutdown::ShutdownReport].
+
+use std::sync::{Arc, Mutex};
+
+use shared::errors::Error;
+
+/// Runs on the thread or task the error happened on.
+pub(crate) type ErrorCallback = Arc<dyn Fn(&Error) + Send + Sync>;
+
+#[derive(Default)]
+struct State {
+    errors: usize,
+    last_error: Option<String>,
+}
+
+/// The errors of a server. Clones share the same errors.
+#[derive(Clone, Default)]
+pub(crate) struct ErrorLog {
+    state: Arc<Mutex<State>>,
+    callback: Option<ErrorCallback>,
+}
+
+impl ErrorLog {
+    pub fn new(callback: Option<ErrorCallback>) -> Self {
+        Self {
+            state: Arc::default(),
+            callback,
+        }
+    }
+
+    pub fn report(&self, err: &Error) {
+        {
+            // The state is always consistent, so a panic while holding the lock does not matter.
+            let mut state = match self.state.lock() {
+                Ok(state) => state,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            state.errors += 1;
+            state.last_error = Some(format!("{err:?}"));
+        }
+        if let Some(callback) = &self.callback {
+            callback(err);
+        }
+    }
+
+    /// How many errors were reported and the last one.
+    pub fn errors(&self) -> (usize, Option<String>) {
+        let state = match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        (state.errors, state.last_error.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use shared::errors::Error;
+
+    use crate::log::ErrorLog;
+
+    #[test]
+    fn report() {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let callback_seen = seen.clone();
+        let log = ErrorLog::new(Some(Arc::new(move |err: &Error| {
+            callback_seen.lock().unwrap().push(format!("{err:?}"));
+        })));
+        assert_eq!(log.errors(), (0, None));
+
+        log.clone().report(&Error::SimpleError("first"));
+        log.report(&Error::ConnectionClosed);
+        assert_eq!(log.errors(), (2, Some("ConnectionClosed".to_string())));
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec!["SimpleError(\"first\")", "ConnectionClosed"]
+        );
+    }
+}
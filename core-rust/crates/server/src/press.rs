@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+
+use shared::{datatypes::nodes::Node, security::permissions::Permissions};
+use uuid::Uuid;
+
+/// A button press of a client, as seen by a callback.
+pub struct Press<'a> {
+    /// The button after the press.
+    pub node: &'a Node,
+    /// The id of the client. Only unique while the client is connected.
+    pub client: u64,
+    /// Fingerprints of all keys the client proved to own.
+    pub fingerprints: &'a [String],
+    pub permissions: &'a Permissions,
+}
+
+/// Runs on the server when a client presses a button.
+/// A returned message is sent to only this client as [shared::remote::message::Message::ServerLog].
+pub type PressCallback = Box<dyn Fn(&Press) -> Option<String> + Send>;
+
+/// The callbacks for button presses, either for a single button or for every button below a node.
+#[derive(Default)]
+pub struct PressCallbacks {
+    nodes: HashMap<Uuid, Vec<PressCallback>>,
+    subtrees: HashMap<Uuid, Vec<PressCallback>>,
+}
+
+impl PressCallbacks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a callback for the button with [id].
+    pub fn on_press(
+        &mut self,
+        id: Uuid,
+        callback: impl Fn(&Press) -> Option<String> + Send + 'static,
+    ) {
+        self.nodes.entry(id).or_default().push(Box::new(callback));
+    }
+
+    /// Adds a callback for every button below the node with [id], including the node itself.
+    pub fn on_press_subtree(
+        &mut self,
+        id: Uuid,
+        callback: impl Fn(&Press) -> Option<String> + Send + 'static,
+    ) {
+        self.subtrees
+            .entry(id)
+            .or_default()
+            .push(Box::new(callback));
+    }
+
+    /// Runs every callback for [press] in [root] and returns their answers.
+    /// Callbacks of the button run first, then the ones of its subtrees from the inside out.
+    pub(crate) fn run(&self, root: &Node, press: &Press) -> Vec<String> {
+        let mut answers = vec![];
+        for callback in self.nodes.get(&press.node.id).into_iter().flatten() {
+            answers.extend(callback(press));
+        }
+
+        let mut current = Some(press.node.id);
+        while let Some(id) = current {
+            for callback in self.subtrees.get(&id).into_iter().flatten() {
+                answers.extend(callback(press));
+            }
+            current = root.find_node(&id).and_then(|node| node.parent_id);
+        }
+        answers
+    }
+}
@@ -0,0 +1,516 @@
+use std::{
+    fmt::Display,
+    fs,
+    net::ToSocketAddrs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::Deserialize;
+use shared::{config, errors::Error};
+
+/// Everything that can be configured about a running [crate::Server].
+///
+/// The configuration can be built in code, loaded from a TOML file and overridden with
+/// environment variables. Every value is optional, missing ones keep their default.
+///
+/// ```toml
+/// address = "0.0.0.0:8001"      # FSCP_ADDRESS
+/// max_clients = 32              # FSCP_MAX_CLIENTS
+/// queue_size = 1024             # FSCP_QUEUE_SIZE
+/// read_timeout_ms = 50          # FSCP_READ_TIMEOUT_MS
+/// handshake_timeout_ms = 10000  # FSCP_HANDSHAKE_TIMEOUT_MS
+/// session_validity = 3600       # FSCP_SESSION_VALIDITY, in seconds
+/// heartbeat_interval_ms = 5000  # FSCP_HEARTBEAT_INTERVAL_MS
+/// missed_heartbeats = 3         # FSCP_MISSED_HEARTBEATS, a quiet client is closed after them
+/// client_ca = "ca.pem"          # FSCP_CLIENT_CA, only with the rustls feature
+/// tls = true                    # FSCP_TLS, without TLS clients need the key exchange
+/// exchange_key = "exchange.pem" # FSCP_EXCHANGE_KEY, PKCS#8 RSA key signing the key exchange
+///
+/// [identity]
+/// path = "server.pfx"           # FSCP_IDENTITY_PATH, PKCS#12 for native-tls
+/// password = "secret"           # FSCP_IDENTITY_PASSWORD
+/// certificate = "cert.pem"      # FSCP_CERTIFICATE, PEM chain for rustls
+/// key = "key.pem"               # FSCP_PRIVATE_KEY
+/// ```
+///
+/// # Example:
+///
+/// ```no_run
+/// use server::server_config::ServerConfig;
+///
+/// let config = ServerConfig::from_file("server.toml")
+///     .and_then(|config| config.from_env())
+///     .unwrap()
+///     .max_clients(8);
+/// ```
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    pub(crate) address: String,
+    pub(crate) identity_path: PathBuf,
+    pub(crate) identity_password: String,
+    pub(crate) certificate_path: PathBuf,
+    pub(crate) private_key_path: PathBuf,
+    pub(crate) client_ca: Option<PathBuf>,
+    pub(crate) tls: bool,
+    pub(crate) exchange_key: Option<PathBuf>,
+    pub(crate) max_clients: u16,
+    pub(crate) queue_size: usize,
+    pub(crate) read_timeout: Duration,
+    pub(crate) handshake_timeout: Duration,
+    pub(crate) session_validity: u16,
+    pub(crate) heartbeat_interval: Duration,
+    pub(crate) missed_heartbeats: u32,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            address: "localhost:8001".to_string(),
+            identity_path: PathBuf::from("test.pfx"),
+            identity_password: String::new(),
+            certificate_path: PathBuf::from("cert.pem"),
+            private_key_path: PathBuf::from("key.pem"),
+            client_ca: None,
+            tls: true,
+            exchange_key: None,
+            max_clients: config::MAX_CLIENTS,
+            queue_size: config::CLIENT_QUEUE_SIZE,
+            read_timeout: config::POLL_INTERVAL,
+            handshake_timeout: config::HANDSHAKE_TIMEOUT,
+            session_validity: config::SESSION_VALIDITY,
+            heartbeat_interval: config::HEARTBEAT_INTERVAL,
+            missed_heartbeats: config::MISSED_HEARTBEATS,
+        }
+    }
+}
+
+// The layout of the TOML file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    address: Option<String>,
+    max_clients: Option<u16>,
+    queue_size: Option<usize>,
+    read_timeout_ms: Option<u64>,
+    handshake_timeout_ms: Option<u64>,
+    session_validity: Option<u16>,
+    heartbeat_interval_ms: Option<u64>,
+    missed_heartbeats: Option<u32>,
+    client_ca: Option<PathBuf>,
+    tls: Option<bool>,
+    exchange_key: Option<PathBuf>,
+    identity: Option<IdentityFile>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IdentityFile {
+    path: Option<PathBuf>,
+    password: Option<String>,
+    certificate: Option<PathBuf>,
+    key: Option<PathBuf>,
+}
+
+impl ServerConfig {
+    /// Creates the default configuration.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads the configuration from a TOML file. See [ServerConfig] for the format.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        match fs::read_to_string(path) {
+            Ok(content) => Self::from_toml(&content),
+            Err(err) => Err(Error::SimpleErrorStr(format!(
+                "Config: Couldnt read {}: {:?}",
+                path.display(),
+                err
+            ))),
+        }
+    }
+
+    /// Parses the configuration from TOML. See [ServerConfig] for the format.
+    pub fn from_toml(content: &str) -> Result<Self, Error> {
+        let file: ConfigFile = match toml::from_str(content) {
+            Ok(file) => file,
+            Err(err) => return Err(Error::SimpleErrorStr(format!("Config: {err}"))),
+        };
+
+        let mut config = Self::default();
+        if let Some(address) = file.address {
+            config.address = address;
+        }
+        if let Some(max_clients) = file.max_clients {
+            config.max_clients = max_clients;
+        }
+        if let Some(queue_size) = file.queue_size {
+            config.queue_size = queue_size;
+        }
+        if let Some(ms) = file.read_timeout_ms {
+            config.read_timeout = Duration::from_millis(ms);
+        }
+        if let Some(ms) = file.handshake_timeout_ms {
+            config.handshake_timeout = Duration::from_millis(ms);
+        }
+        if let Some(validity) = file.session_validity {
+            config.session_validity = validity;
+        }
+        if let Some(ms) = file.heartbeat_interval_ms {
+            config.heartbeat_interval = Duration::from_millis(ms);
+        }
+        if let Some(missed) = file.missed_heartbeats {
+            config.missed_heartbeats = missed;
+        }
+        if let Some(path) = file.client_ca {
+            config.client_ca = Some(path);
+        }
+        if let Some(tls) = file.tls {
+            config.tls = tls;
+        }
+        if let Some(path) = file.exchange_key {
+            config.exchange_key = Some(path);
+        }
+        if let Some(identity) = file.identity {
+            if let Some(path) = identity.path {
+                config.identity_path = path;
+            }
+            if let Some(password) = identity.password {
+                config.identity_password = password;
+            }
+            if let Some(path) = identity.certificate {
+                config.certificate_path = path;
+            }
+            if let Some(path) = identity.key {
+                config.private_key_path = path;
+            }
+        }
+        Ok(config)
+    }
+
+    /// Overrides the values that are set as environment variables. See [ServerConfig] for the
+    /// names.
+    pub fn from_env(self) -> Result<Self, Error> {
+        self.apply_env(|name| std::env::var(name).ok())
+    }
+
+    fn apply_env(mut self, var: impl Fn(&str) -> Option<String>) -> Result<Self, Error> {
+        fn parse<T: std::str::FromStr>(name: &str, value: String) -> Result<T, Error> {
+            match value.trim().parse() {
+                Ok(value) => Ok(value),
+                Err(_) => Err(Error::SimpleErrorStr(format!(
+                    "Config: {name} has the invalid value {value:?}"
+                ))),
+            }
+        }
+
+        if let Some(address) = var("FSCP_ADDRESS") {
+            self.address = address;
+        }
+        if let Some(path) = var("FSCP_IDENTITY_PATH") {
+            self.identity_path = PathBuf::from(path);
+        }
+        if let Some(password) = var("FSCP_IDENTITY_PASSWORD") {
+            self.identity_password = password;
+        }
+        if let Some(path) = var("FSCP_CERTIFICATE") {
+            self.certificate_path = PathBuf::from(path);
+        }
+        if let Some(path) = var("FSCP_PRIVATE_KEY") {
+            self.private_key_path = PathBuf::from(path);
+        }
+        if let Some(path) = var("FSCP_CLIENT_CA") {
+            self.client_ca = Some(PathBuf::from(path));
+        }
+        if let Some(value) = var("FSCP_TLS") {
+            self.tls = parse("FSCP_TLS", value)?;
+        }
+        if let Some(path) = var("FSCP_EXCHANGE_KEY") {
+            self.exchange_key = Some(PathBuf::from(path));
+        }
+        if let Some(value) = var("FSCP_MAX_CLIENTS") {
+            self.max_clients = parse("FSCP_MAX_CLIENTS", value)?;
+        }
+        if let Some(value) = var("FSCP_QUEUE_SIZE") {
+            self.queue_size = parse("FSCP_QUEUE_SIZE", value)?;
+        }
+        if let Some(value) = var("FSCP_READ_TIMEOUT_MS") {
+            self.read_timeout = Duration::from_millis(parse("FSCP_READ_TIMEOUT_MS", value)?);
+        }
+        if let Some(value) = var("FSCP_HANDSHAKE_TIMEOUT_MS") {
+            self.handshake_timeout =
+                Duration::from_millis(parse("FSCP_HANDSHAKE_TIMEOUT_MS", value)?);
+        }
+        if let Some(value) = var("FSCP_SESSION_VALIDITY") {
+            self.session_validity = parse("FSCP_SESSION_VALIDITY", value)?;
+        }
+        if let Some(value) = var("FSCP_HEARTBEAT_INTERVAL_MS") {
+            self.heartbeat_interval =
+                Duration::from_millis(parse("FSCP_HEARTBEAT_INTERVAL_MS", value)?);
+        }
+        if let Some(value) = var("FSCP_MISSED_HEARTBEATS") {
+            self.missed_heartbeats = parse("FSCP_MISSED_HEARTBEATS", value)?;
+        }
+        Ok(self)
+    }
+
+    /// Sets the address (host:port) the server listens on.
+    pub fn address(mut self, address: impl Display) -> Self {
+        self.address = address.to_string();
+        self
+    }
+
+    /// Sets the PKCS#12 file with the certificate and key of the server and its password.
+    /// Is used by the native-tls backend.
+    pub fn identity(mut self, path: impl AsRef<Path>, password: impl Display) -> Self {
+        self.identity_path = path.as_ref().to_path_buf();
+        self.identity_password = password.to_string();
+        self
+    }
+
+    /// Sets the PEM files with the certificate chain and the private key of the server.
+    /// Are used by the rustls backend.
+    pub fn certificate(mut self, certificate: impl AsRef<Path>, key: impl AsRef<Path>) -> Self {
+        self.certificate_path = certificate.as_ref().to_path_buf();
+        self.private_key_path = key.as_ref().to_path_buf();
+        self
+    }
+
+    /// Only accepts clients with a certificate that is signed by one in the PEM file [path].
+    /// Needs the rustls backend.
+    pub fn client_ca(mut self, path: impl AsRef<Path>) -> Self {
+        self.client_ca = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Serves without TLS if [tls] is false. Then every client has to do the key exchange, which
+    /// needs [ServerConfig::exchange_key].
+    pub fn tls(mut self, tls: bool) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Lets clients do the key exchange of [shared::security::exchange], which the server signs
+    /// with the RSA key in the PKCS#8 PEM file [path]. Clients that do it have every frame
+    /// encrypted by the protocol itself, in addition to TLS if it is used.
+    pub fn exchange_key(mut self, path: impl AsRef<Path>) -> Self {
+        self.exchange_key = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets how many clients can be connected at the same time.
+    pub fn max_clients(mut self, max_clients: u16) -> Self {
+        self.max_clients = max_clients;
+        self
+    }
+
+    /// Sets how many messages can wait for a client. A client that does not read fast enough
+    /// skips the changes that do not fit and gets the whole tree once it caught up.
+    pub fn queue_size(mut self, queue_size: usize) -> Self {
+        self.queue_size = queue_size;
+        self
+    }
+
+    /// Sets how long a read on a connection blocks before the connection checks for messages
+    /// to send. Lower values send changes faster but wake up more often.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+
+    /// Sets how long a client may take for each step of the handshake.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Sets how long a session is valid in seconds.
+    pub fn session_validity(mut self, seconds: u16) -> Self {
+        self.session_validity = seconds;
+        self
+    }
+
+    /// Sets how often the server pings each client. Only clients that speak
+    /// [shared::remote::version::HEARTBEAT_VERSION] are pinged.
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.heartbeat_interval = interval;
+        self
+    }
+
+    /// Sets after how many heartbeat intervals without anything from a client its connection
+    /// is closed. The client can resume its session afterwards.
+    pub fn missed_heartbeats(mut self, missed: u32) -> Self {
+        self.missed_heartbeats = missed;
+        self
+    }
+
+    /// Checks the configuration. All problems are reported at once.
+    pub fn validate(&self) -> Result<(), Error> {
+        let mut problems = vec![];
+
+        let resolves = self
+            .address
+            .to_socket_addrs()
+            .is_ok_and(|mut addresses| addresses.next().is_some());
+        if !resolves {
+            problems.push(format!("address {:?} is invalid", self.address));
+        }
+        let mut files = vec![];
+        if !self.tls {
+            if self.exchange_key.is_none() {
+                problems.push("without tls an exchange_key is needed".to_string());
+            }
+        } else if cfg!(feature = "rustls") {
+            files.push(("certificate", &self.certificate_path));
+            files.push(("private key", &self.private_key_path));
+        } else {
+            files.push(("identity", &self.identity_path));
+            if self.client_ca.is_some() {
+                problems.push("client_ca needs the rustls feature".to_string());
+            }
+        }
+        if let Some(client_ca) = &self.client_ca
+            && self.tls
+        {
+            files.push(("client ca", client_ca));
+        }
+        if let Some(exchange_key) = &self.exchange_key {
+            files.push(("exchange key", exchange_key));
+        }
+        for (name, path) in files {
+            if !path.is_file() {
+                problems.push(format!("{name} file {} does not exist", path.display()));
+            }
+        }
+        if self.max_clients == 0 {
+            problems.push("max_clients has to be at least 1".to_string());
+        }
+        if self.queue_size == 0 {
+            problems.push("queue_size has to be at least 1".to_string());
+        }
+        if self.read_timeout.is_zero() {
+            problems.push("read timeout has to be longer than 0".to_string());
+        }
+        if self.handshake_timeout.is_zero() {
+            problems.push("handshake timeout has to be longer than 0".to_string());
+        }
+        if self.session_validity == 0 {
+            problems.push("session validity has to be longer than 0".to_string());
+        }
+        if self.heartbeat_interval.is_zero() {
+            problems.push("heartbeat interval has to be longer than 0".to_string());
+        }
+        if self.missed_heartbeats == 0 {
+            problems.push("missed_heartbeats has to be at least 1".to_string());
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::SimpleErrorStr(format!(
+                "Config: {}",
+                problems.join(", ")
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, time::Duration};
+
+    use crate::server_config::ServerConfig;
+
+    #[test]
+    fn toml_and_env() {
+        let config = ServerConfig::from_toml(
+            r#"
+            address = "127.0.0.1:9000"
+            max_clients = 4
+            read_timeout_ms = 20
+            heartbeat_interval_ms = 1000
+
+            [identity]
+            path = "server.pfx"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.address, "127.0.0.1:9000");
+        assert_eq!(config.max_clients, 4);
+        assert_eq!(config.read_timeout, Duration::from_millis(20));
+        assert_eq!(config.heartbeat_interval, Duration::from_secs(1));
+        assert_eq!(config.identity_path.to_str(), Some("server.pfx"));
+        assert_eq!(config.identity_password, "");
+
+        let env = HashMap::from([
+            ("FSCP_MAX_CLIENTS", "16"),
+            ("FSCP_IDENTITY_PASSWORD", "secret"),
+            ("FSCP_MISSED_HEARTBEATS", "5"),
+        ]);
+        let config = config
+            .apply_env(|name| env.get(name).map(|v| v.to_string()))
+            .unwrap();
+        assert_eq!(config.max_clients, 16);
+        assert_eq!(config.identity_password, "secret");
+        assert_eq!(config.missed_heartbeats, 5);
+        assert_eq!(config.address, "127.0.0.1:9000");
+
+        let env = HashMap::from([("FSCP_MAX_CLIENTS", "many")]);
+        assert!(
+            config
+                .apply_env(|name| env.get(name).map(|v| v.to_string()))
+                .is_err()
+        );
+
+        // Typos are not silently ignored.
+        assert!(ServerConfig::from_toml("adress = \"localhost:1\"").is_err());
+    }
+
+    #[test]
+    fn validate() {
+        let path = std::env::temp_dir().join(format!("fscp-identity-{}", std::process::id()));
+        std::fs::write(&path, "").unwrap();
+
+        let valid = ServerConfig::new()
+            .identity(&path, "")
+            .certificate(&path, &path)
+            .address("127.0.0.1:0");
+        let valid_result = valid.validate();
+        let plain_result = valid.clone().tls(false).exchange_key(&path).validate();
+        let invalid = valid
+            .address("not an address")
+            .max_clients(0)
+            .read_timeout(Duration::ZERO)
+            .missed_heartbeats(0);
+        let invalid_result = invalid.validate();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(valid_result.is_ok());
+        assert!(plain_result.is_ok());
+        let message = format!("{:?}", invalid_result.unwrap_err());
+        assert!(message.contains("address"));
+        assert!(message.contains("max_clients"));
+        assert!(message.contains("read timeout"));
+        assert!(message.contains("missed_heartbeats"));
+
+        assert!(
+            ServerConfig::new()
+                .identity("missing.pfx", "")
+                .certificate("missing.pem", "missing.pem")
+                .validate()
+                .is_err()
+        );
+
+        // Without TLS no certificate is needed, but the key exchange is.
+        let plain = ServerConfig::new()
+            .identity("missing.pfx", "")
+            .certificate("missing.pem", "missing.pem")
+            .address("127.0.0.1:0")
+            .tls(false);
+        let message = format!("{:?}", plain.validate().unwrap_err());
+        assert!(message.contains("exchange_key"));
+        assert!(!message.contains("identity"));
+        assert!(plain.exchange_key("missing.pem").validate().is_err());
+    }
+}
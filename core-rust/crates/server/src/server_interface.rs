@@ -1,11 +1,36 @@
-use shared::{datatypes::nodes::Node, errors::Error};
+use shared::{
+    datatypes::{Data, nodes::Node},
+    errors::Error,
+    security::permissions::Permissions,
+};
 use uuid::Uuid;
 
+/// Changes the tree of a [crate::Server] before it serves, or of a [crate::RunningServer] while
+/// it serves. Code that builds the tree can be written once for both.
 pub trait ServerInterface {
     /// Add a child to the root node of the tree.
     fn add_child(&mut self, node: Node) -> Result<(), Error>;
     /// Add a child to the the node with the corresponding id.
     fn add_child_to_node(&mut self, node: Node, parent_id: &Uuid) -> Result<(), Error>;
+    /// Add a child to the node at [path], like `/plant/line1`. Folders on the way that do not
+    /// exist yet are created. See [shared::datatypes::path] for the syntax.
+    fn add_child_at_path(&mut self, node: Node, path: &str) -> Result<(), Error>;
+    /// Changes the data of the node with this id.
+    fn change_data(&mut self, id: &Uuid, data: Data) -> Result<(), Error>;
+    /// Changes the name of the node with this id.
+    fn change_name(&mut self, id: &Uuid, name: &str) -> Result<(), Error>;
+    /// Changes the permissions of the node with this id.
+    fn change_permissions(&mut self, id: &Uuid, permissions: Permissions) -> Result<(), Error>;
+    /// Removes the node with this id and everything below it. The root cannot be removed.
+    fn remove(&mut self, id: &Uuid) -> Result<(), Error>;
+    /// Moves the node with this id and everything below it to the node with [new_parent], where
+    /// it is the child at [position]. A node cannot be moved below itself.
+    fn move_node(&mut self, id: &Uuid, new_parent: &Uuid, position: usize) -> Result<(), Error>;
+}
+
+/// Reads the tree of a [crate::Server] that does not serve yet. Once it serves, the tree belongs
+/// to the handler and [crate::RunningServer::node] returns copies of the nodes instead.
+pub trait TreeAccess {
     // get a immutable reference of the node with this id.
     fn get_node(&self, id: &Uuid) -> Result<&Node, Error>;
     // get a mutable reference of the node with this id.
@@ -0,0 +1,218 @@
+// Lets [crate::RunningServer::shutdown] stop accepting clients and wait for the open connections.
+// Every accepted connection is tracked until its thread or task ends. A connection that does not
+// close in time is closed by shutting its socket down, which ends everything waiting on it.
+
+use std::{
+    collections::HashMap,
+    net::{Shutdown, SocketAddr, TcpStream},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    time::Instant,
+};
+
+use shared::errors::Error;
+
+use crate::log::ErrorLog;
+
+/// What happened to the connections during [crate::RunningServer::shutdown].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// How many connections closed in time.
+    pub closed: usize,
+    /// The addresses of the clients that did not close in time. Their connections were closed by
+    /// the server.
+    pub timed_out: Vec<SocketAddr>,
+    /// How often accepting a client failed while the server ran, like when it ran out of file
+    /// descriptors.
+    pub accept_errors: usize,
+    /// The last of these errors.
+    pub last_accept_error: Option<String>,
+    /// How many connections ended with an error and how many messages the handler could not
+    /// handle while the server ran. Each was passed to [crate::Server::on_error].
+    pub errors: usize,
+    /// The last of these errors.
+    pub last_error: Option<String>,
+}
+
+#[derive(Default)]
+struct State {
+    stopping: bool,
+    next_id: u64,
+    open: HashMap<u64, (SocketAddr, TcpStream)>,
+    accept_errors: usize,
+    last_accept_error: Option<String>,
+}
+
+/// The connections of a server. Clones share the same connections.
+#[derive(Clone, Default)]
+pub(crate) struct Connections {
+    state: Arc<(Mutex<State>, Condvar)>,
+    errors: ErrorLog,
+}
+
+impl Connections {
+    /// The connections report the errors they end with to [errors].
+    pub fn new(errors: ErrorLog) -> Self {
+        Self {
+            state: Arc::default(),
+            errors,
+        }
+    }
+
+    /// Returns [true] once the listener should stop accepting.
+    pub fn is_stopping(&self) -> bool {
+        self.lock().stopping
+    }
+
+    pub fn stop(&self) {
+        self.lock().stopping = true;
+    }
+
+    /// Tracks a connection until the returned guard is dropped. [socket] is a clone of the socket
+    /// of the connection, to close it if it does not close in time.
+    pub fn track(&self, address: SocketAddr, socket: TcpStream) -> Tracked {
+        let mut state = self.lock();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.open.insert(id, (address, socket));
+        Tracked {
+            connections: self.clone(),
+            id,
+        }
+    }
+
+    pub fn open(&self) -> usize {
+        self.lock().open.len()
+    }
+
+    /// Remembers that accepting a client failed, for the [ShutdownReport].
+    pub fn accept_failed(&self, err: &std::io::Error) {
+        let mut state = self.lock();
+        state.accept_errors += 1;
+        state.last_accept_error = Some(err.to_string());
+    }
+
+    /// Reports the error a connection ended with.
+    pub fn failed(&self, err: &Error) {
+        self.errors.report(err);
+    }
+
+    /// How many errors were reported and the last one, see [ErrorLog].
+    pub fn errors(&self) -> (usize, Option<String>) {
+        self.errors.errors()
+    }
+
+    /// How often accepting a client failed and the last error.
+    pub fn accept_errors(&self) -> (usize, Option<String>) {
+        let state = self.lock();
+        (state.accept_errors, state.last_accept_error.clone())
+    }
+
+    /// Waits until every connection is closed. Returns [false] if some are still open at
+    /// [deadline].
+    pub fn wait(&self, deadline: Option<Instant>) -> bool {
+        let (_, closed) = &*self.state;
+        let mut state = self.lock();
+        while !state.open.is_empty() {
+            state = match deadline {
+                Some(deadline) => {
+                    let Some(timeout) = deadline.checked_duration_since(Instant::now()) else {
+                        return false;
+                    };
+                    match closed.wait_timeout(state, timeout) {
+                        Ok((state, _)) => state,
+                        Err(poisoned) => poisoned.into_inner().0,
+                    }
+                }
+                None => match closed.wait(state) {
+                    Ok(state) => state,
+                    Err(poisoned) => poisoned.into_inner(),
+                },
+            };
+        }
+        true
+    }
+
+    /// Shuts down the sockets of all open connections. Returns the addresses of their clients.
+    pub fn close_all(&self) -> Vec<SocketAddr> {
+        self.lock()
+            .open
+            .values()
+            .map(|(address, socket)| {
+                // The connection might have closed the socket itself just now.
+                let _ = socket.shutdown(Shutdown::Both);
+                *address
+            })
+            .collect()
+    }
+
+    // The state is always consistent, so a panic while holding the lock does not matter.
+    fn lock(&self) -> MutexGuard<'_, State> {
+        match self.state.0.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+/// A tracked connection. Is no longer tracked when dropped.
+pub(crate) struct Tracked {
+    connections: Connections,
+    id: u64,
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.connections.lock().open.remove(&self.id);
+        self.connections.state.1.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::Read,
+        net::{TcpListener, TcpStream},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crate::shutdown::Connections;
+
+    #[test]
+    fn close_all() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut stream, address) = listener.accept().unwrap();
+
+        let connections = Connections::default();
+        let tracked = connections.track(address, stream.try_clone().unwrap());
+        let connection = thread::spawn(move || {
+            // Blocks until the socket is shut down.
+            let _ = stream.read(&mut [0; 16]);
+            drop(tracked);
+        });
+
+        let deadline = Instant::now() + Duration::from_millis(100);
+        assert!(!connections.wait(Some(deadline)));
+        assert_eq!(connections.open(), 1);
+
+        assert_eq!(connections.close_all(), vec![address]);
+        assert!(connections.wait(None));
+        connection.join().unwrap();
+        assert_eq!(connections.open(), 0);
+    }
+
+    #[test]
+    fn accept_errors() {
+        let connections = Connections::default();
+        assert_eq!(connections.accept_errors(), (0, None));
+
+        for _ in 0..2 {
+            connections.accept_failed(&std::io::Error::other("Too many open files"));
+        }
+        assert_eq!(
+            connections.accept_errors(),
+            (2, Some("Too many open files".to_string()))
+        );
+    }
+}
@@ -0,0 +1,295 @@
+// The TLS backend of the server, selected by cargo feature.
+// `native-tls` (default) loads a PKCS#12 identity and uses the TLS library of the system.
+// `rustls` loads the certificate chain and key from PEM files and can verify client
+// certificates against a CA bundle. If both are enabled rustls is used.
+// Without TLS in the config connections stay plain, the key exchange protects them instead.
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+compile_error!("Either the native-tls or the rustls feature of the server has to be enabled");
+
+#[cfg(feature = "rustls")]
+use std::{fs::File, io::BufReader, path::Path, sync::Arc};
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+};
+
+use shared::{errors::Error, security::exchange::ServerExchange};
+
+use crate::server_config::ServerConfig;
+
+#[cfg(feature = "rustls")]
+pub(crate) type TlsStream = rustls::StreamOwned<rustls::ServerConnection, TcpStream>;
+#[cfg(not(feature = "rustls"))]
+pub(crate) type TlsStream = native_tls::TlsStream<TcpStream>;
+
+/// A connection to a client. It is only plain if the server runs without TLS.
+pub(crate) enum Stream {
+    Tls(Box<TlsStream>),
+    Plain(TcpStream),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Tls(stream) => stream.read(buf),
+            Stream::Plain(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Tls(stream) => stream.write(buf),
+            Stream::Plain(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Stream::Tls(stream) => stream.flush(),
+            Stream::Plain(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Prepares new connections for the protocol, as configured.
+pub(crate) struct Acceptor {
+    tls: Option<TlsAcceptor>,
+    exchange: Option<ServerExchange>,
+}
+
+impl Acceptor {
+    /// Loads the TLS identity and the key for the key exchange from the files in [config].
+    pub(crate) fn new(config: &ServerConfig) -> Result<Self, Error> {
+        let tls = match config.tls {
+            true => Some(TlsAcceptor::new(config)?),
+            false => None,
+        };
+        let exchange = match &config.exchange_key {
+            Some(path) => Some(ServerExchange::load(path)?),
+            None => None,
+        };
+        Ok(Self { tls, exchange })
+    }
+
+    /// Does the TLS handshake on a new connection, if the server uses TLS.
+    /// Read timeouts of [stream] end the handshake with an error.
+    pub(crate) fn accept(&self, stream: TcpStream) -> Result<Stream, Error> {
+        match &self.tls {
+            Some(tls) => Ok(Stream::Tls(Box::new(tls.accept(stream)?))),
+            None => Ok(Stream::Plain(stream)),
+        }
+    }
+
+    /// Signs the key exchange, if the server has a key for it.
+    pub(crate) fn exchange(&self) -> Option<&ServerExchange> {
+        self.exchange.as_ref()
+    }
+
+    /// Returns [true] if clients have to do the key exchange, because nothing else protects
+    /// their connections.
+    pub(crate) fn needs_exchange(&self) -> bool {
+        self.tls.is_none()
+    }
+}
+
+/// Does the server side of the TLS handshake for new connections.
+pub(crate) struct TlsAcceptor {
+    #[cfg(feature = "rustls")]
+    config: Arc<rustls::ServerConfig>,
+    #[cfg(not(feature = "rustls"))]
+    acceptor: native_tls::TlsAcceptor,
+}
+
+impl TlsAcceptor {
+    /// Loads the certificate and key from the files in [config].
+    #[cfg(feature = "rustls")]
+    pub(crate) fn new(config: &ServerConfig) -> Result<Self, Error> {
+        Ok(Self {
+            config: rustls_config(config)?,
+        })
+    }
+
+    /// Loads the PKCS#12 identity from the file in [config].
+    #[cfg(not(feature = "rustls"))]
+    pub(crate) fn new(config: &ServerConfig) -> Result<Self, Error> {
+        let path = &config.identity_path;
+        let identity = match std::fs::read(path) {
+            Ok(identity) => identity,
+            Err(err) => {
+                return Err(Error::SimpleErrorStr(format!(
+                    "TLS: Couldnt open pfx file {}: {:?}",
+                    path.display(),
+                    err
+                )));
+            }
+        };
+        let identity = match native_tls::Identity::from_pkcs12(&identity, &config.identity_password)
+        {
+            Ok(identity) => identity,
+            Err(err) => {
+                return Err(Error::SimpleErrorStr(format!(
+                    "TLS: Couldnt create identity from pfx file {} (wrong password?): {:?}",
+                    path.display(),
+                    err
+                )));
+            }
+        };
+        Ok(Self {
+            acceptor: Error::from(native_tls::TlsAcceptor::new(identity))?,
+        })
+    }
+
+    /// Does the TLS handshake on a new connection.
+    /// Read timeouts of [stream] end the handshake with an error.
+    #[cfg(feature = "rustls")]
+    pub(crate) fn accept(&self, mut stream: TcpStream) -> Result<TlsStream, Error> {
+        let mut connection = Error::from(rustls::ServerConnection::new(self.config.clone()))?;
+        while connection.is_handshaking() {
+            if let Err(err) = connection.complete_io(&mut stream) {
+                return Err(Error::SimpleErrorStr(format!("TLS: Handshake: {:?}", err)));
+            }
+        }
+        Ok(rustls::StreamOwned::new(connection, stream))
+    }
+
+    /// Does the TLS handshake on a new connection.
+    /// Read timeouts of [stream] end the handshake with an error.
+    #[cfg(not(feature = "rustls"))]
+    pub(crate) fn accept(&self, stream: TcpStream) -> Result<TlsStream, Error> {
+        match self.acceptor.accept(stream) {
+            Ok(stream) => Ok(stream),
+            Err(err) => Err(Error::SimpleErrorStr(format!("TLS: Handshake: {:?}", err))),
+        }
+    }
+}
+
+/// The connection below the TLS layer, to set timeouts on it.
+pub(crate) fn socket(stream: &Stream) -> &TcpStream {
+    match stream {
+        Stream::Tls(stream) => stream.get_ref(),
+        Stream::Plain(stream) => stream,
+    }
+}
+
+/// Loads the certificate and key from the files in [config].
+#[cfg(feature = "rustls")]
+pub(crate) fn rustls_config(config: &ServerConfig) -> Result<Arc<rustls::ServerConfig>, Error> {
+    let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+    let builder = Error::from(
+        rustls::ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions(),
+    )?;
+
+    let builder = match &config.client_ca {
+        Some(path) => {
+            let mut roots = rustls::RootCertStore::empty();
+            for certificate in load_certificates(path)? {
+                Error::from(roots.add(certificate))?;
+            }
+            let verifier = Error::from(
+                rustls::server::WebPkiClientVerifier::builder_with_provider(
+                    Arc::new(roots),
+                    provider,
+                )
+                .build(),
+            )?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let certificates = load_certificates(&config.certificate_path)?;
+    let key = load_private_key(&config.private_key_path)?;
+    match builder.with_single_cert(certificates, key) {
+        Ok(config) => Ok(Arc::new(config)),
+        Err(err) => Err(Error::SimpleErrorStr(format!(
+            "TLS: Certificate and key do not fit together: {:?}",
+            err
+        ))),
+    }
+}
+
+#[cfg(feature = "rustls")]
+fn load_certificates(
+    path: &Path,
+) -> Result<Vec<rustls::pki_types::CertificateDer<'static>>, Error> {
+    let mut reader = BufReader::new(open(path)?);
+    let certificates: Vec<_> = match rustls_pemfile::certs(&mut reader).collect() {
+        Ok(certificates) => certificates,
+        Err(err) => {
+            return Err(Error::SimpleErrorStr(format!(
+                "TLS: Couldnt read certificates from {}: {:?}",
+                path.display(),
+                err
+            )));
+        }
+    };
+    if certificates.is_empty() {
+        return Err(Error::SimpleErrorStr(format!(
+            "TLS: No certificate in {}",
+            path.display()
+        )));
+    }
+    Ok(certificates)
+}
+
+#[cfg(feature = "rustls")]
+fn load_private_key(path: &Path) -> Result<rustls::pki_types::PrivateKeyDer<'static>, Error> {
+    let mut reader = BufReader::new(open(path)?);
+    match rustls_pemfile::private_key(&mut reader) {
+        Ok(Some(key)) => Ok(key),
+        Ok(None) => Err(Error::SimpleErrorStr(format!(
+            "TLS: No private key in {}",
+            path.display()
+        ))),
+        Err(err) => Err(Error::SimpleErrorStr(format!(
+            "TLS: Couldnt read private key from {}: {:?}",
+            path.display(),
+            err
+        ))),
+    }
+}
+
+#[cfg(feature = "rustls")]
+fn open(path: &Path) -> Result<File, Error> {
+    match File::open(path) {
+        Ok(file) => Ok(file),
+        Err(err) => Err(Error::SimpleErrorStr(format!(
+            "TLS: Couldnt open {}: {:?}",
+            path.display(),
+            err
+        ))),
+    }
+}
+
+#[cfg(all(test, feature = "rustls"))]
+mod test {
+    use std::path::PathBuf;
+
+    use crate::{server_config::ServerConfig, tls::TlsAcceptor};
+
+    // The certificate and key that are checked in at the root of the repository.
+    fn repository_file(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("../../..")
+            .join(name)
+    }
+
+    #[test]
+    fn load_pem() {
+        let config = ServerConfig::new()
+            .certificate(repository_file("cert.pem"), repository_file("key.pem"));
+        assert!(TlsAcceptor::new(&config).is_ok());
+
+        // The key is not a certificate and the other way around.
+        let swapped = ServerConfig::new()
+            .certificate(repository_file("key.pem"), repository_file("cert.pem"));
+        assert!(TlsAcceptor::new(&swapped).is_err());
+
+        let client_ca = config.client_ca(repository_file("missing.pem"));
+        assert!(TlsAcceptor::new(&client_ca).is_err());
+    }
+}
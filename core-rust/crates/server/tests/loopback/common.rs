@@ -0,0 +1,131 @@
+// Serves a server on a free port of the loopback interface and connects clients to it, with the
+// test certificates in the root of the repository. A [Proxy] between them can drop connections.
+
+use std::{
+    io,
+    net::{Shutdown, TcpListener, TcpStream},
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use client::{Client, ClientBuilder};
+use server::{RunningServer, Server, server_config::ServerConfig};
+
+// How long a test waits for something that should happen right away.
+pub const WAIT: Duration = Duration::from_secs(5);
+
+// A file in the root of the repository.
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("../../..")
+        .join(name)
+}
+
+// The configuration of a server on a free port of 127.0.0.1 with the test certificates.
+pub fn config() -> ServerConfig {
+    ServerConfig::new()
+        .address("127.0.0.1:0")
+        .identity(fixture("test.pfx"), "")
+        .certificate(fixture("cert.pem"), fixture("key.pem"))
+}
+
+// Serves [server] and returns it with its address.
+pub fn serve(server: Server) -> (RunningServer, String) {
+    let running = server.serve().unwrap();
+    let address = running.address().unwrap().to_string();
+    (running, address)
+}
+
+// A client for the server at [address] that accepts the self signed certificate.
+pub fn builder(address: &str) -> ClientBuilder {
+    ClientBuilder::new(address)
+        .domain("localhost")
+        .accept_invalid_certs(true)
+}
+
+pub fn connect(address: &str) -> Client {
+    builder(address).connect().unwrap()
+}
+
+// Waits at most [WAIT] until [done] returns true. Returns whether it did.
+pub fn eventually(mut done: impl FnMut() -> bool) -> bool {
+    let deadline = Instant::now() + WAIT;
+    while Instant::now() < deadline {
+        if done() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    done()
+}
+
+// Whether the mirror of [client] has the tree of the server, as far as the client can see it.
+pub fn converged(client: &Client, running: &RunningServer) -> bool {
+    let root = running.node(&uuid::Uuid::nil()).unwrap();
+    client.tree().unwrap().get_hash() == root.get_hash()
+}
+
+// Forwards connections to a server, so that a test can drop them like a network would.
+pub struct Proxy {
+    pub address: String,
+    // Both sockets of every forwarded connection.
+    sockets: Arc<Mutex<Vec<TcpStream>>>,
+    // Connections are closed right away while this is set.
+    blocked: Arc<AtomicBool>,
+}
+
+impl Proxy {
+    // Forwards the connections to a free port of 127.0.0.1 to [target].
+    pub fn new(target: &str) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = Proxy {
+            address: listener.local_addr().unwrap().to_string(),
+            sockets: Arc::default(),
+            blocked: Arc::default(),
+        };
+        let target = target.to_string();
+        let sockets = proxy.sockets.clone();
+        let blocked = proxy.blocked.clone();
+        // Ends with the test.
+        thread::spawn(move || {
+            for client in listener.incoming().flatten() {
+                if blocked.load(Ordering::SeqCst) {
+                    continue;
+                }
+                let Ok(server) = TcpStream::connect(&target) else {
+                    continue;
+                };
+                let mut sockets = sockets.lock().unwrap();
+                sockets.push(client.try_clone().unwrap());
+                sockets.push(server.try_clone().unwrap());
+                forward(client.try_clone().unwrap(), server.try_clone().unwrap());
+                forward(server, client);
+            }
+        });
+        proxy
+    }
+
+    // Drops every forwarded connection. Both ends see it closed.
+    pub fn cut(&self) {
+        for socket in self.sockets.lock().unwrap().drain(..) {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+    }
+
+    // Closes new connections right away while [blocked] is set.
+    pub fn block(&self, blocked: bool) {
+        self.blocked.store(blocked, Ordering::SeqCst);
+    }
+}
+
+fn forward(mut from: TcpStream, mut to: TcpStream) {
+    thread::spawn(move || {
+        let _ = io::copy(&mut from, &mut to);
+        let _ = to.shutdown(Shutdown::Both);
+    });
+}
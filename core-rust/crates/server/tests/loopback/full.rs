@@ -0,0 +1,47 @@
+// A full server tells only a few clients at once that it is full and closes the others right
+// away.
+
+use std::{
+    io::{ErrorKind, Read},
+    net::TcpStream,
+    time::Duration,
+};
+
+use server::Server;
+use shared::config;
+
+use crate::common::{WAIT, config, connect, serve};
+
+#[test]
+fn rejections() {
+    let (running, address) = serve(Server::from_config(config().max_clients(1)));
+    let client = connect(&address);
+
+    // Each of them is in the TLS handshake of a rejection until the handshake times out.
+    let waiting: Vec<TcpStream> = (0..config::MAX_REJECTIONS)
+        .map(|_| TcpStream::connect(&address).unwrap())
+        .collect();
+    let mut over = TcpStream::connect(&address).unwrap();
+
+    let mut buf = [0; 1];
+    over.set_read_timeout(Some(WAIT)).unwrap();
+    match over.read(&mut buf) {
+        Ok(0) => {}
+        Err(err) if err.kind() == ErrorKind::ConnectionReset => {}
+        result => panic!("Expected the connection to be closed, got {result:?}"),
+    }
+    for mut stream in waiting {
+        stream
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let err = stream.read(&mut buf).unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::WouldBlock | ErrorKind::TimedOut
+        ));
+    }
+    assert_eq!(running.connections().current, 1);
+
+    client.disconnect().unwrap();
+    running.shutdown(Duration::from_millis(500)).unwrap();
+}
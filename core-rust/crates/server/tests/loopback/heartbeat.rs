@@ -0,0 +1,45 @@
+// A server closes the connection of a client that stopped answering, and the client reconnects
+// once it answers again.
+
+use std::time::Duration;
+
+use server::{Server, server_interface::ServerInterface};
+use shared::datatypes::{Data, nodes::Node};
+use uuid::Uuid;
+
+use crate::common::{WAIT, config, connect, converged, eventually, serve};
+
+#[test]
+fn quiet_client() {
+    let value = Uuid::from_u128(1);
+    let config = config()
+        .heartbeat_interval(Duration::from_millis(100))
+        .missed_heartbeats(2);
+    let mut server = Server::from_config(config);
+    server
+        .add_child(Node::new().id(value).data(Data::Int32(0)))
+        .unwrap();
+    let (mut running, address) = serve(server);
+
+    let client = connect(&address);
+    let session = client.session();
+    assert_eq!(running.connections().current, 1);
+
+    {
+        // The connection of the client waits for the tree to apply the change and does not
+        // answer the heartbeats meanwhile.
+        let _tree = client.tree().unwrap();
+        running.change_data(&value, Data::Int32(1)).unwrap();
+        assert!(eventually(|| running.connections().current == 0));
+    }
+
+    // The client notices that the connection is gone and resumes its session.
+    assert!(eventually(|| running.connections().current == 1));
+    assert!(client.is_connected());
+    assert_eq!(client.session(), session);
+    running.change_data(&value, Data::Int32(2)).unwrap();
+    assert!(eventually(|| converged(&client, &running)));
+
+    client.disconnect().unwrap();
+    running.shutdown(WAIT).unwrap();
+}
@@ -0,0 +1,11 @@
+// Servers on the loopback interface with real clients connected over TLS.
+// Every test serves its own server on a free port.
+
+mod common;
+mod full;
+mod heartbeat;
+mod mirror;
+mod resume;
+mod shutdown;
+#[cfg(feature = "tokio")]
+mod slow;
@@ -0,0 +1,122 @@
+// A client connects over TLS, mirrors the tree, presses buttons and proves it owns a key.
+
+use std::time::Duration;
+
+use rsa::RsaPrivateKey;
+use server::{Server, server_interface::ServerInterface};
+use shared::{
+    datatypes::{Data, nodes::Node},
+    security::{fingerprint::fingerprint, permissions::Permissions},
+};
+use uuid::Uuid;
+
+use crate::common::{WAIT, config, connect, converged, eventually, serve};
+
+#[test]
+fn mirror() {
+    let value = Uuid::from_u128(1);
+    let mut server = Server::from_config(config());
+    server
+        .add_child(Node::new().id(value).name("value").data(Data::Int32(0)))
+        .unwrap();
+    let (mut running, address) = serve(server);
+
+    let client = connect(&address);
+    assert!(converged(&client, &running));
+
+    running.change_data(&value, Data::Int32(1)).unwrap();
+    running.change_name(&value, "renamed").unwrap();
+    running
+        .add_child_at_path(Node::new().data(Data::Float64(0.5)), "/plant/line1")
+        .unwrap();
+    running.move_node(&value, &Uuid::nil(), 1).unwrap();
+    assert!(eventually(|| converged(&client, &running)));
+    assert!(matches!(
+        client.tree().unwrap().find_node(&value).unwrap().data,
+        Data::Int32(1)
+    ));
+
+    running.remove(&value).unwrap();
+    assert!(eventually(|| converged(&client, &running)));
+    assert!(client.tree().unwrap().find_node(&value).is_none());
+
+    client.disconnect().unwrap();
+    let report = running.shutdown(WAIT).unwrap();
+    assert_eq!(report.errors, 0, "{:?}", report.last_error);
+}
+
+#[test]
+fn press() {
+    let button = Uuid::from_u128(1);
+    let (pressed_s, pressed_r) = crossbeam::channel::unbounded();
+    let mut server = Server::from_config(config());
+    server
+        .add_child(Node::new().id(button).data(Data::Button(0)))
+        .unwrap();
+    server.on_press(button, move |press| {
+        pressed_s.send(press.client).unwrap();
+        Some("pressed".to_string())
+    });
+    let (running, address) = serve(server);
+
+    let client = connect(&address);
+    client.press(&button).unwrap().wait(WAIT).unwrap();
+    assert!(pressed_r.recv_timeout(WAIT).is_ok());
+    assert_eq!(client.next_log(WAIT).as_deref(), Some("pressed"));
+    // The new count of the button is mirrored.
+    assert!(eventually(|| converged(&client, &running)));
+    assert!(matches!(
+        client.tree().unwrap().find_node(&button).unwrap().data,
+        Data::Button(1)
+    ));
+
+    client.disconnect().unwrap();
+    running.shutdown(WAIT).unwrap();
+}
+
+#[test]
+fn add_permissions() {
+    let secret = Uuid::from_u128(1);
+    let mut server = Server::from_config(config());
+    server
+        .add_child(
+            Node::new()
+                .id(secret)
+                .data(Data::Int32(7))
+                .permissions(Permissions::Admin),
+        )
+        .unwrap();
+    let (running, address) = serve(server);
+
+    // A small key, the size is not checked and a big one takes long to generate.
+    let key = RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+    running
+        .add_credential(
+            fingerprint(&key.to_public_key()).unwrap(),
+            Permissions::Admin,
+        )
+        .unwrap();
+
+    let client = connect(&address);
+    assert!(client.tree().unwrap().find_node(&secret).is_none());
+
+    // The server challenges the client to prove it owns the key.
+    client
+        .add_permissions(key)
+        .unwrap()
+        .wait(Duration::from_secs(10))
+        .unwrap();
+    assert!(matches!(
+        client.tree().unwrap().find_node(&secret).unwrap().data,
+        Data::Int32(7)
+    ));
+    assert!(eventually(|| converged(&client, &running)));
+
+    // Only the client that proved it owns the key sees the node.
+    let other = connect(&address);
+    assert!(other.tree().unwrap().find_node(&secret).is_none());
+    other.disconnect().unwrap();
+
+    client.disconnect().unwrap();
+    running.shutdown(WAIT).unwrap();
+}
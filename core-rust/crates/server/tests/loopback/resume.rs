@@ -0,0 +1,110 @@
+// A client whose connection is dropped resumes its session and only gets the changes it missed,
+// unless the session expired in the meantime.
+
+use std::{thread, time::Duration};
+
+use crossbeam::channel::{Sender, TryRecvError};
+use server::{Server, server_interface::ServerInterface};
+use shared::{
+    datatypes::{Data, nodes::Node},
+    events::EventSubscriber,
+};
+use uuid::Uuid;
+
+use crate::common::{Proxy, WAIT, builder, config, converged, eventually, serve};
+
+// Sends the new data of the node it is subscribed to. A snapshot replaces the mirror and with it
+// the subscriber.
+struct Changes(Sender<Data>);
+
+impl EventSubscriber for Changes {
+    fn handle_data_changed(&self, node: &Node, _previous_data: &Data) {
+        let _ = self.0.send(node.data.clone());
+    }
+}
+
+#[test]
+fn resume() {
+    let value = Uuid::from_u128(1);
+    let mut server = Server::from_config(config());
+    server
+        .add_child(Node::new().id(value).data(Data::Int32(0)))
+        .unwrap();
+    let (mut running, address) = serve(server);
+    let proxy = Proxy::new(&address);
+
+    let client = builder(&proxy.address).connect().unwrap();
+    let session = client.session();
+    let (changes_s, changes_r) = crossbeam::channel::unbounded();
+    client
+        .tree()
+        .unwrap()
+        .find_node_mut(&value)
+        .unwrap()
+        .subscribe(Changes(changes_s));
+
+    // The change is made while the client cannot reconnect.
+    proxy.block(true);
+    proxy.cut();
+    assert!(eventually(|| running.connections().current == 0));
+    running.change_data(&value, Data::Int32(1)).unwrap();
+    proxy.block(false);
+
+    assert!(matches!(changes_r.recv_timeout(WAIT), Ok(Data::Int32(1))));
+    assert_eq!(client.session(), session);
+    assert!(client.is_connected());
+    assert!(converged(&client, &running));
+
+    // Changes after the resume still reach the same mirror.
+    running.change_data(&value, Data::Int32(2)).unwrap();
+    assert!(matches!(changes_r.recv_timeout(WAIT), Ok(Data::Int32(2))));
+
+    client.disconnect().unwrap();
+    running.shutdown(WAIT).unwrap();
+}
+
+#[test]
+fn expired() {
+    let value = Uuid::from_u128(1);
+    let mut server = Server::from_config(config().session_validity(1));
+    server
+        .add_child(Node::new().id(value).data(Data::Int32(0)))
+        .unwrap();
+    let (mut running, address) = serve(server);
+    let proxy = Proxy::new(&address);
+
+    let client = builder(&proxy.address).connect().unwrap();
+    let session = client.session();
+    let (changes_s, changes_r) = crossbeam::channel::unbounded();
+    client
+        .tree()
+        .unwrap()
+        .find_node_mut(&value)
+        .unwrap()
+        .subscribe(Changes(changes_s));
+
+    // The client tries again after 0.2, 0.6, 1.4, 3 and 6.2 seconds, the session is valid for
+    // one second after the connection was lost.
+    proxy.block(true);
+    proxy.cut();
+    assert!(eventually(|| running.connections().current == 0));
+    running.change_data(&value, Data::Int32(1)).unwrap();
+    thread::sleep(Duration::from_millis(1500));
+    proxy.block(false);
+
+    // The server refused the session, so the client started a new one with a snapshot.
+    assert!(eventually(|| client.session() != session));
+    assert!(eventually(|| converged(&client, &running)));
+    assert!(client.is_connected());
+    assert!(matches!(
+        client.tree().unwrap().find_node(&value).unwrap().data,
+        Data::Int32(1)
+    ));
+    assert!(matches!(
+        changes_r.try_recv(),
+        Err(TryRecvError::Disconnected)
+    ));
+
+    client.disconnect().unwrap();
+    running.shutdown(WAIT).unwrap();
+}
@@ -0,0 +1,29 @@
+// Shutting down a server with a client that leaves on the goodbye and a connection that never
+// finishes the handshake.
+
+use std::{
+    net::{TcpListener, TcpStream},
+    time::Duration,
+};
+
+use server::Server;
+
+use crate::common::{config, connect, eventually, serve};
+
+#[test]
+fn shutdown() {
+    let (running, address) = serve(Server::from_config(config()));
+
+    let client = connect(&address);
+    // Does not even start the TLS handshake, so it never reads the goodbye.
+    let raw = TcpStream::connect(&address).unwrap();
+    assert!(eventually(|| running.connections().current == 2));
+
+    let report = running.shutdown(Duration::from_millis(500)).unwrap();
+    assert_eq!(report.closed, 1);
+    assert_eq!(report.timed_out, vec![raw.local_addr().unwrap()]);
+    assert!(eventually(|| !client.is_connected()));
+
+    // The port is free again.
+    assert!(TcpListener::bind(&address).is_ok());
+}
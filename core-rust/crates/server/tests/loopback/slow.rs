@@ -0,0 +1,78 @@
+// A client that does not read does not hold up the others on a server with tokio. It skips the
+// changes that do not fit into its queue and gets a snapshot once it reads again.
+
+use std::time::Duration;
+
+use crossbeam::channel::Sender;
+use server::{Server, server_interface::ServerInterface};
+use shared::{
+    datatypes::{Data, nodes::Node},
+    events::EventSubscriber,
+};
+use uuid::Uuid;
+
+use crate::common::{WAIT, config, connect, converged, eventually};
+
+// Counts the changes of the node it is subscribed to. A snapshot replaces the mirror and with it
+// the subscriber.
+struct Changes(Sender<()>);
+
+impl EventSubscriber for Changes {
+    fn handle_data_changed(&self, _node: &Node, _previous_data: &Data) {
+        let _ = self.0.send(());
+    }
+}
+
+#[test]
+fn slow_client() {
+    // Much more than the socket buffers hold.
+    const CHANGES: usize = 200;
+    const SIZE: usize = 64 * 1024;
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let value = Uuid::from_u128(1);
+    let config = config()
+        .queue_size(4)
+        .heartbeat_interval(Duration::from_secs(1))
+        .missed_heartbeats(60);
+    let mut server = Server::from_config(config);
+    server
+        .add_child(Node::new().id(value).data(Data::String(String::new())))
+        .unwrap();
+    let mut running = runtime.block_on(server.serve_async()).unwrap();
+    let address = running.address().unwrap().to_string();
+
+    let slow = connect(&address);
+    let fast = connect(&address);
+    let (changes_s, changes_r) = crossbeam::channel::unbounded();
+    slow.tree()
+        .unwrap()
+        .find_node_mut(&value)
+        .unwrap()
+        .subscribe(Changes(changes_s));
+
+    {
+        // The connection of the slow client waits for the tree and stops reading.
+        let _tree = slow.tree().unwrap();
+        for i in 0..CHANGES {
+            let data = Data::String(i.to_string().repeat(SIZE / i.to_string().len()));
+            running.change_data(&value, data).unwrap();
+        }
+        assert!(eventually(|| converged(&fast, &running)));
+        assert_eq!(running.connections().current, 2);
+    }
+
+    assert!(eventually(|| converged(&slow, &running)));
+    assert!(changes_r.try_iter().count() < CHANGES);
+
+    slow.disconnect().unwrap();
+    fast.disconnect().unwrap();
+    let report = runtime
+        .block_on(async { tokio::task::spawn_blocking(move || running.shutdown(WAIT)).await })
+        .unwrap()
+        .unwrap();
+    assert_eq!(report.closed, 0);
+}
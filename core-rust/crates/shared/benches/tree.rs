@@ -0,0 +1,139 @@
+// Looks up, adds, changes, inserts, removes and moves nodes on a tree with 100k nodes.
+// Run with `cargo bench -p shared`. The `scan` benchmark searches the tree the way every lookup
+// did before the index, to compare against.
+
+use std::hint::black_box;
+
+use criterion::{BatchSize, Criterion, criterion_group, criterion_main};
+use shared::datatypes::{
+    Data,
+    nodes::Node,
+    treebuilder::{TreeBuilder, TreeChange},
+};
+use uuid::Uuid;
+
+const FOLDERS: u128 = 100;
+const PER_FOLDER: u128 = 1000;
+
+// 100 folders with 1000 values each. The values have the ids 1..=100_000.
+fn tree() -> Node {
+    let mut root = Node::root();
+    for folder in 0..FOLDERS {
+        let values = (0..PER_FOLDER)
+            .map(|value| {
+                Node::new()
+                    .id(Uuid::from_u128(folder * PER_FOLDER + value + 1))
+                    .data(Data::UInt64(0))
+            })
+            .collect();
+        root.add_child(
+            Node::new()
+                .id(Uuid::from_u128(u128::MAX - folder))
+                .children(values),
+        );
+    }
+    root
+}
+
+// The last value is the worst case for a search.
+fn last() -> Uuid {
+    Uuid::from_u128(FOLDERS * PER_FOLDER)
+}
+
+fn scan<'a>(node: &'a Node, id: &Uuid) -> Option<&'a Node> {
+    if node.id == *id {
+        return Some(node);
+    }
+    node.children
+        .as_ref()?
+        .iter()
+        .find_map(|child| scan(child, id))
+}
+
+fn lookups(c: &mut Criterion) {
+    let mut root = tree();
+    let id = last();
+
+    c.bench_function("find_node 100k", |b| {
+        b.iter(|| black_box(root.find_node(black_box(&id)).is_some()))
+    });
+    c.bench_function("scan 100k", |b| {
+        b.iter(|| black_box(scan(&root, black_box(&id)).is_some()))
+    });
+    c.bench_function("find_node_mut 100k", |b| {
+        b.iter(|| black_box(root.find_node_mut(black_box(&id)).is_some()))
+    });
+}
+
+fn adds(c: &mut Criterion) {
+    // Adds the nodes one by one below their parents, like a client that replays the tree.
+    let changes = TreeBuilder::replay(&tree());
+    let mut group = c.benchmark_group("replay");
+    group.sample_size(10);
+    group.bench_function("add 100k", |b| {
+        b.iter_batched(
+            || changes.clone(),
+            |changes| {
+                let mut root = Node::root();
+                for change in changes {
+                    TreeBuilder::change(&mut root, change).unwrap();
+                }
+                root
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+fn changes(c: &mut Criterion) {
+    // Changes the data of every value once, which the handler does for each client view.
+    let mut root = tree();
+    let changes: Vec<TreeChange> = (1..=FOLDERS * PER_FOLDER)
+        .map(|id| TreeChange::NodeChangedData(Uuid::from_u128(id), Data::UInt64(1)))
+        .collect();
+    let mut group = c.benchmark_group("change");
+    group.sample_size(10);
+    group.bench_function("change data 100k times on 100k", |b| {
+        b.iter_batched(
+            || changes.clone(),
+            |changes| {
+                for change in changes {
+                    TreeBuilder::change(&mut root, change).unwrap();
+                }
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+fn fronts(c: &mut Criterion) {
+    // Inserting before all other children moves every sibling. Below the root these are the
+    // folders with everything below them, below a folder only values.
+    let mut root = tree();
+    let first = Uuid::from_u128(u128::MAX - FOLDERS);
+    let folder = Uuid::from_u128(u128::MAX);
+
+    for (name, parent) in [("root", Uuid::nil()), ("folder", folder)] {
+        c.bench_function(&format!("insert and remove first below {name} 100k"), |b| {
+            b.iter(|| {
+                root.find_node_mut(&parent)
+                    .unwrap()
+                    .insert_child_at(0, Node::new().id(first))
+                    .unwrap();
+                black_box(root.remove_child(black_box(&first)).is_some())
+            })
+        });
+    }
+    c.bench_function("move first folder to the end 100k", |b| {
+        b.iter(|| {
+            let moved = root.children.as_ref().unwrap()[0].id;
+            root.move_node(black_box(&moved), &Uuid::nil(), FOLDERS as usize - 1)
+                .unwrap()
+        })
+    });
+}
+
+criterion_group!(benches, lookups, adds, changes, fronts);
+criterion_main!(benches);
@@ -1,10 +1,53 @@
-pub fn get_ip() -> &'static str {
-    return "127.0.0.1:9123";
-}
+use std::time::Duration;
 
-pub const CURRENT_VERSION: u8 = 1;
+// The highest version of the protocol this crate speaks, see [crate::remote::version].
+pub const CURRENT_VERSION: u8 = 5;
+
+// The lowest version of the protocol this crate still speaks.
+pub const MIN_VERSION: u8 = 1;
 
 // The size of the RSA KEYS to use.
 pub const RSA_KEY_SIZE: usize = 2048;
 
 pub const MAX_CLIENTS: u16 = 32;
+
+// How many clients that find the server full are told so at the same time. Telling one takes a
+// TLS handshake, so the connections of further ones are closed right away.
+pub const MAX_REJECTIONS: u16 = 4;
+
+// The maximum size of a single frame on the wire, without the length prefix.
+pub const MAX_FRAME_SIZE: usize = 1 << 20;
+
+// How deep [crate::datatypes::Data::Tuple] and [crate::datatypes::Data::List] may be nested in a
+// frame.
+pub const MAX_DATA_DEPTH: usize = 32;
+
+// How long a read on a connection blocks before the connection checks for messages to send.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// How long a client and the server may take for each step of the handshake.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long a session is valid in seconds. Is sent with [crate::remote::message::Message::ServerAccept].
+pub const SESSION_VALIDITY: u16 = 3600;
+
+// How many random bytes the server encrypts for a client to prove it owns its key.
+pub const NONCE_SIZE: usize = 32;
+
+// How many changes the server remembers per client to resend the ones a client missed.
+pub const HASH_HISTORY: usize = 256;
+
+// How often each side of a connection pings the other, see [crate::remote::heartbeat].
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+// After how many heartbeat intervals without anything from the other side a connection is
+// considered dead.
+pub const MISSED_HEARTBEATS: u32 = 3;
+
+// How often a client tries to connect again after it lost its connection. It waits
+// RECONNECT_DELAY before the first try and twice as long before each next one.
+pub const RECONNECT_ATTEMPTS: u32 = 5;
+pub const RECONNECT_DELAY: Duration = Duration::from_millis(200);
+
+// How many messages can wait for a client before it skips changes and gets a snapshot instead.
+pub const CLIENT_QUEUE_SIZE: usize = 1024;
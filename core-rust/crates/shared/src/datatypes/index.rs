@@ -0,0 +1,179 @@
+// Finds nodes by their id without searching the whole tree.
+// All nodes of a tree share one index. It maps every id to the id of the parent of the node and
+// the position of the node among the children of its parent. [Node::find_node] walks up the
+// parents to the node it starts from and then follows the positions back down, so a lookup takes
+// as many steps as the node is deep. Adding or removing a child only moves the siblings after it,
+// everything below them keeps its entry. [Node::add_child], [Node::remove_child], [Node::move_node]
+// and [Node::children] keep the index up to date.
+//
+// [Node::add_child]: crate::datatypes::nodes::Node::add_child
+// [Node::remove_child]: crate::datatypes::nodes::Node::remove_child
+// [Node::move_node]: crate::datatypes::nodes::Node::move_node
+// [Node::children]: crate::datatypes::nodes::Node::children
+// [Node::find_node]: crate::datatypes::nodes::Node::find_node
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use uuid::Uuid;
+
+/// Where a node is in its tree. The root of the tree has no parent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Location {
+    pub parent: Option<Uuid>,
+    pub position: usize,
+}
+
+impl Location {
+    /// The location of the root of a tree.
+    pub const ROOT: Location = Location {
+        parent: None,
+        position: 0,
+    };
+
+    /// The child of the node with [parent] at [position].
+    pub fn child(parent: Uuid, position: usize) -> Self {
+        Self {
+            parent: Some(parent),
+            position,
+        }
+    }
+}
+
+/// Maps the id of every node of a tree to its location.
+pub(crate) type Locations = HashMap<Uuid, Location>;
+
+/// The index shared by all nodes of a tree. Cloning it shares it.
+#[derive(Clone, Default)]
+pub(crate) struct Index(Arc<Mutex<Locations>>);
+
+impl Index {
+    /// The index of a tree that only has the node with [id].
+    pub fn new(id: Uuid) -> Self {
+        Self(Arc::new(Mutex::new(HashMap::from([(id, Location::ROOT)]))))
+    }
+
+    /// Locks the index. Must not be held while subscribers are called, they might look up nodes.
+    pub fn lock(&self) -> MutexGuard<'_, Locations> {
+        // The locations are only changed in one step, a panic cannot leave them half done.
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Checks if both are the index of the same tree.
+    pub fn is(&self, other: &Index) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+
+    /// Returns the positions that lead from the node with [from] to the node with [to].
+    /// Returns [None] if [to] is not below [from].
+    pub fn between(&self, from: &Uuid, to: &Uuid) -> Option<Vec<usize>> {
+        let locations = self.lock();
+        locations.get(from)?;
+        let mut positions = vec![];
+        let mut current = *to;
+        while current != *from {
+            // Entries of nodes that were changed directly might even form a loop.
+            if positions.len() >= locations.len() {
+                return None;
+            }
+            let location = locations.get(&current)?;
+            positions.push(location.position);
+            current = location.parent?;
+        }
+        positions.reverse();
+        Some(positions)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use uuid::Uuid;
+
+    use crate::datatypes::{
+        index::{Index, Location},
+        nodes::Node,
+        snapshot::Snapshot,
+        treebuilder::TreeBuilder,
+    };
+
+    #[test]
+    fn between() {
+        let id = Uuid::from_u128;
+        let index = Index::new(id(0));
+        index.lock().insert(id(1), Location::child(id(0), 2));
+        index.lock().insert(id(5), Location::child(id(1), 0));
+        index.lock().insert(id(2), Location::child(id(5), 1));
+        index.lock().insert(id(3), Location::child(id(0), 1));
+
+        let between = |from, to| index.between(&Uuid::from_u128(from), &Uuid::from_u128(to));
+        assert_eq!(between(0, 2), Some(vec![2, 0, 1]));
+        assert_eq!(between(1, 2), Some(vec![0, 1]));
+        assert_eq!(between(2, 2), Some(vec![]));
+        assert_eq!(between(3, 2), None);
+        assert_eq!(between(0, 4), None);
+
+        // Entries that point at each other end the search.
+        index.lock().insert(id(6), Location::child(id(7), 0));
+        index.lock().insert(id(7), Location::child(id(6), 0));
+        assert_eq!(between(0, 6), None);
+    }
+
+    #[test]
+    fn tree() {
+        let id = Uuid::from_u128;
+        let mut root = Node::root().children(vec![
+            Node::new().id(id(1)),
+            Node::new()
+                .id(id(2))
+                .children(vec![Node::new().id(id(3)), Node::new().id(id(4))]),
+        ]);
+        root.find_node_mut(&id(3))
+            .unwrap()
+            .add_child(Node::new().id(id(5)).children(vec![Node::new().id(id(6))]));
+        assert_eq!(root.find_node(&id(6)).unwrap().parent_id, Some(id(5)));
+        assert!(root.find_node(&id(2)).unwrap().find_node(&id(6)).is_some());
+        assert!(root.find_node(&id(1)).unwrap().find_node(&id(6)).is_none());
+
+        // The siblings after a removed node move forward.
+        assert!(root.remove_child(&id(1)).is_some());
+        assert!(root.find_node(&id(1)).is_none());
+        for n in 2..=6 {
+            assert_eq!(root.find_node(&id(n)).unwrap().id, id(n));
+        }
+
+        // Moved subtrees and the siblings they leave and join can still be found.
+        root.move_node(&id(5), &id(2), 0).unwrap();
+        root.find_node_mut(&id(2))
+            .unwrap()
+            .insert_child_at(0, Node::new().id(id(8)))
+            .unwrap();
+        for (n, parent) in [(8, 2), (5, 2), (6, 5), (3, 2), (4, 2)] {
+            assert_eq!(root.find_node(&id(n)).unwrap().parent_id, Some(id(parent)));
+        }
+        root.move_node(&id(5), &id(3), 0).unwrap();
+        assert!(root.remove_child(&id(8)).is_some());
+        assert_eq!(root.find_node(&id(6)).unwrap().parent_id, Some(id(5)));
+        assert_eq!(root.path_of(&id(6)), Some("/[0]/[0]/[0]/[0]".to_string()));
+
+        // Copies have their own index.
+        let mut copy = root.clone();
+        copy.find_node_mut(&id(2)).unwrap().remove_child(&id(3));
+        assert!(copy.find_node(&id(6)).is_none());
+        assert!(root.find_node(&id(6)).is_some());
+        let mirror = Node::from_snapshot(&Snapshot::new(&root)).unwrap();
+        assert_eq!(mirror.find_node(&id(6)).unwrap().id, id(6));
+        assert_eq!(TreeBuilder::replay(&mirror).len(), 5);
+
+        // Direct changes need a new index.
+        if let Some(children) = &mut root.children {
+            children.insert(0, Node::new().id(id(7)));
+        }
+        assert!(root.find_node(&id(7)).is_none());
+        assert!(root.find_node(&id(2)).is_none());
+        root.reindex();
+        assert!(root.find_node(&id(7)).is_some());
+        assert!(root.find_node(&id(6)).is_some());
+    }
+}
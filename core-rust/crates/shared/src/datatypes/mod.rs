@@ -2,7 +2,11 @@ use std::{fmt::Display, hash::Hash};
 
 use serde::{Deserialize, Serialize};
 
+mod index;
 pub mod nodes;
+pub mod path;
+pub mod snapshot;
+pub mod traversal;
 pub mod treebuilder;
 /// All possible Datatypes
 #[derive(Clone, Serialize, Deserialize, Debug)]
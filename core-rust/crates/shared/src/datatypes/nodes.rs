@@ -1,14 +1,29 @@
 use std::{
+    collections::HashMap,
     fmt::Display,
     hash::{DefaultHasher, Hash, Hasher},
 };
 
 use crate::{
-    datatypes::Data, errors::Error, events::EventSubscriber, security::permissions::Permissions,
+    datatypes::{
+        Data,
+        index::{Index, Location, Locations},
+        path::{self, Segment},
+        snapshot::Snapshot,
+        traversal::{Ancestors, BreadthFirst, DepthFirst, DepthFirstMut, Walk},
+        treebuilder::TreeChange,
+    },
+    errors::Error,
+    events::EventSubscriber,
+    security::permissions::Permissions,
 };
 use uuid::Uuid;
 
 /// The base type for a node.
+///
+/// Nodes are found by their id through an index shared by the whole tree. Changing [Node::id] or
+/// [Node::children] directly instead of through the methods of the node leaves the index
+/// behind, call [Node::reindex] afterwards.
 pub struct Node {
     pub data: Data,
 
@@ -18,35 +33,45 @@ pub struct Node {
     pub parent_id: Option<Uuid>,
     pub permissions: Permissions,
 
-    subscribers: Option<Vec<Box<dyn EventSubscriber>>>,
+    subscribers: Option<Vec<Box<dyn EventSubscriber + Send>>>,
+    index: Index,
 }
 
 impl Clone for Node {
+    /// The copy is a tree of its own with its own index.
     fn clone(&self) -> Self {
-        Self {
-            data: self.data.clone(),
-            name: self.name.clone(),
-            id: self.id.clone(),
-            children: self.children.clone(),
-            parent_id: self.parent_id.clone(),
-            permissions: self.permissions.clone(),
+        let index = Index::default();
+        let mut locations = index.lock();
+        self.copy(None, &index, &mut locations, Location::ROOT)
+    }
+}
 
-            subscribers: None, // Cloning does not take the subscribers, as they should be local to
-                               // the threads
-        }
+// Subscribers are left out, closures cannot be printed.
+impl std::fmt::Debug for Node {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Node")
+            .field("data", &self.data)
+            .field("name", &self.name)
+            .field("id", &self.id)
+            .field("children", &self.children)
+            .field("parent_id", &self.parent_id)
+            .field("permissions", &self.permissions)
+            .finish_non_exhaustive()
     }
 }
 
 impl Default for Node {
     fn default() -> Self {
+        let id = Uuid::new_v4();
         Self {
             data: Data::Folder,
             children: None,
             name: None,
-            id: Uuid::new_v4(),
+            id,
             parent_id: None,
             permissions: Permissions::Public,
             subscribers: None,
+            index: Index::new(id),
         }
     }
 }
@@ -57,6 +82,57 @@ impl Node {
         Node::default()
     }
 
+    /// Creates the root of a tree. The root always has the nil id, so that a client can mirror
+    /// the tree of a server without knowing its id beforehand.
+    pub fn root() -> Self {
+        Node::default().id(Uuid::nil())
+    }
+
+    /// Rebuilds the tree of a [Snapshot].
+    /// Returns an error if the nodes do not form a tree or the tree does not have the hash of
+    /// the snapshot.
+    pub fn from_snapshot(snapshot: &Snapshot) -> Result<Self, Error> {
+        let mut root = None;
+        let mut children: HashMap<Uuid, Vec<Node>> = HashMap::new();
+
+        // Backwards, so that all children of a node are built before the node itself.
+        for record in snapshot.nodes.iter().rev() {
+            let mut node = Node::new()
+                .id(record.id)
+                .data(record.data.clone())
+                .permissions(record.permissions.clone());
+            node.name = record.name.clone();
+            node.parent_id = record.parent_id;
+            if let Some(mut c) = children.remove(&record.id) {
+                c.reverse();
+                node.children = Some(c);
+            }
+
+            match record.parent_id {
+                Some(parent) => children.entry(parent).or_default().push(node),
+                None if root.is_none() => root = Some(node),
+                None => return Err(Error::SimpleError("Snapshot: More than one root")),
+            }
+        }
+
+        let mut root = match root {
+            Some(root) => root,
+            None => return Err(Error::SimpleError("Snapshot: No root")),
+        };
+        if let Some(parent) = children.keys().next() {
+            return Err(Error::SimpleErrorStr(format!(
+                "Snapshot: Cannot find node with id={:?}",
+                parent
+            )));
+        }
+        if root.get_hash() != snapshot.hash {
+            return Err(Error::SimpleError("Snapshot: Hash does not match"));
+        }
+        // The children were set directly, so that the index is only built once.
+        root.reindex();
+        Ok(root)
+    }
+
     // Sets the Display name of the node.
     pub fn name(mut self, name: impl Display) -> Self {
         self.name = Some(name.to_string());
@@ -71,8 +147,16 @@ impl Node {
 
     /// Sets all children.
     pub fn children(mut self, mut children: Vec<Node>) -> Self {
-        for c in children.iter_mut() {
-            c.parent_id = Some(self.id.clone());
+        let index = self.index.clone();
+        let mut locations = index.lock();
+        if let Some(old) = &self.children {
+            for c in old {
+                c.forget(&mut locations);
+            }
+        }
+        for (position, c) in children.iter_mut().enumerate() {
+            c.parent_id = Some(self.id);
+            c.attach(&index, &mut locations, Location::child(self.id, position));
         }
         self.children = Some(children);
         self
@@ -92,7 +176,18 @@ impl Node {
     /// This is only needed when reconstruing a tree.
     /// Normally, a new id is generated when adding a node.
     pub fn id(mut self, id: Uuid) -> Self {
+        let index = self.index.clone();
+        let mut locations = index.lock();
+        if let Some(location) = locations.remove(&self.id) {
+            locations.insert(id, location);
+        }
         self.id = id;
+        if let Some(children) = &mut self.children {
+            for (position, c) in children.iter_mut().enumerate() {
+                c.parent_id = Some(id);
+                locations.insert(c.id, Location::child(id, position));
+            }
+        }
         self
     }
 
@@ -118,7 +213,7 @@ impl Node {
 
         if let Some(subs) = &self.subscribers {
             for s in subs {
-                s.handle_name_changed(&self, &old_name);
+                s.handle_name_changed(self, &old_name);
             }
         }
     }
@@ -130,106 +225,299 @@ impl Node {
     }
 
     /// Adds a single new node to the children list.
-    pub fn add_child(&mut self, mut node: Node) -> &mut Self {
+    pub fn add_child(&mut self, node: Node) -> &mut Self {
         // trigger the event.
         self.trigger_child_added(&node);
-        node.parent_id = Some(self.id.clone());
+        self.insert(self.get_children_count(), node);
+        self
+    }
 
-        // Add the node to the children list.
-        if let Some(c) = &mut self.children {
-            c.push(node);
-        } else {
-            self.children = Some(vec![node]);
+    /// Adds a single new node to the children list at [position], the children from
+    /// [position] on move one back.
+    /// Returns an error if there are less than [position] children.
+    pub fn insert_child_at(&mut self, position: usize, node: Node) -> Result<&mut Self, Error> {
+        if position > self.get_children_count() {
+            return Err(Error::SimpleErrorStr(format!(
+                "Insert: Position {position} is out of range"
+            )));
         }
+        self.trigger_child_added(&node);
+        self.insert(position, node);
+        Ok(self)
+    }
 
-        self
+    /// Moves the node with [id] and everything below it to [new_parent], where it is the child
+    /// at [position] afterwards. Both have to be below this node. The node keeps its subscribers.
+    /// Returns an error if a node cannot be found, [position] is out of range or [new_parent] is
+    /// the node itself or below it. Nothing is changed then.
+    ///
+    /// Triggers the [NodeMoved] event.
+    pub fn move_node(
+        &mut self,
+        id: &Uuid,
+        new_parent: &Uuid,
+        position: usize,
+    ) -> Result<(), Error> {
+        let node = match self.find_node(id) {
+            Some(node) if *id != self.id => node,
+            Some(_) => return Err(Error::SimpleError("Move: The root cannot be moved")),
+            None => {
+                return Err(Error::SimpleErrorStr(format!(
+                    "Move: Cannot find node with id={:?}",
+                    id
+                )));
+            }
+        };
+        if node.find_node(new_parent).is_some() {
+            return Err(Error::SimpleErrorStr(format!(
+                "Move: Cannot move node with id={:?} below itself",
+                id
+            )));
+        }
+        let stays = node.parent_id == Some(*new_parent);
+        let parent = match self.find_node(new_parent) {
+            Some(parent) => parent,
+            None => {
+                return Err(Error::SimpleErrorStr(format!(
+                    "Move: Cannot find node with id={:?}",
+                    new_parent
+                )));
+            }
+        };
+        // Within the same parent the node does not count itself.
+        let count = parent.get_children_count() - usize::from(stays);
+        if position > count {
+            return Err(Error::SimpleErrorStr(format!(
+                "Move: Position {position} is out of range"
+            )));
+        }
+
+        let (node, previous_parent) = match self.take(id) {
+            Some(taken) => taken,
+            None => return Err(Error::SimpleError("Move: Cannot take the node")),
+        };
+        match self.find_node_mut(new_parent) {
+            Some(parent) => parent.insert(position, node),
+            None => return Err(Error::SimpleError("Move: The new parent is gone")),
+        }
+        if let Some(node) = self.find_node(id) {
+            node.trigger_moved(&previous_parent);
+        }
+        Ok(())
     }
 
     /// Get the child by index.
     /// Returns a mutable reference to the child if present.
     /// Otherwise returns an Error
     pub fn get_child(&mut self, index: usize) -> Result<&mut Node, Error> {
-        if let Some(c) = &mut self.children {
-            if let Some(child) = c.get_mut(index) {
-                return Ok(child);
-            }
+        if let Some(c) = &mut self.children
+            && let Some(child) = c.get_mut(index)
+        {
+            return Ok(child);
         }
         Err(Error::SimpleError("This child does not exist"))
     }
 
-    /// Traverses the tree to find a node with the given id.
+    /// Finds the node with the given id below this node through the index of the tree.
     /// Returns mutable ref
     pub fn find_node_mut(&mut self, id: &Uuid) -> Option<&mut Node> {
-        if self.id == *id {
-            return Some(self);
+        let mut node = self;
+        for position in node.index.between(&node.id, id)? {
+            node = node.children.as_mut()?.get_mut(position)?;
         }
+        // Entries of nodes that were changed directly might be outdated.
+        if node.id == *id { Some(node) } else { None }
+    }
 
-        if let Some(children) = &mut self.children {
-            for child in children {
-                if let Some(node) = child.find_node_mut(id) {
-                    return Some(node);
-                }
-            }
+    /// Finds the node with the given id below this node through the index of the tree.
+    /// Returns immutable ref
+    pub fn find_node(&self, id: &Uuid) -> Option<&Node> {
+        let mut node = self;
+        for position in self.index.between(&self.id, id)? {
+            node = node.children.as_ref()?.get(position)?;
         }
+        if node.id == *id { Some(node) } else { None }
+    }
 
-        None
+    /// Rebuilds the index of the tree below this node.
+    /// Only needed after [Node::id] or [Node::children] were changed directly.
+    pub fn reindex(&mut self) {
+        let index = self.index.clone();
+        let mut locations = index.lock();
+        let location = locations.get(&self.id).copied().unwrap_or(Location::ROOT);
+        self.attach(&index, &mut locations, location);
     }
 
-    /// Traverses the tree to find a node with the given id.
-    /// Returns immutable ref
-    pub fn find_node(&self, id: &Uuid) -> Option<&Node> {
-        if self.id == *id {
-            return Some(self);
+    /// Finds the node at [path] below this node. See [crate::datatypes::path] for the syntax.
+    /// Returns an error if the path is invalid or there is no node at it.
+    pub fn find_by_path(&self, path: &str) -> Result<&Node, Error> {
+        let mut node = self;
+        for segment in path::parse(path)? {
+            node = match node.child_by_segment(&segment) {
+                Some(child) => child,
+                None => return Err(not_found(path)),
+            };
         }
+        Ok(node)
+    }
 
-        if let Some(children) = &self.children {
-            for child in children {
-                if let Some(node) = child.find_node(id) {
-                    return Some(node);
-                }
-            }
+    /// Finds the node at [path] below this node. See [Node::find_by_path].
+    /// Returns mutable ref
+    pub fn find_by_path_mut(&mut self, path: &str) -> Result<&mut Node, Error> {
+        let mut node = self;
+        for segment in path::parse(path)? {
+            node = match node.child_by_segment_mut(&segment) {
+                Some(child) => child,
+                None => return Err(not_found(path)),
+            };
         }
+        Ok(node)
+    }
 
-        None
+    /// Returns the path of the node with [id] below this node, which [Node::find_by_path] turns
+    /// back into the node. This node itself is `/`.
+    pub fn path_of(&self, id: &Uuid) -> Option<String> {
+        let mut node = self;
+        let mut segments = vec![];
+        for position in self.index.between(&self.id, id)? {
+            let children = node.children.as_ref()?;
+            node = children.get(position)?;
+            // Siblings before this one with the same name.
+            let mut segment = Segment::new(node.name.as_deref().unwrap_or(""), 0);
+            segment.index = children[..position]
+                .iter()
+                .filter(|sibling| segment.matches(sibling))
+                .count();
+            segments.push(segment);
+        }
+        if node.id == *id {
+            Some(path::format(&segments))
+        } else {
+            None
+        }
     }
 
-    /// Deletes the node with id in the tree.
-    /// Returns [true] if this was successfull, [false] otherwise.
+    /// Creates the folders of [path] that do not exist yet, like `mkdir -p`.
+    /// Returns the id of the node at [path] and the changes that added the folders, parents
+    /// before their children.
     ///
-    /// It is impossible to delete the root.
-    pub fn remove_child(&mut self, id: &Uuid) -> bool {
-        if let Some(children) = &mut self.children {
-            let old_size = children.len();
-            children.retain(|elem| {
-                if elem.id == *id {
-                    // Found => Should be deleted
-                    //
-                    // trigger deleted event.
-                    elem.trigger_deleted();
-                    false
-                } else {
-                    false
+    /// Unnamed folders cannot be created, and neither can `name[n]` if there are less than `n`
+    /// children with that name.
+    pub fn create_path(&mut self, path: &str) -> Result<(Uuid, Vec<TreeChange>), Error> {
+        let mut node = self;
+        let mut changes = vec![];
+        for segment in path::parse(path)? {
+            if node.child_by_segment(&segment).is_none() {
+                let siblings = match &node.children {
+                    Some(children) => children.iter().filter(|c| segment.matches(c)).count(),
+                    None => 0,
+                };
+                if segment.name.is_empty() || segment.index != siblings {
+                    return Err(Error::SimpleErrorStr(format!(
+                        "Path: Cannot create {segment} of {path}"
+                    )));
                 }
-            });
-
-            // difference should never be greater then one as Uuid ids are unique.
-            if old_size != children.len() {
-                return true;
+                let folder = Node::new().name(&segment.name);
+                changes.push(TreeChange::NodeAdded(
+                    Data::Folder,
+                    folder.name.clone(),
+                    folder.id,
+                    node.id,
+                ));
+                node.add_child(folder);
             }
+            node = match node.child_by_segment_mut(&segment) {
+                Some(child) => child,
+                None => return Err(not_found(path)),
+            };
         }
-        false
+        Ok((node.id, changes))
+    }
+
+    /// Deletes the node with id anywhere below this node, together with everything below it.
+    /// Returns the removed subtree, which is a tree of its own afterwards, or [None] if there is
+    /// no such node below this node.
+    ///
+    /// It is impossible to delete this node itself, so the root can never be deleted.
+    ///
+    /// Triggers the [ChildRemoved] event for every removed node.
+    pub fn remove_child(&mut self, id: &Uuid) -> Option<Node> {
+        let (mut removed, _) = self.take(id)?;
+        removed.forget(&mut self.index.lock());
+        removed.parent_id = None;
+        removed.index = Index::new(removed.id);
+        removed.reindex();
+        removed.trigger_deleted();
+        Some(removed)
     }
 
     /// Returns the amount of children this node has.
     /// If no children are present returns 0.
     pub fn get_children_count(&self) -> usize {
         if let Some(c) = &self.children {
-            return c.len();
+            c.len()
         } else {
-            return 0;
+            0
+        }
+    }
+
+    /// Goes through this node and everything below it, depth first. Parents come before their
+    /// children.
+    pub fn iter(&self) -> DepthFirst<'_> {
+        DepthFirst::new(self)
+    }
+
+    /// Goes through this node and everything below it, depth first.
+    /// Only the fields of the nodes can be changed, see [crate::datatypes::traversal::NodeMut].
+    pub fn iter_mut(&mut self) -> DepthFirstMut<'_> {
+        DepthFirstMut::new(self)
+    }
+
+    /// Goes through this node and everything below it level by level.
+    pub fn breadth_first(&self) -> BreadthFirst<'_> {
+        BreadthFirst::new(self)
+    }
+
+    /// Goes through everything below this node, depth first, without this node itself.
+    pub fn descendants(&self) -> std::iter::Skip<DepthFirst<'_>> {
+        self.iter().skip(1)
+    }
+
+    /// Goes through the nodes on the way from this node down to the node with [id], starting with
+    /// this node and ending with the parent of the node with [id].
+    /// Yields nothing if there is no such node below this node.
+    pub fn ancestors(&self, id: &Uuid) -> Ancestors<'_> {
+        let positions = match self.find_node(id) {
+            Some(_) => self.index.between(&self.id, id).unwrap_or_default(),
+            None => vec![],
+        };
+        Ancestors::new(self, positions)
+    }
+
+    /// Calls [visitor] with this node and everything below it, depth first, together with the
+    /// depth of the node below this node. The [Walk] the visitor returns decides if the
+    /// children of the node are visited.
+    pub fn walk(&self, mut visitor: impl FnMut(&Node, usize) -> Walk) {
+        let mut stack = vec![(self, 0)];
+        while let Some((node, depth)) = stack.pop() {
+            match visitor(node, depth) {
+                Walk::Continue => {}
+                Walk::Skip => continue,
+                Walk::Stop => return,
+            }
+            if let Some(children) = &node.children {
+                stack.extend(children.iter().rev().map(|child| (child, depth + 1)));
+            }
         }
     }
 
+    /// Like [Node::walk], but the visitor can change the nodes through their methods, which
+    /// triggers the events as usual.
+    /// Children the visitor adds to a node are visited as well.
+    pub fn walk_mut(&mut self, mut visitor: impl FnMut(&mut Node, usize) -> Walk) {
+        self.walk_mut_at(&mut visitor, 0);
+    }
+
     /// Subscribe to this node might emit.
     ///
     /// Possible Events:
@@ -237,6 +525,7 @@ impl Node {
     /// - [ChildAdded]
     /// - [ChildRemoved]
     /// - [NameChanged]
+    /// - [NodeMoved]
     /// - [ButtonPressed]
     ///
     /// # Example:
@@ -252,7 +541,7 @@ impl Node {
     /// }
     /// ));
     /// ```
-    pub fn subscribe(&mut self, subscriber: impl EventSubscriber + 'static) {
+    pub fn subscribe(&mut self, subscriber: impl EventSubscriber + Send + 'static) {
         if let Some(s) = &mut self.subscribers {
             s.push(Box::new(subscriber));
         } else {
@@ -265,7 +554,10 @@ impl Node {
     /// Possible Events:
     /// - [DataChanged]
     /// - [ChildAdded]
-    pub fn subscribe_to_children(&mut self, subscriber: impl EventSubscriber + 'static + Clone) {
+    pub fn subscribe_to_children(
+        &mut self,
+        subscriber: impl EventSubscriber + Send + 'static + Clone,
+    ) {
         if let Some(ch) = &mut self.children {
             for child in ch {
                 child.subscribe_to_children(subscriber.clone());
@@ -279,7 +571,7 @@ impl Node {
     /// - id
     /// - name
     /// - data
-    /// - children
+    /// - children and their amount
     pub fn get_hash(&self) -> u64 {
         let mut hasher = DefaultHasher::new();
         self.hash(&mut hasher);
@@ -309,26 +601,182 @@ impl Node {
         self.permissions.can_be_accessed(permissions)
     }
 
+    /// Copies the part of the tree a user with the [permissions] can access.
+    /// Returns [None] if the user cannot access this node.
+    ///
+    /// Permissions are transitive, so everything below a node that cannot be accessed is left
+    /// out, even if it could be accessed on its own.
+    pub fn filter(&self, permissions: &Permissions) -> Option<Node> {
+        if !self.can_acces(permissions) {
+            return None;
+        }
+
+        let index = Index::default();
+        let mut locations = index.lock();
+        Some(self.copy(Some(permissions), &index, &mut locations, Location::ROOT))
+    }
+
+    // Copies the tree below this node into [index] at [location]. Children a user with
+    // [permissions] cannot access are left out, without [permissions] everything is copied.
+    // Subscribers are not copied, as they should be local to the threads.
+    fn copy(
+        &self,
+        permissions: Option<&Permissions>,
+        index: &Index,
+        locations: &mut Locations,
+        location: Location,
+    ) -> Node {
+        locations.insert(self.id, location);
+        let children = self.children.as_ref().map(|children| {
+            let mut copies = vec![];
+            for child in children {
+                if permissions.is_some_and(|permissions| !child.can_acces(permissions)) {
+                    continue;
+                }
+                let location = Location::child(self.id, copies.len());
+                copies.push(child.copy(permissions, index, locations, location));
+            }
+            copies
+        });
+
+        Node {
+            data: self.data.clone(),
+            name: self.name.clone(),
+            id: self.id,
+            children,
+            parent_id: self.parent_id,
+            permissions: self.permissions.clone(),
+            subscribers: None,
+            index: index.clone(),
+        }
+    }
+
+    // Inserts [node] into the children at [position] and into the index of this tree.
+    fn insert(&mut self, position: usize, mut node: Node) {
+        node.parent_id = Some(self.id);
+        let index = self.index.clone();
+        let mut locations = index.lock();
+        let location = Location::child(self.id, position);
+        // Everything below a node taken out of this tree is still in the index.
+        if node.index.is(&index) {
+            locations.insert(node.id, location);
+        } else {
+            node.attach(&index, &mut locations, location);
+        }
+
+        let children = self.children.get_or_insert_with(Vec::new);
+        children.insert(position, node);
+        // Only the siblings after it move, the nodes below them stay where they are.
+        for (position, sibling) in children.iter().enumerate().skip(position + 1) {
+            locations.insert(sibling.id, Location::child(self.id, position));
+        }
+    }
+
+    // Takes the node with [id] out of the tree below this node, without triggering any event.
+    // Returns the node, which still has its old parent id, and the id of its parent. Everything
+    // below the node stays in the index, [Node::forget] it if it does not come back.
+    // This node itself has no position below itself, so it cannot be taken.
+    fn take(&mut self, id: &Uuid) -> Option<(Node, Uuid)> {
+        let mut path = self.index.between(&self.id, id)?;
+        let position = path.pop()?;
+        let mut parent = self;
+        for p in path {
+            parent = parent.children.as_mut()?.get_mut(p)?;
+        }
+        let children = parent.children.as_mut()?;
+        if children.get(position)?.id != *id {
+            return None;
+        }
+        let taken = children.remove(position);
+
+        // The siblings after the taken node moved one position forward.
+        let mut locations = parent.index.lock();
+        for (position, sibling) in children.iter().enumerate().skip(position) {
+            locations.insert(sibling.id, Location::child(parent.id, position));
+        }
+        Some((taken, parent.id))
+    }
+
+    // Makes this node and everything below it part of [index] at [location].
+    fn attach(&mut self, index: &Index, locations: &mut Locations, location: Location) {
+        self.index = index.clone();
+        locations.insert(self.id, location);
+        if let Some(children) = &mut self.children {
+            for (position, child) in children.iter_mut().enumerate() {
+                child.attach(index, locations, Location::child(self.id, position));
+            }
+        }
+    }
+
+    // Removes this node and everything below it from [locations].
+    fn forget(&self, locations: &mut Locations) {
+        for node in self.iter() {
+            locations.remove(&node.id);
+        }
+    }
+
+    // Walks below this node, returns false once the walk stops.
+    fn walk_mut_at(
+        &mut self,
+        visitor: &mut impl FnMut(&mut Node, usize) -> Walk,
+        depth: usize,
+    ) -> bool {
+        match visitor(self, depth) {
+            Walk::Continue => {}
+            Walk::Skip => return true,
+            Walk::Stop => return false,
+        }
+        if let Some(children) = &mut self.children {
+            for child in children {
+                if !child.walk_mut_at(visitor, depth + 1) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    // The child a single segment of a path points to.
+    fn child_by_segment(&self, segment: &Segment) -> Option<&Node> {
+        self.children
+            .as_ref()?
+            .iter()
+            .filter(|child| segment.matches(child))
+            .nth(segment.index)
+    }
+
+    fn child_by_segment_mut(&mut self, segment: &Segment) -> Option<&mut Node> {
+        self.children
+            .as_mut()?
+            .iter_mut()
+            .filter(|child| segment.matches(child))
+            .nth(segment.index)
+    }
+
     // Used internally to trigger the deletion event.
     fn trigger_deleted(&self) {
-        // First trigger self
-        if let Some(subs) = &self.subscribers {
-            for s in subs {
-                s.handle_child_removed(&self);
+        // Parents first, then their children.
+        for node in self.iter() {
+            if let Some(subs) = &node.subscribers {
+                for s in subs {
+                    s.handle_child_removed(node);
+                }
             }
         }
-        // then trigger children
-        if let Some(children) = &self.children {
-            for c in children {
-                c.trigger_deleted();
+    }
+
+    fn trigger_child_added(&self, node: &Node) {
+        if let Some(subs) = &self.subscribers {
+            for s in subs {
+                s.handle_child_added(self, node);
             }
         }
     }
 
-    fn trigger_child_added(&self, node: &Node) {
+    fn trigger_moved(&self, previous_parent: &Uuid) {
         if let Some(subs) = &self.subscribers {
             for s in subs {
-                s.handle_child_added(&self, node);
+                s.handle_node_moved(self, previous_parent);
             }
         }
     }
@@ -336,7 +784,7 @@ impl Node {
     fn trigger_permissions_changed(&self) {
         if let Some(subs) = &self.subscribers {
             for s in subs {
-                s.handle_permissions_changed(&self);
+                s.handle_permissions_changed(self);
             }
         }
     }
@@ -345,27 +793,30 @@ impl Node {
         if let Some(subs) = &self.subscribers {
             if let Data::Button(_) = self.data {
                 for s in subs {
-                    s.handle_button_press(&self);
+                    s.handle_button_press(self);
                 }
             } else {
                 for s in subs {
-                    s.handle_data_changed(&self, &old_data);
+                    s.handle_data_changed(self, old_data);
                 }
             }
         }
     }
 }
 
+fn not_found(path: &str) -> Error {
+    Error::SimpleErrorStr(format!("Path: Cannot find node at {path}"))
+}
+
 impl Hash for Node {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.id.hash(state);
-        self.name.hash(state);
-        self.data.hash(state);
-
-        if let Some(children) = &self.children {
-            for child in children {
-                child.hash(state);
-            }
+        // Parents before their children. The amount of children marks where a level ends, so
+        // moving a node changes the hash even if the order stays the same.
+        for node in self.iter() {
+            node.id.hash(state);
+            node.name.hash(state);
+            node.data.hash(state);
+            node.get_children_count().hash(state);
         }
     }
 }
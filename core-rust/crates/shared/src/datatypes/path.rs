@@ -0,0 +1,230 @@
+// Addresses nodes by the names on the way from the root, like `/plant/line1/temperature`.
+// Every segment is the name of a child. Siblings with the same name are told apart by their
+// position among each other: `line[0]` is the first child named `line`, `line[1]` the second and
+// `line` is short for `line[0]`. Unnamed children are addressed by the index alone, `[2]` is the
+// third unnamed child. `\` escapes `/`, `[` and `\` inside of names.
+// The root is `/`. Use [crate::datatypes::nodes::Node::find_by_path] to find a node and
+// [crate::datatypes::nodes::Node::path_of] to get the path of a node.
+
+use std::fmt::Display;
+
+use crate::{datatypes::nodes::Node, errors::Error};
+
+/// A single step of a path. An empty name stands for unnamed children.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub name: String,
+    pub index: usize,
+}
+
+impl Segment {
+    /// The [index]th child with [name].
+    pub fn new(name: impl Display, index: usize) -> Self {
+        Self {
+            name: name.to_string(),
+            index,
+        }
+    }
+
+    /// Checks if [node] has the name of this segment. The index is not checked.
+    pub fn matches(&self, node: &Node) -> bool {
+        node.name.as_deref().unwrap_or("") == self.name
+    }
+}
+
+impl Display for Segment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for c in self.name.chars() {
+            if matches!(c, '/' | '[' | '\\') {
+                write!(f, "\\")?;
+            }
+            write!(f, "{c}")?;
+        }
+        // Unnamed children always need their index.
+        if self.index != 0 || self.name.is_empty() {
+            write!(f, "[{}]", self.index)?;
+        }
+        Ok(())
+    }
+}
+
+/// Splits [path] into its segments. The leading `/` is optional, the root is `/` or the empty
+/// path.
+pub fn parse(path: &str) -> Result<Vec<Segment>, Error> {
+    let rest = path.strip_prefix('/').unwrap_or(path);
+    if rest.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut segments = vec![];
+    let mut chars = rest.chars();
+    loop {
+        let mut name = String::new();
+        let mut index = None;
+        let mut end = true;
+        while let Some(c) = chars.next() {
+            match c {
+                '/' => {
+                    end = false;
+                    break;
+                }
+                '\\' => match chars.next() {
+                    Some(escaped) => name.push(escaped),
+                    None => {
+                        return Err(Error::SimpleErrorStr(format!(
+                            "Path: {path} ends with an escape"
+                        )));
+                    }
+                },
+                '[' => index = Some(parse_index(&mut chars, path)?),
+                // Nothing may follow the index.
+                _ if index.is_some() => {
+                    return Err(Error::SimpleErrorStr(format!(
+                        "Path: Name continues after the index in {path}"
+                    )));
+                }
+                c => name.push(c),
+            }
+        }
+
+        if name.is_empty() && index.is_none() {
+            return Err(Error::SimpleErrorStr(format!(
+                "Path: Empty segment in {path}"
+            )));
+        }
+        segments.push(Segment::new(name, index.unwrap_or(0)));
+        if end {
+            return Ok(segments);
+        }
+    }
+}
+
+/// Formats [segments] as a path.
+pub fn format(segments: &[Segment]) -> String {
+    if segments.is_empty() {
+        return "/".to_string();
+    }
+    segments
+        .iter()
+        .map(|segment| format!("/{segment}"))
+        .collect()
+}
+
+// Reads the index after the `[` up to and including the `]`.
+fn parse_index(chars: &mut std::str::Chars, path: &str) -> Result<usize, Error> {
+    let mut digits = String::new();
+    for c in chars.by_ref() {
+        if c == ']' {
+            return match digits.parse() {
+                Ok(index) => Ok(index),
+                Err(_) => Err(Error::SimpleErrorStr(format!(
+                    "Path: Invalid index [{digits}] in {path}"
+                ))),
+            };
+        }
+        digits.push(c);
+    }
+    Err(Error::SimpleErrorStr(format!(
+        "Path: Index is not closed in {path}"
+    )))
+}
+
+#[cfg(test)]
+mod test {
+    use uuid::Uuid;
+
+    use crate::datatypes::{
+        Data,
+        nodes::Node,
+        path::{Segment, format, parse},
+        treebuilder::TreeChange,
+    };
+
+    #[test]
+    fn parse_and_format() {
+        let segments = parse("/plant/line[1]/[0]/a\\/b\\[c\\\\").unwrap();
+        assert_eq!(
+            segments,
+            vec![
+                Segment::new("plant", 0),
+                Segment::new("line", 1),
+                Segment::new("", 0),
+                Segment::new("a/b[c\\", 0),
+            ]
+        );
+        assert_eq!(format(&segments), "/plant/line[1]/[0]/a\\/b\\[c\\\\");
+
+        assert_eq!(parse("/").unwrap(), vec![]);
+        assert_eq!(parse("").unwrap(), vec![]);
+        assert_eq!(parse("plant").unwrap(), vec![Segment::new("plant", 0)]);
+        assert_eq!(format(&[]), "/");
+
+        assert!(parse("/plant//line").is_err());
+        assert!(parse("/plant/").is_err());
+        assert!(parse("/line[x]").is_err());
+        assert!(parse("/line[1").is_err());
+        assert!(parse("/line[1]x").is_err());
+        assert!(parse("/line\\").is_err());
+    }
+
+    #[test]
+    fn find() {
+        let mut root = Node::root().children(vec![Node::new().name("plant").children(vec![
+            Node::new().name("line").id(Uuid::from_u128(1)),
+            Node::new().id(Uuid::from_u128(2)),
+            Node::new().name("line").id(Uuid::from_u128(3)).children(vec![
+                Node::new()
+                    .name("temp/°C")
+                    .id(Uuid::from_u128(4))
+                    .data(Data::Float32(21.5)),
+            ]),
+        ])]);
+
+        assert_eq!(root.find_by_path("/").unwrap().id, Uuid::nil());
+        assert_eq!(
+            root.find_by_path("/plant/line").unwrap().id,
+            Uuid::from_u128(1)
+        );
+        assert_eq!(
+            root.find_by_path("/plant/[0]").unwrap().id,
+            Uuid::from_u128(2)
+        );
+        let temperature = root.find_by_path_mut("/plant/line[1]/temp\\/°C").unwrap();
+        temperature.change_data(Data::Float32(22.0));
+        assert!(root.find_by_path("/plant/line[2]").is_err());
+        assert!(root.find_by_path("/plant/[1]").is_err());
+
+        // Every path leads back to its node.
+        for id in 0..=4 {
+            let id = Uuid::from_u128(id);
+            let path = root.path_of(&id).unwrap();
+            assert_eq!(root.find_by_path(&path).unwrap().id, id);
+        }
+        assert_eq!(root.path_of(&Uuid::from_u128(3)).unwrap(), "/plant/line[1]");
+        assert_eq!(root.path_of(&Uuid::from_u128(2)).unwrap(), "/plant/[0]");
+        assert!(root.path_of(&Uuid::max()).is_none());
+    }
+
+    #[test]
+    fn create() {
+        let mut root = Node::root().children(vec![Node::new().name("plant")]);
+
+        let (id, changes) = root.create_path("/plant/line1/sensors").unwrap();
+        assert_eq!(changes.len(), 2);
+        assert!(matches!(
+            &changes[0],
+            TreeChange::NodeAdded(Data::Folder, Some(name), _, _) if name == "line1"
+        ));
+        assert_eq!(root.find_by_path("/plant/line1/sensors").unwrap().id, id);
+
+        // Existing folders are reused.
+        let (again, changes) = root.create_path("/plant/line1/sensors").unwrap();
+        assert_eq!(again, id);
+        assert!(changes.is_empty());
+
+        let (second, _) = root.create_path("/plant/line1[1]").unwrap();
+        assert_eq!(root.path_of(&second).unwrap(), "/plant/line1[1]");
+        assert!(root.create_path("/plant/line1[3]").is_err());
+        assert!(root.create_path("/plant/[0]").is_err());
+    }
+}
@@ -0,0 +1,113 @@
+// A snapshot is the whole tree as a flat list, so that it can be sent in a single message.
+// Use [Snapshot::new] to take it and [Node::from_snapshot] to rebuild the tree.
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    datatypes::{Data, nodes::Node},
+    security::permissions::Permissions,
+};
+
+/// A single node of a [Snapshot] without its children.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NodeRecord {
+    pub id: Uuid,
+    pub parent_id: Option<Uuid>, // None only for the root.
+    pub name: Option<String>,
+    pub data: Data,
+    pub permissions: Permissions,
+}
+
+/// The whole tree with the hash it had when the snapshot was taken.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot {
+    pub hash: u64,
+    /// The root first, afterwards parents always come before their children.
+    pub nodes: Vec<NodeRecord>,
+}
+
+impl Snapshot {
+    /// Takes a snapshot of the tree with [root].
+    pub fn new(root: &Node) -> Self {
+        fn add(node: &Node, parent_id: Option<Uuid>, nodes: &mut Vec<NodeRecord>) {
+            nodes.push(NodeRecord {
+                id: node.id,
+                parent_id,
+                name: node.name.clone(),
+                data: node.data.clone(),
+                permissions: node.permissions.clone(),
+            });
+            if let Some(children) = &node.children {
+                for child in children {
+                    add(child, Some(node.id), nodes);
+                }
+            }
+        }
+
+        let mut nodes = vec![];
+        add(root, None, &mut nodes);
+        Self {
+            hash: root.get_hash(),
+            nodes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use uuid::Uuid;
+
+    use crate::{
+        datatypes::{Data, nodes::Node, snapshot::Snapshot},
+        security::permissions::Permissions,
+    };
+
+    #[test]
+    fn roundtrip() {
+        let mut tree = Node::root().name("root").children(vec![
+            Node::new()
+                .name("a")
+                .id(Uuid::from_u128(1))
+                .children(vec![Node::new().data(Data::Int32(3))]),
+            Node::new()
+                .name("b")
+                .data(Data::Button(2))
+                .permissions(Permissions::Admin),
+        ]);
+        tree.get_child(0)
+            .unwrap()
+            .add_child(Node::new().name("late"));
+
+        let snapshot = Snapshot::new(&tree);
+        let mut copy = Node::from_snapshot(&snapshot).unwrap();
+        assert_eq!(copy.get_hash(), tree.get_hash());
+        assert_eq!(copy.get_child(1).unwrap().permissions, Permissions::Admin);
+        assert_eq!(
+            copy.find_node(&Uuid::from_u128(1))
+                .unwrap()
+                .get_children_count(),
+            2
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        let tree = Node::root().children(vec![Node::new().name("a")]);
+
+        // The tree does not match the hash.
+        let mut snapshot = Snapshot::new(&tree);
+        snapshot.nodes[1].name = Some("b".to_string());
+        assert!(Node::from_snapshot(&snapshot).is_err());
+
+        // A node whose parent is not in the snapshot.
+        let mut snapshot = Snapshot::new(&tree);
+        snapshot.nodes[1].parent_id = Some(Uuid::from_u128(7));
+        assert!(Node::from_snapshot(&snapshot).is_err());
+
+        // No root.
+        let mut snapshot = Snapshot::new(&tree);
+        snapshot.nodes.remove(0);
+        assert!(Node::from_snapshot(&snapshot).is_err());
+    }
+}
@@ -0,0 +1,237 @@
+// Goes through the nodes of a tree without writing the recursion by hand.
+// [Node::iter] and [Node::breadth_first] go through a node and everything below it,
+// [Node::walk] tells a visitor the depth of every node and lets it skip subtrees.
+// [Node::iter_mut] cannot hand out a node and its children at the same time, so it hands out
+// [NodeMut], the fields of a node without its children.
+
+use std::collections::VecDeque;
+
+use uuid::Uuid;
+
+use crate::{
+    datatypes::{Data, nodes::Node},
+    security::permissions::Permissions,
+};
+
+/// Depth first, parents before their children. See [Node::iter].
+pub struct DepthFirst<'a> {
+    stack: Vec<&'a Node>,
+}
+
+impl<'a> DepthFirst<'a> {
+    pub(crate) fn new(node: &'a Node) -> Self {
+        Self { stack: vec![node] }
+    }
+}
+
+impl<'a> Iterator for DepthFirst<'a> {
+    type Item = &'a Node;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.stack.pop()?;
+        // Reversed, so that the first child comes next.
+        if let Some(children) = &node.children {
+            self.stack.extend(children.iter().rev());
+        }
+        Some(node)
+    }
+}
+
+/// Breadth first, level by level. See [Node::breadth_first].
+pub struct BreadthFirst<'a> {
+    queue: VecDeque<&'a Node>,
+}
+
+impl<'a> BreadthFirst<'a> {
+    pub(crate) fn new(node: &'a Node) -> Self {
+        Self {
+            queue: VecDeque::from([node]),
+        }
+    }
+}
+
+impl<'a> Iterator for BreadthFirst<'a> {
+    type Item = &'a Node;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.queue.pop_front()?;
+        if let Some(children) = &node.children {
+            self.queue.extend(children.iter());
+        }
+        Some(node)
+    }
+}
+
+/// The fields of a node that can be changed while going through the tree.
+/// The id and the children stay as they are, as the index of the tree depends on them.
+///
+/// Changing the fields directly triggers no events, use [Node::walk_mut] for that.
+pub struct NodeMut<'a> {
+    pub id: &'a Uuid,
+    pub parent_id: &'a Option<Uuid>,
+    pub data: &'a mut Data,
+    pub name: &'a mut Option<String>,
+    pub permissions: &'a mut Permissions,
+}
+
+/// Depth first, parents before their children. See [Node::iter_mut].
+pub struct DepthFirstMut<'a> {
+    stack: Vec<&'a mut Node>,
+}
+
+impl<'a> DepthFirstMut<'a> {
+    pub(crate) fn new(node: &'a mut Node) -> Self {
+        Self { stack: vec![node] }
+    }
+}
+
+impl<'a> Iterator for DepthFirstMut<'a> {
+    type Item = NodeMut<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // The node is split into its fields, so that the children can be handed out on their own.
+        let Node {
+            data,
+            name,
+            id,
+            children,
+            parent_id,
+            permissions,
+            ..
+        } = self.stack.pop()?;
+        if let Some(children) = children {
+            self.stack.extend(children.iter_mut().rev());
+        }
+        Some(NodeMut {
+            id,
+            parent_id,
+            data,
+            name,
+            permissions,
+        })
+    }
+}
+
+/// The nodes on the way from a node down to one below it. See [Node::ancestors].
+pub struct Ancestors<'a> {
+    node: Option<&'a Node>,
+    positions: std::vec::IntoIter<usize>,
+}
+
+impl<'a> Ancestors<'a> {
+    /// Follows [positions] from [node], see [crate::datatypes::index].
+    pub(crate) fn new(node: &'a Node, positions: Vec<usize>) -> Self {
+        Self {
+            node: Some(node),
+            positions: positions.into_iter(),
+        }
+    }
+}
+
+impl<'a> Iterator for Ancestors<'a> {
+    type Item = &'a Node;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Every node but the last one on the way has a position to go on with.
+        let position = self.positions.next()?;
+        let node = self.node?;
+        self.node = node
+            .children
+            .as_ref()
+            .and_then(|children| children.get(position));
+        Some(node)
+    }
+}
+
+/// What [Node::walk] does after a visitor saw a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Walk {
+    /// Goes on with the children of the node.
+    Continue,
+    /// Leaves out everything below the node.
+    Skip,
+    /// Ends the walk.
+    Stop,
+}
+
+#[cfg(test)]
+mod test {
+    use uuid::Uuid;
+
+    use crate::{
+        datatypes::{Data, nodes::Node, traversal::Walk},
+        security::permissions::Permissions,
+    };
+
+    fn tree() -> Node {
+        let id = Uuid::from_u128;
+        Node::root().children(vec![
+            Node::new().id(id(1)).children(vec![
+                Node::new().id(id(3)),
+                Node::new()
+                    .id(id(4))
+                    .children(vec![Node::new().id(id(6)).data(Data::Int32(2))]),
+            ]),
+            Node::new().id(id(2)).children(vec![Node::new().id(id(5))]),
+        ])
+    }
+
+    fn ids<'a>(nodes: impl Iterator<Item = &'a Node>) -> Vec<u128> {
+        nodes.map(|node| node.id.as_u128()).collect()
+    }
+
+    #[test]
+    fn iterators() {
+        let mut tree = tree();
+        assert_eq!(ids(tree.iter()), vec![0, 1, 3, 4, 6, 2, 5]);
+        assert_eq!(ids(tree.breadth_first()), vec![0, 1, 2, 3, 4, 5, 6]);
+        assert_eq!(ids(tree.descendants()), vec![1, 3, 4, 6, 2, 5]);
+        assert_eq!(ids(tree.ancestors(&Uuid::from_u128(6))), vec![0, 1, 4]);
+        assert_eq!(ids(tree.ancestors(&Uuid::nil())), Vec::<u128>::new());
+        assert_eq!(ids(tree.ancestors(&Uuid::max())), Vec::<u128>::new());
+
+        for node in tree.iter_mut() {
+            if node.id.as_u128() > 2 {
+                *node.permissions = Permissions::Admin;
+                *node.name = Some(format!("node {}", node.id.as_u128()));
+            }
+        }
+        let admin = tree
+            .iter()
+            .filter(|node| node.permissions == Permissions::Admin)
+            .count();
+        assert_eq!(admin, 4);
+        let named = tree.find_node(&Uuid::from_u128(6)).unwrap();
+        assert_eq!(named.name.as_deref(), Some("node 6"));
+    }
+
+    #[test]
+    fn walk() {
+        let tree = tree();
+        let mut seen = vec![];
+        tree.walk(|node, depth| {
+            seen.push((node.id.as_u128(), depth));
+            match node.id.as_u128() {
+                4 => Walk::Skip,
+                2 => Walk::Stop,
+                _ => Walk::Continue,
+            }
+        });
+        assert_eq!(seen, vec![(0, 0), (1, 1), (3, 2), (4, 2), (2, 1)]);
+
+        let mut tree = tree;
+        let mut pressed = 0;
+        tree.walk_mut(|node, _| {
+            if let Data::Int32(n) = node.data {
+                node.change_data(Data::Int32(n + 1));
+                pressed += 1;
+            }
+            Walk::Continue
+        });
+        assert_eq!(pressed, 1);
+        assert!(matches!(
+            tree.find_node(&Uuid::from_u128(6)).unwrap().data,
+            Data::Int32(3)
+        ));
+    }
+}
@@ -10,22 +10,30 @@ use uuid::Uuid;
 use crate::{
     datatypes::{Data, nodes::Node},
     errors::Error,
+    security::permissions::Permissions,
 };
 
 /// Defines possible changes that can be done to the tree.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum TreeChange {
     /// Data for added node, name, id, parent-id
     NodeAdded(Data, Option<String>, Uuid, Uuid),
     NodeRemoved(Uuid),
     NodeChangedName(Uuid, String),
     NodeChangedData(Uuid, Data),
+    NodeChangedPermissions(Uuid, Permissions),
+    /// Moves a node to a new parent. id, new parent-id, position among the children of the new
+    /// parent
+    NodeMoved(Uuid, Uuid, usize),
 }
 
 pub struct TreeBuilder;
 
 impl TreeBuilder {
-    pub fn change(root: &mut Node, change: TreeChange) -> Result<u64, Error> {
+    /// Applies [change] to the tree below [root]. Takes as long as finding the nodes of the
+    /// change, the tree is not hashed. Use [Node::get_hash] for that, which goes through the
+    /// whole tree.
+    pub fn change(root: &mut Node, change: TreeChange) -> Result<(), Error> {
         match change {
             // A node has been added.
             TreeChange::NodeAdded(data, Some(name), id, parent) => {
@@ -50,11 +58,15 @@ impl TreeBuilder {
             }
 
             TreeChange::NodeRemoved(id) => {
-                if !root.remove_child(&id) {
+                if root.remove_child(&id).is_none() {
                     return Err(Error::SimpleError("No node to delete"));
                 }
             }
 
+            TreeChange::NodeMoved(id, parent, position) => {
+                root.move_node(&id, &parent, position)?
+            }
+
             // Data has changed.
             TreeChange::NodeChangedData(id, data) => {
                 if let Some(node) = root.find_node_mut(&id) {
@@ -67,6 +79,18 @@ impl TreeBuilder {
                 }
             }
 
+            // Permissions have changed.
+            TreeChange::NodeChangedPermissions(id, permissions) => {
+                if let Some(node) = root.find_node_mut(&id) {
+                    node.change_permissions(permissions);
+                } else {
+                    return Err(Error::SimpleErrorStr(format!(
+                        "TreeBuilder: Cannot find node with id={:?}",
+                        id
+                    )));
+                }
+            }
+
             // Name has changed.
             TreeChange::NodeChangedName(id, name) => {
                 if let Some(node) = root.find_node_mut(&id) {
@@ -80,18 +104,61 @@ impl TreeBuilder {
             }
         };
 
-        Ok(root.get_hash())
+        Ok(())
+    }
+
+    /// Creates the changes that build [root] when applied to an empty [Node::root].
+    /// Parents always come before their children.
+    pub fn replay(root: &Node) -> Vec<TreeChange> {
+        let mut changes = vec![];
+
+        if let Some(name) = &root.name {
+            changes.push(TreeChange::NodeChangedName(root.id, name.clone()));
+        }
+        if !matches!(root.data, Data::Folder) {
+            changes.push(TreeChange::NodeChangedData(root.id, root.data.clone()));
+        }
+
+        if let Some(children) = &root.children {
+            for child in children {
+                changes.extend(TreeBuilder::added(child, root.id));
+            }
+        }
+
+        changes
+    }
+
+    /// Creates the changes that add [node] and everything below it to the node with [parent].
+    /// Parents always come before their children.
+    pub fn added(node: &Node, parent: Uuid) -> Vec<TreeChange> {
+        let mut changes = vec![TreeChange::NodeAdded(
+            node.data.clone(),
+            node.name.clone(),
+            node.id,
+            parent,
+        )];
+        if let Some(children) = &node.children {
+            for child in children {
+                changes.extend(TreeBuilder::added(child, node.id));
+            }
+        }
+        changes
     }
 }
 
 #[cfg(test)]
 pub mod test {
+    use std::sync::{Arc, Mutex};
+
     use uuid::Uuid;
 
-    use crate::datatypes::{
-        Data,
-        nodes::Node,
-        treebuilder::{TreeBuilder, TreeChange},
+    use crate::{
+        datatypes::{
+            Data,
+            nodes::Node,
+            treebuilder::{TreeBuilder, TreeChange},
+        },
+        events::{ChildRemoved, NodeMoved},
     };
 
     fn make_default_tree() -> Node {
@@ -114,8 +181,8 @@ pub mod test {
         let mut tree = make_default_tree();
         let mut tree2 = make_default_tree();
 
-        let id = tree2.get_child(0).unwrap().id.clone();
-        let id2 = tree2.get_child(1).unwrap().id.clone();
+        let id = tree2.get_child(0).unwrap().id;
+        let id2 = tree2.get_child(1).unwrap().id;
 
         tree.get_child(0)
             .unwrap()
@@ -145,7 +212,7 @@ pub mod test {
             tree.add_child(Node::new().name(format!("node {i}")).id(Uuid::from_u128(i)));
         }
 
-        let root_id = tree2.id.clone();
+        let root_id = tree2.id;
         for i in 0..100 {
             TreeBuilder::change(
                 &mut tree2,
@@ -153,7 +220,7 @@ pub mod test {
                     Data::Folder,
                     Some(format!("node {i}")),
                     Uuid::from_u128(i),
-                    root_id.clone(),
+                    root_id,
                 ),
             )
             .unwrap();
@@ -162,16 +229,174 @@ pub mod test {
         assert_eq!(tree.get_hash(), tree2.get_hash());
     }
 
+    #[test]
+    fn replay() {
+        // The first child already has the nil id of [Node::root].
+        let mut tree = make_default_tree();
+        tree.get_child(0)
+            .unwrap()
+            .add_child(Node::new().name("nested").data(Data::Bool(false)));
+
+        let mut mirror = Node::root().id(tree.id);
+        for change in TreeBuilder::replay(&tree) {
+            TreeBuilder::change(&mut mirror, change).unwrap();
+        }
+
+        assert_eq!(tree.get_hash(), mirror.get_hash());
+    }
+
     #[test]
     fn remove() {
         let mut tree = make_default_tree();
         let mut tree2 = make_default_tree();
 
-        let right_id = tree.get_child(0).unwrap().id.clone();
+        let right_id = tree.get_child(0).unwrap().id;
         tree.remove_child(&right_id);
 
         TreeBuilder::change(&mut tree2, TreeChange::NodeRemoved(right_id)).unwrap();
 
         assert_eq!(tree.get_hash(), tree2.get_hash());
     }
+
+    #[test]
+    fn remove_nested() {
+        let id = Uuid::from_u128;
+        let mut tree = Node::root().children(vec![
+            Node::new().id(id(1)).children(vec![
+                Node::new().id(id(2)),
+                Node::new()
+                    .id(id(3))
+                    .children(vec![Node::new().id(id(4)), Node::new().id(id(5))]),
+                Node::new().id(id(6)),
+            ]),
+            Node::new().id(id(7)),
+        ]);
+        let removed_ids = Arc::new(Mutex::new(vec![]));
+        let ids = removed_ids.clone();
+        tree.subscribe_to_children(ChildRemoved::new(move |node: &Node| {
+            ids.lock().unwrap().push(node.id)
+        }));
+
+        // The subtree comes back as a tree of its own.
+        let removed = tree.remove_child(&id(3)).unwrap();
+        assert_eq!(removed.parent_id, None);
+        assert_eq!(removed.get_children_count(), 2);
+        assert!(removed.find_node(&id(5)).is_some());
+        assert_eq!(*removed_ids.lock().unwrap(), vec![id(3), id(4), id(5)]);
+
+        // Only the subtree is gone, its siblings keep their order.
+        for gone in 3..=5 {
+            assert!(tree.find_node(&id(gone)).is_none());
+        }
+        let parent = tree.find_node(&id(1)).unwrap();
+        let siblings: Vec<Uuid> = parent
+            .children
+            .iter()
+            .flatten()
+            .map(|child| child.id)
+            .collect();
+        assert_eq!(siblings, vec![id(2), id(6)]);
+        assert!(tree.find_node(&id(7)).is_some());
+
+        // The root and missing nodes cannot be removed.
+        assert!(tree.remove_child(&Uuid::nil()).is_none());
+        assert!(tree.remove_child(&id(3)).is_none());
+        assert!(TreeBuilder::change(&mut tree, TreeChange::NodeRemoved(Uuid::nil())).is_err());
+
+        // The changes of a deep removal work on a mirror as well.
+        let mut mirror = Node::root();
+        for change in TreeBuilder::replay(&tree) {
+            TreeBuilder::change(&mut mirror, change).unwrap();
+        }
+        tree.remove_child(&id(6)).unwrap();
+        TreeBuilder::change(&mut mirror, TreeChange::NodeRemoved(id(6))).unwrap();
+        assert_eq!(tree.get_hash(), mirror.get_hash());
+    }
+
+    #[test]
+    fn move_nodes() {
+        let id = Uuid::from_u128;
+        let children = |tree: &Node, parent| -> Vec<Uuid> {
+            let parent = tree.find_node(&id(parent)).unwrap();
+            parent
+                .children
+                .iter()
+                .flatten()
+                .map(|child| child.id)
+                .collect()
+        };
+        let mut tree = Node::root().id(id(0)).children(vec![
+            Node::new().id(id(1)).children(vec![
+                Node::new().id(id(2)),
+                Node::new().id(id(3)).children(vec![Node::new().id(id(4))]),
+            ]),
+            Node::new().id(id(5)).children(vec![Node::new().id(id(6))]),
+        ]);
+        let mut mirror = Node::root().id(id(0));
+        for change in TreeBuilder::replay(&tree) {
+            TreeBuilder::change(&mut mirror, change).unwrap();
+        }
+
+        let moves = Arc::new(Mutex::new(vec![]));
+        let seen = moves.clone();
+        tree.find_node_mut(&id(3))
+            .unwrap()
+            .subscribe(NodeMoved::new(move |node: &Node, previous: &Uuid| {
+                seen.lock()
+                    .unwrap()
+                    .push((node.id, node.parent_id, *previous))
+            }));
+
+        // To another parent, the subtree and the subscribers go along.
+        tree.move_node(&id(3), &id(5), 0).unwrap();
+        assert_eq!(children(&tree, 1), vec![id(2)]);
+        assert_eq!(children(&tree, 5), vec![id(3), id(6)]);
+        assert_eq!(tree.find_node(&id(4)).unwrap().parent_id, Some(id(3)));
+        assert_eq!(*moves.lock().unwrap(), vec![(id(3), Some(id(5)), id(1))]);
+
+        // Among its siblings.
+        tree.move_node(&id(3), &id(5), 1).unwrap();
+        assert_eq!(children(&tree, 5), vec![id(6), id(3)]);
+        assert_eq!(moves.lock().unwrap().len(), 2);
+
+        // Nothing changes if a move is not possible.
+        let hash = tree.get_hash();
+        assert!(tree.move_node(&id(5), &id(4), 0).is_err());
+        assert!(tree.move_node(&id(5), &id(5), 0).is_err());
+        assert!(tree.move_node(&id(0), &id(1), 0).is_err());
+        assert!(tree.move_node(&id(3), &id(5), 2).is_err());
+        assert!(tree.move_node(&id(3), &id(1), 2).is_err());
+        assert!(tree.move_node(&id(9), &id(1), 0).is_err());
+        assert_eq!(tree.get_hash(), hash);
+
+        for change in [
+            TreeChange::NodeMoved(id(3), id(5), 0),
+            TreeChange::NodeMoved(id(3), id(5), 1),
+        ] {
+            TreeBuilder::change(&mut mirror, change).unwrap();
+        }
+        assert_eq!(tree.get_hash(), mirror.get_hash());
+        assert!(TreeBuilder::change(&mut mirror, TreeChange::NodeMoved(id(1), id(2), 0)).is_err());
+
+        // Moving a node up next to its parent keeps the order of the nodes, but not the hash.
+        let mut nested = Node::root().id(id(0)).children(vec![
+            Node::new().id(id(1)).children(vec![Node::new().id(id(2))]),
+        ]);
+        let hash = nested.get_hash();
+        nested.move_node(&id(2), &id(0), 1).unwrap();
+        assert_ne!(nested.get_hash(), hash);
+
+        tree.find_node_mut(&id(1))
+            .unwrap()
+            .insert_child_at(0, Node::new().id(id(7)))
+            .unwrap();
+        assert_eq!(children(&tree, 1), vec![id(7), id(2)]);
+        assert!(tree.find_node(&id(2)).is_some());
+        assert!(
+            tree.find_node_mut(&id(1))
+                .unwrap()
+                .insert_child_at(3, Node::new())
+                .is_err()
+        );
+    }
 }
@@ -1,9 +1,19 @@
 use core::fmt;
 
+use crate::remote::message::NackCode;
+
 #[derive(Debug)]
 pub enum Error {
     SimpleError(&'static str),
     SimpleErrorStr(String),
+    /// A frame announced more bytes than [crate::config::MAX_FRAME_SIZE] allows.
+    FrameTooLarge(usize),
+    /// The stream or a payload ended in the middle of a frame.
+    FrameTruncated,
+    /// The other side closed the connection between two frames.
+    ConnectionClosed,
+    /// The server refused a request, see [crate::remote::message::Message::ServerNack].
+    Refused(NackCode, String),
 }
 
 impl Error {
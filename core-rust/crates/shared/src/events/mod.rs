@@ -1,3 +1,5 @@
+use uuid::Uuid;
+
 use crate::datatypes::{Data, nodes::Node};
 
 /// This trait implements the events.
@@ -18,6 +20,9 @@ pub trait EventSubscriber {
 
     fn handle_permissions_changed(&self, _node: &Node) {}
 
+    /// Is called after the node moved, the parent id of [node] is already the new one.
+    fn handle_node_moved(&self, _node: &Node, _previous_parent: &Uuid) {}
+
     /// Special event that is triggered when a node is the [Data::Button] and is pressed.
     ///
     /// This event is transmitted, only from client to server.
@@ -101,6 +106,14 @@ make_event_subscriber!(
     }
 );
 
+make_event_subscriber!(
+    NodeMoved,
+    Fn(&Node, &Uuid),
+    fn handle_node_moved(&self, node: &Node, previous_parent: &Uuid) {
+        (self.handler)(node, previous_parent)
+    }
+);
+
 make_event_subscriber!(
     PermissionsChanged,
     Fn(&Node),
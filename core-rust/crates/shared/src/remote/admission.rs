@@ -0,0 +1,153 @@
+// Decides how many connections a server serves at the same time.
+// Every connection holds a [Slot] while it is served. The slot is given back when it is dropped,
+// so the count is right however the connection ends, even if its thread panics.
+
+use std::{
+    io::{Read, Write},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    errors::Error,
+    remote::{codec::FramedStream, message::Message},
+};
+
+/// How many connections are served right now and how many were at most.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConnectionStats {
+    pub current: u16,
+    /// The highest [ConnectionStats::current] since the server started.
+    pub peak: u16,
+    pub max: u16,
+}
+
+/// Counts the live connections of a server. Clones share the same count.
+#[derive(Clone, Debug)]
+pub struct Admission {
+    stats: Arc<Mutex<ConnectionStats>>,
+}
+
+impl Admission {
+    /// Creates the admission for at most [max] connections at the same time.
+    pub fn new(max: u16) -> Self {
+        Self {
+            stats: Arc::new(Mutex::new(ConnectionStats {
+                current: 0,
+                peak: 0,
+                max,
+            })),
+        }
+    }
+
+    /// Takes a slot for a new connection. Returns [None] if all slots are taken, the connection
+    /// should then get [Admission::rejection] and be closed.
+    pub fn try_admit(&self) -> Option<Slot> {
+        let mut stats = self.lock();
+        if stats.current >= stats.max {
+            return None;
+        }
+        stats.current += 1;
+        stats.peak = stats.peak.max(stats.current);
+        Some(Slot {
+            admission: self.clone(),
+        })
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        *self.lock()
+    }
+
+    /// The message that tells a client why it is not served.
+    pub fn rejection(&self) -> Message {
+        Message::ServerLog(format!(
+            "Server is full, all {} connections are in use. Try again later.",
+            self.lock().max
+        ))
+    }
+
+    /// Sends [Admission::rejection] to a client and waits up to [timeout] for it to close the
+    /// connection. Closing first could reset the connection before the client read why.
+    /// The read timeout of [stream] should be shorter than [timeout].
+    pub fn reject<S: Read + Write>(
+        &self,
+        stream: &mut FramedStream<S>,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        stream.send(&self.rejection())?;
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if stream.recv().is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // The stats are always consistent, so a panic while holding the lock does not matter.
+    fn lock(&self) -> MutexGuard<'_, ConnectionStats> {
+        match self.stats.lock() {
+            Ok(stats) => stats,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+/// A taken slot of an [Admission]. Is given back when dropped.
+#[derive(Debug)]
+pub struct Slot {
+    admission: Admission,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.admission.lock().current -= 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use crate::remote::admission::{Admission, ConnectionStats};
+
+    #[test]
+    fn slots() {
+        let admission = Admission::new(2);
+        let first = admission.try_admit().unwrap();
+        let second = admission.clone().try_admit().unwrap();
+        assert!(admission.try_admit().is_none());
+
+        drop(first);
+        let third = admission.try_admit().unwrap();
+        assert_eq!(
+            admission.stats(),
+            ConnectionStats {
+                current: 2,
+                peak: 2,
+                max: 2
+            }
+        );
+
+        drop(second);
+        drop(third);
+        assert_eq!(admission.stats().current, 0);
+        assert_eq!(admission.stats().peak, 2);
+    }
+
+    // A connection that panics still gives its slot back.
+    #[test]
+    fn panic() {
+        let admission = Admission::new(1);
+        let slot = admission.try_admit().unwrap();
+        let result = thread::spawn(move || {
+            let _slot = slot;
+            panic!("Connection failed");
+        })
+        .join();
+
+        assert!(result.is_err());
+        assert_eq!(admission.stats().current, 0);
+        assert!(admission.try_admit().is_some());
+    }
+}
@@ -0,0 +1,1123 @@
+// Length prefixed binary framing for [Message].
+//
+// Every frame is a u32 (little endian) length followed by that many bytes of payload.
+// The payload starts with a tag byte for the variant of the message followed by its fields.
+//
+// - integers are little endian.
+// - strings and byte vectors are prefixed with their length as u32.
+// - options are prefixed with 0 (None) or 1 (Some).
+// - uuids are their 16 bytes.
+// - [Message::ClientHello] uses the layout of the client_hello module.
+//
+// Some messages have more fields in later versions of the protocol, see [crate::remote::version].
+// [decode] reads every version, [encode_version] writes the one the other side speaks.
+
+use std::{
+    io::{Cursor, ErrorKind, Read, Write},
+    time::{Duration, Instant},
+};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use rsa::traits::PublicKeyParts;
+use uuid::Uuid;
+
+use crate::{
+    config,
+    datatypes::{
+        Data,
+        snapshot::{NodeRecord, Snapshot},
+        treebuilder::TreeChange,
+    },
+    errors::Error,
+    remote::{
+        message::{Message, NackCode, client_hello, client_hello_rsa_key},
+        version::{Capabilities, HEARTBEAT_VERSION, MOVE_VERSION, REQUEST_VERSION},
+    },
+    security::{
+        exchange::{self, Binding, SecureChannel},
+        permissions::Permissions,
+    },
+};
+
+/// Encodes the message into a payload in [config::CURRENT_VERSION]. The length prefix is not
+/// included.
+pub fn encode(message: &Message) -> Result<Vec<u8>, Error> {
+    encode_version(message, config::CURRENT_VERSION)
+}
+
+/// Encodes the message into a payload for a peer that speaks [version].
+/// [Message::ClientHello] and [Message::ClientResume] are always encoded in the version they
+/// carry, as they are sent before the version is agreed on.
+pub fn encode_version(message: &Message, version: u8) -> Result<Vec<u8>, Error> {
+    let mut buf = vec![];
+
+    match message {
+        Message::ClientHello(version, None, capabilities) => {
+            buf.push(0);
+            buf.append(&mut client_hello::serialize_without_cert(
+                *version,
+                *capabilities,
+            )?);
+        }
+        Message::ClientHello(version, Some(_), capabilities) => {
+            buf.push(0);
+            let key = client_hello_rsa_key(message)?;
+            buf.append(&mut client_hello::serialize(*version, key, *capabilities)?);
+        }
+        Message::ServerAuth(nonce) => {
+            buf.push(1);
+            write_bytes(&mut buf, nonce)?;
+        }
+        Message::ClientAuth(nonce) => {
+            buf.push(2);
+            write_bytes(&mut buf, nonce)?;
+        }
+        Message::ServerAccept(session, validity, capabilities) => {
+            buf.push(3);
+            buf.extend_from_slice(&session.to_le_bytes());
+            buf.extend_from_slice(&validity.to_le_bytes());
+            if version >= 2 {
+                buf.extend_from_slice(&capabilities.bits().to_le_bytes());
+            } else if !capabilities.is_empty() {
+                return Err(Error::SimpleError(
+                    "Encode Message: Version 1 has no capabilities",
+                ));
+            }
+        }
+        Message::ServerRefreshPermissions => buf.push(4),
+        Message::ServerChange(TreeChange::NodeMoved(..)) if version < MOVE_VERSION => {
+            return Err(Error::SimpleErrorStr(format!(
+                "Encode Message: Version {version} cannot move nodes"
+            )));
+        }
+        Message::ServerChange(change) => {
+            buf.push(5);
+            write_tree_change(&mut buf, change)?;
+        }
+        Message::ServerLog(log) => {
+            buf.push(6);
+            write_bytes(&mut buf, log.as_bytes())?;
+        }
+        Message::ClientHash(hash) => {
+            buf.push(7);
+            buf.extend_from_slice(&hash.to_le_bytes());
+        }
+        Message::ClientTrigger(request, id) => {
+            buf.push(8);
+            buf.extend_from_slice(id.as_bytes());
+            write_request(&mut buf, *request, version);
+        }
+        Message::ClientAddPermissions(request, n, e) => {
+            buf.push(9);
+            write_bytes(&mut buf, n)?;
+            write_bytes(&mut buf, e)?;
+            write_request(&mut buf, *request, version);
+        }
+        Message::Snapshot(snapshot) => {
+            buf.push(10);
+            write_snapshot(&mut buf, snapshot)?;
+        }
+        Message::ServerGoodbye(reason) => {
+            buf.push(11);
+            write_bytes(&mut buf, reason.as_bytes())?;
+        }
+        Message::ClientResume(version, session, hash) => {
+            buf.push(12);
+            buf.extend_from_slice(&session.to_le_bytes());
+            buf.extend_from_slice(&hash.to_le_bytes());
+            if *version >= 2 {
+                buf.push(*version);
+            }
+        }
+        Message::ClientKeyExchange(key) => {
+            buf.push(13);
+            write_bytes(&mut buf, key)?;
+        }
+        Message::ServerKeyExchange(key, n, e, signature) => {
+            buf.push(14);
+            write_bytes(&mut buf, key)?;
+            write_bytes(&mut buf, n)?;
+            write_bytes(&mut buf, e)?;
+            write_bytes(&mut buf, signature)?;
+        }
+        Message::ServerVersions(min, max, capabilities) => {
+            buf.push(15);
+            buf.push(*min);
+            buf.push(*max);
+            buf.extend_from_slice(&capabilities.bits().to_le_bytes());
+        }
+        Message::Ping(_) | Message::Pong(_) if version < HEARTBEAT_VERSION => {
+            return Err(Error::SimpleErrorStr(format!(
+                "Encode Message: Version {version} has no heartbeats"
+            )));
+        }
+        Message::Ping(counter) => {
+            buf.push(16);
+            buf.extend_from_slice(&counter.to_le_bytes());
+        }
+        Message::Pong(counter) => {
+            buf.push(17);
+            buf.extend_from_slice(&counter.to_le_bytes());
+        }
+        Message::ServerAck(_) | Message::ServerNack(..) if version < REQUEST_VERSION => {
+            return Err(Error::SimpleErrorStr(format!(
+                "Encode Message: Version {version} has no request ids"
+            )));
+        }
+        Message::ServerAck(request) => {
+            buf.push(18);
+            buf.extend_from_slice(&request.to_le_bytes());
+        }
+        Message::ServerNack(request, code, message) => {
+            buf.push(19);
+            buf.extend_from_slice(&request.to_le_bytes());
+            buf.extend_from_slice(&code.code().to_le_bytes());
+            write_bytes(&mut buf, message.as_bytes())?;
+        }
+    }
+
+    if buf.len() > config::MAX_FRAME_SIZE {
+        return Err(Error::FrameTooLarge(buf.len()));
+    }
+    Ok(buf)
+}
+
+/// Decodes a payload (without the length prefix) into a message of any version.
+/// The whole payload has to be used by the message.
+pub fn decode(payload: &[u8]) -> Result<Message, Error> {
+    let mut reader = Cursor::new(payload);
+
+    let message = match read_u8(&mut reader)? {
+        0 => {
+            let (version, key, capabilities) = client_hello::deserialize(&mut reader)?;
+            // A newer client may say more, which this version does not understand. It is asked
+            // for an older hello instead.
+            if version > config::CURRENT_VERSION {
+                reader.set_position(payload.len() as u64);
+            }
+            Message::ClientHello(
+                version,
+                key.map(|key| (key.n().to_bytes_le(), key.e().to_bytes_le())),
+                capabilities,
+            )
+        }
+        1 => Message::ServerAuth(read_bytes(&mut reader)?),
+        2 => Message::ClientAuth(read_bytes(&mut reader)?),
+        3 => {
+            let session = read_u64(&mut reader)?;
+            let validity = read_u16(&mut reader)?;
+            // Version 1 has no capabilities.
+            let capabilities = match remaining(&reader) {
+                0 => Capabilities::NONE,
+                _ => Capabilities::from_bits(read_u32(&mut reader)?),
+            };
+            Message::ServerAccept(session, validity, capabilities)
+        }
+        4 => Message::ServerRefreshPermissions,
+        5 => Message::ServerChange(read_tree_change(&mut reader)?),
+        6 => Message::ServerLog(read_string(&mut reader)?),
+        7 => Message::ClientHash(read_u64(&mut reader)?),
+        8 => {
+            let id = read_uuid(&mut reader)?;
+            Message::ClientTrigger(read_request(&mut reader)?, id)
+        }
+        9 => {
+            let n = read_bytes(&mut reader)?;
+            let e = read_bytes(&mut reader)?;
+            Message::ClientAddPermissions(read_request(&mut reader)?, n, e)
+        }
+        10 => Message::Snapshot(read_snapshot(&mut reader)?),
+        11 => Message::ServerGoodbye(read_string(&mut reader)?),
+        12 => {
+            let session = read_u64(&mut reader)?;
+            let hash = read_u64(&mut reader)?;
+            // Version 1 does not send its version.
+            let version = match remaining(&reader) {
+                0 => 1,
+                _ => read_u8(&mut reader)?,
+            };
+            Message::ClientResume(version, session, hash)
+        }
+        13 => Message::ClientKeyExchange(read_bytes(&mut reader)?),
+        14 => Message::ServerKeyExchange(
+            read_bytes(&mut reader)?,
+            read_bytes(&mut reader)?,
+            read_bytes(&mut reader)?,
+            read_bytes(&mut reader)?,
+        ),
+        15 => Message::ServerVersions(
+            read_u8(&mut reader)?,
+            read_u8(&mut reader)?,
+            Capabilities::from_bits(read_u32(&mut reader)?),
+        ),
+        16 => Message::Ping(read_u64(&mut reader)?),
+        17 => Message::Pong(read_u64(&mut reader)?),
+        18 => Message::ServerAck(read_u64(&mut reader)?),
+        19 => Message::ServerNack(
+            read_u64(&mut reader)?,
+            NackCode::from_code(read_u16(&mut reader)?),
+            read_string(&mut reader)?,
+        ),
+        tag => {
+            return Err(Error::SimpleErrorStr(format!(
+                "Decode Message: Unknown message tag {tag}"
+            )));
+        }
+    };
+
+    if reader.position() as usize != payload.len() {
+        return Err(Error::SimpleErrorStr(format!(
+            "Decode Message: {} bytes left after message",
+            payload.len() - reader.position() as usize
+        )));
+    }
+    Ok(message)
+}
+
+/// Writes the message as a single frame and flushes the writer.
+pub fn write_message<W: Write>(writer: &mut W, message: &Message) -> Result<(), Error> {
+    write_frame(writer, &encode(message)?)
+}
+
+// Writes [payload] with its length prefix and flushes the writer.
+fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> Result<(), Error> {
+    let mut frame = Vec::with_capacity(payload.len() + 4);
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(payload);
+
+    Error::from(writer.write_all(&frame))?;
+    Error::from(writer.flush())
+}
+
+/// Blocks until a whole frame is read and decodes it.
+/// Read timeouts of the reader are returned as errors, use [FrameReader] to handle them.
+pub fn read_message<R: Read>(reader: &mut R) -> Result<Message, Error> {
+    let mut len_buffer = [0u8; 4];
+    if let Err(err) = reader.read_exact(&mut len_buffer) {
+        return Err(match err.kind() {
+            ErrorKind::UnexpectedEof => Error::ConnectionClosed,
+            _ => Error::SimpleErrorStr(format!("Read Message: {:?}", err)),
+        });
+    }
+
+    let len = u32::from_le_bytes(len_buffer) as usize;
+    if len > config::MAX_FRAME_SIZE {
+        return Err(Error::FrameTooLarge(len));
+    }
+
+    let mut payload = vec![0u8; len];
+    if let Err(err) = reader.read_exact(&mut payload) {
+        return Err(match err.kind() {
+            ErrorKind::UnexpectedEof => Error::FrameTruncated,
+            _ => Error::SimpleErrorStr(format!("Read Message: {:?}", err)),
+        });
+    }
+
+    decode(&payload)
+}
+
+/// Collects bytes from a reader until a whole frame is present.
+/// In contrast to [read_message] a read timeout does not lose the bytes that were already read,
+/// so it can be used on streams with a read timeout to also write in between reads.
+pub struct FrameReader {
+    buffer: Vec<u8>,
+    max_frame_size: usize,
+}
+
+impl Default for FrameReader {
+    fn default() -> Self {
+        Self {
+            buffer: vec![],
+            max_frame_size: config::MAX_FRAME_SIZE,
+        }
+    }
+}
+
+impl FrameReader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads from [reader] until a frame is complete.
+    /// Returns [None] if the reader timed out before that.
+    pub fn read<R: Read>(&mut self, reader: &mut R) -> Result<Option<Message>, Error> {
+        match self.read_payload(reader)? {
+            Some(payload) => decode(&payload).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Like [FrameReader::read], but returns the payload of the frame without decoding it.
+    pub fn read_payload<R: Read>(&mut self, reader: &mut R) -> Result<Option<Vec<u8>>, Error> {
+        let mut chunk = [0u8; 4096];
+        loop {
+            if let Some(payload) = self.next_frame()? {
+                return Ok(Some(payload));
+            }
+
+            match reader.read(&mut chunk) {
+                Ok(0) if self.buffer.is_empty() => return Err(Error::ConnectionClosed),
+                Ok(0) => return Err(Error::FrameTruncated),
+                Ok(n) => self.buffer.extend_from_slice(&chunk[..n]),
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                // rustls reports a peer that closed without a close_notify like this.
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof && self.buffer.is_empty() => {
+                    return Err(Error::ConnectionClosed);
+                }
+                Err(err)
+                    if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut =>
+                {
+                    return Ok(None);
+                }
+                Err(err) => return Err(Error::SimpleErrorStr(format!("Read Message: {:?}", err))),
+            }
+        }
+    }
+
+    // Takes the payload of the first frame out of the buffer if it is complete.
+    fn next_frame(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        if self.buffer.len() < 4 {
+            return Ok(None);
+        }
+
+        let len = u32::from_le_bytes([
+            self.buffer[0],
+            self.buffer[1],
+            self.buffer[2],
+            self.buffer[3],
+        ]) as usize;
+        if len > self.max_frame_size {
+            return Err(Error::FrameTooLarge(len));
+        }
+        if self.buffer.len() < len + 4 {
+            return Ok(None);
+        }
+
+        let payload = self.buffer[4..len + 4].to_vec();
+        self.buffer.drain(..len + 4);
+        Ok(Some(payload))
+    }
+}
+
+/// Sends and recieves framed messages over any stream. Is used by the server and the client.
+/// After [FramedStream::secure] every frame is sealed by a [SecureChannel].
+pub struct FramedStream<S: Read + Write> {
+    stream: S,
+    reader: FrameReader,
+    channel: Option<SecureChannel>,
+    version: u8,
+}
+
+impl<S: Read + Write> FramedStream<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            reader: FrameReader::new(),
+            channel: None,
+            version: config::CURRENT_VERSION,
+        }
+    }
+
+    /// Sends every message in [version] from now on, once the other side said which it speaks.
+    /// Messages of every version are recieved.
+    pub fn set_version(&mut self, version: u8) {
+        self.version = version;
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Seals every frame that is sent and opens every frame that is recieved with [channel] from
+    /// now on. Both sides have to start at the same frame, see [crate::security::exchange].
+    pub fn secure(&mut self, channel: SecureChannel) {
+        // The tag makes sealed frames a bit larger than the message in them.
+        self.reader.max_frame_size = config::MAX_FRAME_SIZE + exchange::TAG_SIZE;
+        self.channel = Some(channel);
+    }
+
+    /// The binding of the [SecureChannel], if the stream is secured.
+    pub fn binding(&self) -> Option<Binding> {
+        self.channel.as_ref().map(|channel| *channel.binding())
+    }
+
+    pub fn send(&mut self, message: &Message) -> Result<(), Error> {
+        let payload = encode_version(message, self.version)?;
+        match &mut self.channel {
+            Some(channel) => write_frame(&mut self.stream, &channel.seal(&payload)?),
+            None => write_frame(&mut self.stream, &payload),
+        }
+    }
+
+    /// Recieves the next message. Returns [None] if the stream timed out before a whole frame was
+    /// read. Without a read timeout this blocks until a message is there.
+    pub fn recv(&mut self) -> Result<Option<Message>, Error> {
+        let Some(payload) = self.reader.read_payload(&mut self.stream)? else {
+            return Ok(None);
+        };
+        match &mut self.channel {
+            Some(channel) => decode(&channel.open(&payload)?).map(Some),
+            None => decode(&payload).map(Some),
+        }
+    }
+
+    /// Recieves the next message, but waits at most [timeout].
+    /// The stream needs a read timeout that is shorter than [timeout] for this to work.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Message, Error> {
+        let start = Instant::now();
+        loop {
+            if let Some(message) = self.recv()? {
+                return Ok(message);
+            }
+            if start.elapsed() >= timeout {
+                return Err(Error::SimpleError("Recieve: Timed out"));
+            }
+        }
+    }
+
+    /// Returns the stream. Bytes that were already read but are not a whole frame are lost.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+}
+
+fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) -> Result<(), Error> {
+    if bytes.len() > config::MAX_FRAME_SIZE {
+        return Err(Error::FrameTooLarge(bytes.len()));
+    }
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+    Ok(())
+}
+
+fn write_option_string(buf: &mut Vec<u8>, string: &Option<String>) -> Result<(), Error> {
+    match string {
+        Some(s) => {
+            buf.push(1);
+            write_bytes(buf, s.as_bytes())
+        }
+        None => {
+            buf.push(0);
+            Ok(())
+        }
+    }
+}
+
+fn write_tree_change(buf: &mut Vec<u8>, change: &TreeChange) -> Result<(), Error> {
+    match change {
+        TreeChange::NodeAdded(data, name, id, parent) => {
+            buf.push(0);
+            write_data(buf, data, 0)?;
+            write_option_string(buf, name)?;
+            buf.extend_from_slice(id.as_bytes());
+            buf.extend_from_slice(parent.as_bytes());
+        }
+        TreeChange::NodeRemoved(id) => {
+            buf.push(1);
+            buf.extend_from_slice(id.as_bytes());
+        }
+        TreeChange::NodeChangedName(id, name) => {
+            buf.push(2);
+            buf.extend_from_slice(id.as_bytes());
+            write_bytes(buf, name.as_bytes())?;
+        }
+        TreeChange::NodeChangedData(id, data) => {
+            buf.push(3);
+            buf.extend_from_slice(id.as_bytes());
+            write_data(buf, data, 0)?;
+        }
+        TreeChange::NodeChangedPermissions(id, permissions) => {
+            buf.push(4);
+            buf.extend_from_slice(id.as_bytes());
+            write_permissions(buf, permissions)?;
+        }
+        TreeChange::NodeMoved(id, parent, position) => {
+            buf.push(5);
+            buf.extend_from_slice(id.as_bytes());
+            buf.extend_from_slice(parent.as_bytes());
+            buf.extend_from_slice(&(*position as u64).to_le_bytes());
+        }
+    }
+    Ok(())
+}
+
+fn write_snapshot(buf: &mut Vec<u8>, snapshot: &Snapshot) -> Result<(), Error> {
+    buf.extend_from_slice(&snapshot.hash.to_le_bytes());
+    buf.extend_from_slice(&(snapshot.nodes.len() as u32).to_le_bytes());
+    for node in &snapshot.nodes {
+        buf.extend_from_slice(node.id.as_bytes());
+        match node.parent_id {
+            Some(parent) => {
+                buf.push(1);
+                buf.extend_from_slice(parent.as_bytes());
+            }
+            None => buf.push(0),
+        }
+        write_option_string(buf, &node.name)?;
+        write_data(buf, &node.data, 0)?;
+        write_permissions(buf, &node.permissions)?;
+
+        // Checked on the way, so a huge tree does not have to be encoded completely first.
+        if buf.len() > config::MAX_FRAME_SIZE {
+            return Err(Error::FrameTooLarge(buf.len()));
+        }
+    }
+    Ok(())
+}
+
+fn write_permissions(buf: &mut Vec<u8>, permissions: &Permissions) -> Result<(), Error> {
+    match permissions {
+        Permissions::Admin => buf.push(0),
+        Permissions::User(None) => buf.push(1),
+        Permissions::User(Some(groups)) => {
+            buf.push(2);
+            buf.extend_from_slice(&(groups.len() as u32).to_le_bytes());
+            for group in groups {
+                write_bytes(buf, group.as_bytes())?;
+            }
+        }
+        Permissions::Public => buf.push(3),
+    }
+    Ok(())
+}
+
+fn write_data(buf: &mut Vec<u8>, data: &Data, depth: usize) -> Result<(), Error> {
+    if depth > config::MAX_DATA_DEPTH {
+        return Err(Error::SimpleError("Encode Data: Nested too deep"));
+    }
+
+    match data {
+        Data::Folder => buf.push(0),
+        Data::Button(n) => {
+            buf.push(1);
+            buf.extend_from_slice(&n.to_le_bytes());
+        }
+        Data::Float32(v) => {
+            buf.push(2);
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        Data::Float64(v) => {
+            buf.push(3);
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        Data::Int32(v) => {
+            buf.push(4);
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        Data::Int64(v) => {
+            buf.push(5);
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        Data::UInt32(v) => {
+            buf.push(6);
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        Data::UInt64(v) => {
+            buf.push(7);
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        Data::String(s) => {
+            buf.push(8);
+            write_bytes(buf, s.as_bytes())?;
+        }
+        Data::Bool(b) => {
+            buf.push(9);
+            buf.push(*b as u8);
+        }
+        Data::Tuple(n, tuple) => {
+            buf.push(10);
+            buf.extend_from_slice(&(*n as u64).to_le_bytes());
+            buf.extend_from_slice(&(tuple.len() as u32).to_le_bytes());
+            for d in tuple.iter() {
+                write_data(buf, d, depth + 1)?;
+            }
+        }
+        Data::List(ls) => {
+            buf.push(11);
+            buf.extend_from_slice(&(ls.len() as u32).to_le_bytes());
+            for d in ls.iter() {
+                write_data(buf, d, depth + 1)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// All reads of a payload fail only if the payload ended too early.
+fn truncated<T>(res: Result<T, std::io::Error>) -> Result<T, Error> {
+    res.map_err(|_| Error::FrameTruncated)
+}
+
+fn read_u8(reader: &mut Cursor<&[u8]>) -> Result<u8, Error> {
+    truncated(reader.read_u8())
+}
+
+fn read_u16(reader: &mut Cursor<&[u8]>) -> Result<u16, Error> {
+    truncated(reader.read_u16::<LittleEndian>())
+}
+
+fn read_u32(reader: &mut Cursor<&[u8]>) -> Result<u32, Error> {
+    truncated(reader.read_u32::<LittleEndian>())
+}
+
+fn read_u64(reader: &mut Cursor<&[u8]>) -> Result<u64, Error> {
+    truncated(reader.read_u64::<LittleEndian>())
+}
+
+fn read_uuid(reader: &mut Cursor<&[u8]>) -> Result<Uuid, Error> {
+    let mut bytes = [0u8; 16];
+    truncated(reader.read_exact(&mut bytes))?;
+    Ok(Uuid::from_bytes(bytes))
+}
+
+// Returns how many bytes of the payload are not read yet.
+fn remaining(reader: &Cursor<&[u8]>) -> usize {
+    reader.get_ref().len() - reader.position() as usize
+}
+
+// Requests carry their id at the end since [REQUEST_VERSION].
+fn write_request(buf: &mut Vec<u8>, request: u64, version: u8) {
+    if version >= REQUEST_VERSION {
+        buf.extend_from_slice(&request.to_le_bytes());
+    }
+}
+
+fn read_request(reader: &mut Cursor<&[u8]>) -> Result<u64, Error> {
+    match remaining(reader) {
+        0 => Ok(0),
+        _ => read_u64(reader),
+    }
+}
+
+fn read_bytes(reader: &mut Cursor<&[u8]>) -> Result<Vec<u8>, Error> {
+    let len = read_u32(reader)? as usize;
+    // Check before allocating, the length could be anything.
+    if len > remaining(reader) {
+        return Err(Error::FrameTruncated);
+    }
+    let mut bytes = vec![0u8; len];
+    truncated(reader.read_exact(&mut bytes))?;
+    Ok(bytes)
+}
+
+fn read_string(reader: &mut Cursor<&[u8]>) -> Result<String, Error> {
+    Error::from(String::from_utf8(read_bytes(reader)?))
+}
+
+fn read_option_string(reader: &mut Cursor<&[u8]>) -> Result<Option<String>, Error> {
+    match read_u8(reader)? {
+        0 => Ok(None),
+        1 => Ok(Some(read_string(reader)?)),
+        flag => Err(Error::SimpleErrorStr(format!(
+            "Decode Option: Unknown flag {flag}"
+        ))),
+    }
+}
+
+fn read_tree_change(reader: &mut Cursor<&[u8]>) -> Result<TreeChange, Error> {
+    match read_u8(reader)? {
+        0 => Ok(TreeChange::NodeAdded(
+            read_data(reader, 0)?,
+            read_option_string(reader)?,
+            read_uuid(reader)?,
+            read_uuid(reader)?,
+        )),
+        1 => Ok(TreeChange::NodeRemoved(read_uuid(reader)?)),
+        2 => Ok(TreeChange::NodeChangedName(
+            read_uuid(reader)?,
+            read_string(reader)?,
+        )),
+        3 => Ok(TreeChange::NodeChangedData(
+            read_uuid(reader)?,
+            read_data(reader, 0)?,
+        )),
+        4 => Ok(TreeChange::NodeChangedPermissions(
+            read_uuid(reader)?,
+            read_permissions(reader)?,
+        )),
+        5 => Ok(TreeChange::NodeMoved(
+            read_uuid(reader)?,
+            read_uuid(reader)?,
+            read_u64(reader)? as usize,
+        )),
+        tag => Err(Error::SimpleErrorStr(format!(
+            "Decode TreeChange: Unknown tag {tag}"
+        ))),
+    }
+}
+
+fn read_snapshot(reader: &mut Cursor<&[u8]>) -> Result<Snapshot, Error> {
+    let hash = read_u64(reader)?;
+    let len = read_u32(reader)? as usize;
+    // Every node needs at least its id and one byte for each other field.
+    if len.saturating_mul(20) > remaining(reader) {
+        return Err(Error::FrameTruncated);
+    }
+
+    let mut nodes = Vec::with_capacity(len);
+    for _ in 0..len {
+        let id = read_uuid(reader)?;
+        let parent_id = match read_u8(reader)? {
+            0 => None,
+            1 => Some(read_uuid(reader)?),
+            flag => {
+                return Err(Error::SimpleErrorStr(format!(
+                    "Decode Option: Unknown flag {flag}"
+                )));
+            }
+        };
+        nodes.push(NodeRecord {
+            id,
+            parent_id,
+            name: read_option_string(reader)?,
+            data: read_data(reader, 0)?,
+            permissions: read_permissions(reader)?,
+        });
+    }
+    Ok(Snapshot { hash, nodes })
+}
+
+fn read_permissions(reader: &mut Cursor<&[u8]>) -> Result<Permissions, Error> {
+    match read_u8(reader)? {
+        0 => Ok(Permissions::Admin),
+        1 => Ok(Permissions::User(None)),
+        2 => {
+            let len = read_u32(reader)? as usize;
+            // Every group needs at least its length prefix.
+            if len.saturating_mul(4) > remaining(reader) {
+                return Err(Error::FrameTruncated);
+            }
+            let mut groups = Vec::with_capacity(len);
+            for _ in 0..len {
+                groups.push(read_string(reader)?);
+            }
+            Ok(Permissions::User(Some(groups)))
+        }
+        3 => Ok(Permissions::Public),
+        tag => Err(Error::SimpleErrorStr(format!(
+            "Decode Permissions: Unknown tag {tag}"
+        ))),
+    }
+}
+
+fn read_data(reader: &mut Cursor<&[u8]>, depth: usize) -> Result<Data, Error> {
+    if depth > config::MAX_DATA_DEPTH {
+        return Err(Error::SimpleError("Decode Data: Nested too deep"));
+    }
+
+    let data = match read_u8(reader)? {
+        0 => Data::Folder,
+        1 => Data::Button(read_u64(reader)?),
+        2 => Data::Float32(truncated(reader.read_f32::<LittleEndian>())?),
+        3 => Data::Float64(truncated(reader.read_f64::<LittleEndian>())?),
+        4 => Data::Int32(truncated(reader.read_i32::<LittleEndian>())?),
+        5 => Data::Int64(truncated(reader.read_i64::<LittleEndian>())?),
+        6 => Data::UInt32(read_u32(reader)?),
+        7 => Data::UInt64(read_u64(reader)?),
+        8 => Data::String(read_string(reader)?),
+        9 => Data::Bool(read_u8(reader)? != 0),
+        10 => {
+            let n = read_u64(reader)? as usize;
+            let elements = read_data_list(reader, depth)?;
+            Data::Tuple(n, elements.into_boxed_slice())
+        }
+        11 => Data::List(Box::new(read_data_list(reader, depth)?)),
+        tag => {
+            return Err(Error::SimpleErrorStr(format!(
+                "Decode Data: Unknown tag {tag}"
+            )));
+        }
+    };
+    Ok(data)
+}
+
+fn read_data_list(reader: &mut Cursor<&[u8]>, depth: usize) -> Result<Vec<Data>, Error> {
+    let len = read_u32(reader)? as usize;
+    // Every element needs at least one byte.
+    if len > remaining(reader) {
+        return Err(Error::FrameTruncated);
+    }
+    let mut elements = Vec::with_capacity(len);
+    for _ in 0..len {
+        elements.push(read_data(reader, depth + 1)?);
+    }
+    Ok(elements)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use uuid::Uuid;
+
+    use crate::{
+        config,
+        datatypes::{Data, nodes::Node, snapshot::Snapshot, treebuilder::TreeChange},
+        errors::Error,
+        remote::{
+            codec::{FrameReader, decode, encode, encode_version, read_message, write_message},
+            message::{Message, NackCode},
+            version::Capabilities,
+        },
+        security::permissions::Permissions,
+    };
+
+    fn all_messages() -> Vec<Message> {
+        let data = Data::List(Box::new(vec![
+            Data::Folder,
+            Data::Button(3),
+            Data::Float32(1.5),
+            Data::Float64(-2.25),
+            Data::Int32(-7),
+            Data::Int64(i64::MIN),
+            Data::UInt32(7),
+            Data::UInt64(u64::MAX),
+            Data::String("hello".to_string()),
+            Data::Bool(true),
+            Data::Tuple(2, Box::new([Data::Int32(1), Data::String("a".to_string())])),
+        ]));
+
+        vec![
+            Message::ClientHello(config::CURRENT_VERSION, None, Capabilities::BATCHING),
+            Message::ClientHello(
+                config::CURRENT_VERSION,
+                Some((vec![55], vec![3])),
+                Capabilities::NONE,
+            ),
+            Message::ClientHello(1, None, Capabilities::NONE),
+            Message::ServerAuth(vec![1, 2, 3]),
+            Message::ClientAuth(vec![]),
+            Message::ServerAccept(42, 3600, Capabilities::COMPRESSION),
+            Message::ServerVersions(1, 2, Capabilities::NONE),
+            Message::ClientResume(config::CURRENT_VERSION, 42, 1234),
+            Message::ClientResume(1, 42, 1234),
+            Message::Ping(7),
+            Message::Pong(7),
+            Message::ClientKeyExchange(vec![9; 32]),
+            Message::ServerKeyExchange(vec![9; 32], vec![55], vec![3], vec![1, 2]),
+            Message::ServerRefreshPermissions,
+            Message::ServerChange(TreeChange::NodeAdded(
+                data.clone(),
+                Some("node".to_string()),
+                Uuid::from_u128(1),
+                Uuid::from_u128(2),
+            )),
+            Message::ServerChange(TreeChange::NodeAdded(
+                Data::Folder,
+                None,
+                Uuid::from_u128(1),
+                Uuid::from_u128(2),
+            )),
+            Message::ServerChange(TreeChange::NodeRemoved(Uuid::from_u128(3))),
+            Message::ServerChange(TreeChange::NodeChangedName(
+                Uuid::from_u128(4),
+                "new name".to_string(),
+            )),
+            Message::ServerChange(TreeChange::NodeChangedData(Uuid::from_u128(5), data)),
+            Message::ServerChange(TreeChange::NodeChangedPermissions(
+                Uuid::from_u128(5),
+                Permissions::User(Some(vec!["a".to_string(), "b".to_string()])),
+            )),
+            Message::ServerChange(TreeChange::NodeChangedPermissions(
+                Uuid::from_u128(5),
+                Permissions::Admin,
+            )),
+            Message::ServerChange(TreeChange::NodeMoved(
+                Uuid::from_u128(5),
+                Uuid::from_u128(6),
+                2,
+            )),
+            Message::ServerLog("log".to_string()),
+            Message::ServerGoodbye("bye".to_string()),
+            Message::ClientHash(1234),
+            Message::ClientTrigger(3, Uuid::from_u128(6)),
+            Message::ClientAddPermissions(4, vec![55], vec![3]),
+            Message::ServerAck(3),
+            Message::ServerNack(4, NackCode::AuthenticationFailed, "wrong key".to_string()),
+            Message::Snapshot(Snapshot::new(&Node::root().name("root").children(vec![
+                Node::new().data(Data::Int32(1)).id(Uuid::from_u128(7)),
+                Node::new()
+                    .name("admin")
+                    .permissions(Permissions::Admin)
+                    .id(Uuid::from_u128(8))
+                    .children(vec![Node::new().name("nested").id(Uuid::from_u128(9))]),
+            ]))),
+        ]
+    }
+
+    #[test]
+    fn roundtrip() {
+        for message in all_messages() {
+            let decoded = decode(&encode(&message).unwrap()).unwrap();
+            assert_eq!(format!("{:?}", message), format!("{:?}", decoded));
+        }
+    }
+
+    // Both versions are spoken at the same time, the older one without the newer fields.
+    #[test]
+    fn versions() {
+        let accept = Message::ServerAccept(42, 3600, Capabilities::NONE);
+        let old = encode_version(&accept, 1).unwrap();
+        assert_eq!(old.len() + 4, encode(&accept).unwrap().len());
+        assert!(matches!(
+            decode(&old),
+            Ok(Message::ServerAccept(42, 3600, Capabilities::NONE))
+        ));
+        let accept = Message::ServerAccept(42, 3600, Capabilities::BATCHING);
+        assert!(encode_version(&accept, 1).is_err());
+
+        let resume = encode_version(&Message::ClientResume(1, 42, 1234), 2).unwrap();
+        assert_eq!(resume.len(), 17);
+
+        // Older peers do not know heartbeats.
+        assert!(encode_version(&Message::Ping(1), 2).is_err());
+        assert!(encode_version(&Message::Pong(1), 3).is_ok());
+
+        // Older peers send requests without an id.
+        let trigger = encode_version(&Message::ClientTrigger(5, Uuid::from_u128(6)), 3).unwrap();
+        assert_eq!(trigger.len(), 17);
+        assert!(matches!(
+            decode(&trigger),
+            Ok(Message::ClientTrigger(0, id)) if id == Uuid::from_u128(6)
+        ));
+        assert!(encode_version(&Message::ServerAck(5), 3).is_err());
+        let moved = Message::ServerChange(TreeChange::NodeMoved(Uuid::nil(), Uuid::nil(), 0));
+        assert!(encode_version(&moved, 4).is_err());
+
+        // A newer hello is read as far as this version understands it.
+        let mut hello = encode(&Message::ClientHello(9, None, Capabilities::BATCHING)).unwrap();
+        hello.extend_from_slice(&[1, 2, 3]);
+        assert!(matches!(
+            decode(&hello),
+            Ok(Message::ClientHello(9, None, Capabilities::BATCHING))
+        ));
+        // Unlike an older one.
+        let mut hello = encode(&Message::ClientHello(2, None, Capabilities::NONE)).unwrap();
+        hello.push(1);
+        assert!(decode(&hello).is_err());
+    }
+
+    #[test]
+    fn stream() {
+        let mut buf = vec![];
+        for message in all_messages() {
+            write_message(&mut buf, &message).unwrap();
+        }
+
+        let mut reader = Cursor::new(buf);
+        for message in all_messages() {
+            let decoded = read_message(&mut reader).unwrap();
+            assert_eq!(format!("{:?}", message), format!("{:?}", decoded));
+        }
+        assert!(matches!(
+            read_message(&mut reader),
+            Err(Error::ConnectionClosed)
+        ));
+    }
+
+    #[test]
+    fn truncated() {
+        let mut buf = vec![];
+        write_message(&mut buf, &Message::ServerLog("hello".to_string())).unwrap();
+        buf.pop();
+
+        assert!(matches!(
+            read_message(&mut Cursor::new(buf.clone())),
+            Err(Error::FrameTruncated)
+        ));
+        assert!(matches!(
+            FrameReader::new().read(&mut Cursor::new(buf.clone())),
+            Err(Error::FrameTruncated)
+        ));
+        // The length inside the payload does not match.
+        assert!(matches!(decode(&buf[4..]), Err(Error::FrameTruncated)));
+    }
+
+    #[test]
+    fn too_large() {
+        let mut buf = ((config::MAX_FRAME_SIZE + 1) as u32).to_le_bytes().to_vec();
+        buf.push(4);
+
+        assert!(matches!(
+            read_message(&mut Cursor::new(buf.clone())),
+            Err(Error::FrameTooLarge(_))
+        ));
+        assert!(matches!(
+            FrameReader::new().read(&mut Cursor::new(buf)),
+            Err(Error::FrameTooLarge(_))
+        ));
+        assert!(matches!(
+            encode(&Message::ServerLog("a".repeat(config::MAX_FRAME_SIZE))),
+            Err(Error::FrameTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn too_deep() {
+        let mut data = Data::Folder;
+        for _ in 0..=config::MAX_DATA_DEPTH + 1 {
+            data = Data::List(Box::new(vec![data]));
+        }
+
+        let message = Message::ServerChange(TreeChange::NodeChangedData(Uuid::nil(), data));
+        assert!(encode(&message).is_err());
+    }
+
+    // Reads that time out in the middle of a frame must not lose the bytes read so far.
+    #[test]
+    fn partial_reads() {
+        struct Chunked {
+            data: Vec<u8>,
+            position: usize,
+            timed_out: bool,
+        }
+
+        impl std::io::Read for Chunked {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                self.timed_out = !self.timed_out;
+                if self.timed_out {
+                    return Err(std::io::ErrorKind::WouldBlock.into());
+                }
+                let n = 3.min(self.data.len() - self.position).min(buf.len());
+                buf[..n].copy_from_slice(&self.data[self.position..self.position + n]);
+                self.position += n;
+                Ok(n)
+            }
+        }
+
+        let mut data = vec![];
+        for message in all_messages() {
+            write_message(&mut data, &message).unwrap();
+        }
+        let mut reader = Chunked {
+            data,
+            position: 0,
+            timed_out: false,
+        };
+
+        let mut frames = FrameReader::new();
+        let mut decoded = vec![];
+        loop {
+            match frames.read(&mut reader) {
+                Ok(Some(message)) => decoded.push(format!("{:?}", message)),
+                Ok(None) => {}
+                Err(Error::ConnectionClosed) => break,
+                Err(err) => panic!("{:?}", err),
+            }
+        }
+
+        let expected: Vec<String> = all_messages()
+            .iter()
+            .map(|message| format!("{:?}", message))
+            .collect();
+        assert_eq!(decoded, expected);
+    }
+}
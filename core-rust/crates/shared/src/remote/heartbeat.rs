@@ -0,0 +1,84 @@
+// Notices connections that are open but dead, like when the other side lost power.
+// Both sides ping the other every interval and answer its pings with a pong. Anything the other
+// side sends counts as a sign of life. A connection that hears nothing for [Heartbeat::timeout] is
+// closed, even if TCP still thinks it is open.
+// Only peers that speak [crate::remote::version::HEARTBEAT_VERSION] know heartbeats.
+
+use std::time::{Duration, Instant};
+
+use crate::remote::message::Message;
+
+/// The heartbeat of one side of a connection.
+#[derive(Debug)]
+pub struct Heartbeat {
+    interval: Duration,
+    missed: u32,
+    last_seen: Instant,
+    last_ping: Instant,
+    counter: u64,
+}
+
+impl Heartbeat {
+    /// Pings every [interval] and gives up after [missed] intervals without a sign of life.
+    pub fn new(interval: Duration, missed: u32) -> Self {
+        Self {
+            interval,
+            missed,
+            last_seen: Instant::now(),
+            last_ping: Instant::now(),
+            counter: 0,
+        }
+    }
+
+    /// How long the other side may be quiet.
+    pub fn timeout(&self) -> Duration {
+        self.interval.saturating_mul(self.missed)
+    }
+
+    /// Has to be called for every message of the other side.
+    pub fn seen(&mut self) {
+        self.last_seen = Instant::now();
+    }
+
+    /// Returns the [Message::Ping] to send, once an interval passed since the last one.
+    pub fn ping(&mut self) -> Option<Message> {
+        if self.last_ping.elapsed() < self.interval {
+            return None;
+        }
+        self.last_ping = Instant::now();
+        self.counter += 1;
+        Some(Message::Ping(self.counter))
+    }
+
+    /// Returns [true] once the other side was quiet for longer than [Heartbeat::timeout].
+    pub fn is_dead(&self) -> bool {
+        self.last_seen.elapsed() > self.timeout()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{thread, time::Duration};
+
+    use crate::remote::{heartbeat::Heartbeat, message::Message};
+
+    #[test]
+    fn heartbeat() {
+        let mut heartbeat = Heartbeat::new(Duration::from_millis(20), 2);
+        assert_eq!(heartbeat.timeout(), Duration::from_millis(40));
+        assert!(heartbeat.ping().is_none());
+
+        thread::sleep(Duration::from_millis(25));
+        assert!(matches!(heartbeat.ping(), Some(Message::Ping(1))));
+        assert!(heartbeat.ping().is_none());
+        assert!(!heartbeat.is_dead());
+
+        thread::sleep(Duration::from_millis(25));
+        assert!(matches!(heartbeat.ping(), Some(Message::Ping(2))));
+        assert!(heartbeat.is_dead());
+
+        // Any message of the other side counts.
+        heartbeat.seen();
+        assert!(!heartbeat.is_dead());
+    }
+}
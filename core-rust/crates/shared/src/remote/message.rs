@@ -3,35 +3,110 @@
 use rsa::{BigUint, RsaPublicKey};
 use uuid::Uuid;
 
-use crate::{datatypes::treebuilder::TreeChange, errors::Error};
+use crate::{
+    datatypes::{snapshot::Snapshot, treebuilder::TreeChange},
+    errors::Error,
+    remote::version::{Capabilities, REQUEST_VERSION},
+};
 
 use serde::{Deserialize, Serialize};
 
 // All messages always
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Message {
-    ClientHello(u8, Option<(Vec<u8>, Vec<u8>)>), // version, optional public key. Functions as the
-    // clients certificate. 256 len of n and 3 for e
-    ServerAuth(Vec<u8>),    // nonce encrypted with public key.
-    ClientAuth(Vec<u8>),    // nonce decrypted
-    ServerAccept(u64, u16), // Is sent after authentication to start the loop. session number
-    // (random), session validaty time in seconds
+    ClientHello(u8, Option<(Vec<u8>, Vec<u8>)>, Capabilities), // version, optional public key.
+    // Functions as the clients certificate. 256 len of n and 3 for e. Capabilities of the client.
+    ServerAuth(Vec<u8>),                  // nonce encrypted with public key.
+    ClientAuth(Vec<u8>),                  // nonce decrypted
+    ServerAccept(u64, u16, Capabilities), // Is sent after authentication to start the loop.
+    // session number (random), session validaty time in seconds, capabilities both support.
     ServerRefreshPermissions,
     ServerChange(TreeChange), // This message is sent to communicate changes in the tree.
     ServerLog(String),        // Is send to inform client of succesffull button press or any erros.
     ClientHash(u64), // Sends the hash of the tree. If Server sees difference check saved hashes
     // and resend.
-    ClientTrigger(Uuid), // Tries to trigger a Button node.
-    ClientAddPermissions(Vec<u8>, Vec<u8>),
+    ClientTrigger(u64, Uuid), // request id, Button node to trigger. Answered with ServerAck or
+    // ServerNack since version 4.
+    ClientAddPermissions(u64, Vec<u8>, Vec<u8>), // request id, n and e of the key. The server
+    // answers with ServerAuth, the client proves to own the key with ClientAuth.
+    ServerGoodbye(String), // The server closes the connection, with the reason. The client
+    // should close it too.
+    ClientResume(u8, u64, u64), // Instead of ClientHello after a lost connection. version,
+    // session number, hash of the tree of the client. Answered with ServerAccept and the changes
+    // it missed.
+    ClientKeyExchange(Vec<u8>), // Ephemeral x25519 key of the client. Is sent before ClientHello
+    // to encrypt the frames with the protocol itself, see [crate::security::exchange].
+    ServerKeyExchange(Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>), // Ephemeral x25519 key of the server,
+    // n and e of its identity key and the signature of both ephemeral keys with it.
+    ServerVersions(u8, u8, Capabilities), // The lowest and highest version the server speaks and
+    // its capabilities. Answers a ClientHello with a version the server does not speak.
+    Ping(u64), // Sent by both sides every heartbeat interval, with a counter. Since version 3.
+    Pong(u64), // Answers a Ping with its counter.
+    ServerAck(u64), // The request with this id was done. Since version 4.
+    ServerNack(u64, NackCode, String), // The request with this id was refused, why and a message
+    // for humans.
+    Snapshot(Snapshot), // The whole tree. Sent after ServerAccept and when a client is out of sync.
+}
+
+/// Why the server refused a request, see [Message::ServerNack].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NackCode {
+    /// A code of a newer version, or a failure that has no code of its own.
+    Failed,
+    /// The node of the request does not exist.
+    NotFound,
+    /// The client cannot see the node.
+    PermissionDenied,
+    /// The node cannot be pressed.
+    NotAButton,
+    /// The client could not prove to own the key.
+    AuthenticationFailed,
+    /// The server cannot handle this kind of request.
+    Unsupported,
+}
+
+impl NackCode {
+    /// The code on the wire.
+    pub fn code(self) -> u16 {
+        match self {
+            NackCode::Failed => 0,
+            NackCode::NotFound => 1,
+            NackCode::PermissionDenied => 2,
+            NackCode::NotAButton => 3,
+            NackCode::AuthenticationFailed => 4,
+            NackCode::Unsupported => 5,
+        }
+    }
+
+    pub fn from_code(code: u16) -> NackCode {
+        match code {
+            1 => NackCode::NotFound,
+            2 => NackCode::PermissionDenied,
+            3 => NackCode::NotAButton,
+            4 => NackCode::AuthenticationFailed,
+            5 => NackCode::Unsupported,
+            _ => NackCode::Failed,
+        }
+    }
+}
+
+/// Refuses [request] of a peer that speaks [version]. Peers before [REQUEST_VERSION] cannot tell
+/// which request it was, they get a [Message::ServerLog] with [message] only.
+pub fn nack(version: u8, request: u64, code: NackCode, message: String) -> Message {
+    if version >= REQUEST_VERSION {
+        Message::ServerNack(request, code, message)
+    } else {
+        Message::ServerLog(message)
+    }
 }
 
 /// Helper Function to extract the RsaPublicKey from a message.
 /// Returns an error if the [ClientHello] has no certificate or is a different enum kind.
 pub fn client_hello_rsa_key(message: &Message) -> Result<RsaPublicKey, crate::errors::Error> {
     match message {
-        Message::ClientHello(_, Some((n, e))) => {
-            let n = BigUint::from_bytes_le(&n);
-            let e = BigUint::from_bytes_le(&e);
+        Message::ClientHello(_, Some((n, e)), _) => {
+            let n = BigUint::from_bytes_le(n);
+            let e = BigUint::from_bytes_le(e);
 
             let key = match RsaPublicKey::new(n.clone(), e.clone()) {
                 Ok(key) => key,
@@ -50,42 +125,51 @@ pub fn client_hello_rsa_key(message: &Message) -> Result<RsaPublicKey, crate::er
 
 /// Helper Function to check if a message is a [ClientHello] with a certificate.
 pub fn client_hello_has_rsa_key(message: &Message) -> bool {
-    match message {
-        Message::ClientHello(_, Some((_, _))) => true,
-        _ => false,
-    }
+    matches!(message, Message::ClientHello(_, Some((_, _)), _))
 }
 
 /// Implements serialiazation for the client hello world.
-mod client_hello {
-    use std::io::{BufReader, Cursor, Read};
+pub(crate) mod client_hello {
+    use std::io::Read;
+    #[cfg(test)]
+    use std::io::{BufReader, Cursor};
 
+    #[cfg(test)]
     use rand::thread_rng;
+    #[cfg(test)]
+    use rsa::RsaPrivateKey;
     use rsa::traits::PublicKeyParts;
-    use rsa::{BigUint, RsaPrivateKey, RsaPublicKey};
+    use rsa::{BigUint, RsaPublicKey};
 
-    use byteorder::ReadBytesExt;
+    use byteorder::{LittleEndian, ReadBytesExt};
 
     use crate::config;
     use crate::errors::Error;
+    use crate::remote::version::Capabilities;
 
     /// The version is saved in the first seven bits of version byte
     /// Last bit is used for a flag. CERTIFICATE_PRESENT = 1|0
     /// CERTIFICATE_PRESENT = 1 -> there is a certificate.
     /// CERTIFICATE_PRESENT = 0 -> there is not certificate meaning all 0 bits
+    /// So there can be at most 127 versions.
     ///
     ///
     /// byte 0: version and if there is a certificate.
     /// byte 1: len of n
     /// byte 2: len e
-    fn serialize(version: u8, certificate: RsaPublicKey) -> Vec<u8> {
+    /// Since version 2 the capabilities follow as u32.
+    pub(crate) fn serialize(
+        version: u8,
+        certificate: RsaPublicKey,
+        capabilities: Capabilities,
+    ) -> Result<Vec<u8>, Error> {
         let mut buf = vec![0; 4];
 
         buf[0] = (version << 1) | 0b1;
 
         // A quick check that I didn't change this in the config,
         // would then create problems as we assume n and e are combind max 259
-        assert!(config::RSA_KEY_SIZE == 2048);
+        const { assert!(config::RSA_KEY_SIZE == 2048) };
 
         let mut n = certificate.n().to_bytes_le();
         let mut e = certificate.e().to_bytes_le();
@@ -93,10 +177,15 @@ mod client_hello {
         // let mut n = BigUint::from(42u8).to_bytes_le();
         // let mut e = BigUint::from(6u8).to_bytes_le();
 
-        assert!(n.len() <= 256);
-        assert!(e.len() <= 3);
+        if n.len() > 256 || e.len() > 3 {
+            return Err(Error::SimpleErrorStr(format!(
+                "Serialize ClientHello: Key too large, n has {} bytes and e has {} bytes",
+                n.len(),
+                e.len()
+            )));
+        }
 
-        // Save casting as we asserted that both lens fit into u8
+        // Save casting as we checked that both lens fit
         let size_bytes = n.len().to_le_bytes();
         buf[1] = size_bytes[0];
         buf[2] = size_bytes[1];
@@ -104,17 +193,40 @@ mod client_hello {
 
         buf.append(&mut n);
         buf.append(&mut e);
+        serialize_capabilities(&mut buf, version, capabilities)?;
 
-        buf
+        Ok(buf)
     }
 
-    fn serialize_without_cert(version: u8) -> [u8; 1] {
-        [version << 1]
+    pub(crate) fn serialize_without_cert(
+        version: u8,
+        capabilities: Capabilities,
+    ) -> Result<Vec<u8>, Error> {
+        let mut buf = vec![version << 1];
+        serialize_capabilities(&mut buf, version, capabilities)?;
+        Ok(buf)
     }
 
-    fn deserialize<R: Read>(
-        reader: &mut BufReader<R>,
-    ) -> Result<(u8, Option<RsaPublicKey>), Error> {
+    fn serialize_capabilities(
+        buf: &mut Vec<u8>,
+        version: u8,
+        capabilities: Capabilities,
+    ) -> Result<(), Error> {
+        if version >= 2 {
+            buf.extend_from_slice(&capabilities.bits().to_le_bytes());
+        } else if !capabilities.is_empty() {
+            return Err(Error::SimpleError(
+                "Serialize ClientHello: Version 1 has no capabilities",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Reads the hello of every version. Hellos of versions after [config::CURRENT_VERSION] may
+    /// have more fields after the capabilities, the caller has to skip them.
+    pub(crate) fn deserialize<R: Read>(
+        reader: &mut R,
+    ) -> Result<(u8, Option<RsaPublicKey>, Capabilities), Error> {
         // Helper function to use ? syntax
         fn reader_error<T>(
             res: Result<T, std::io::Error>,
@@ -133,15 +245,15 @@ mod client_hello {
 
         let version = (version_and_has_cert & 0b11111110) >> 1;
 
-        if version != config::CURRENT_VERSION {
-            return Err(Error::SimpleErrorStr(format!(
-                "Deserialize ClientHello: Wrong version: current {}, got: {version}",
-                config::CURRENT_VERSION
-            )));
+        // Other versions are negotiated, see [crate::remote::version].
+        if version == 0 {
+            return Err(Error::SimpleError(
+                "Deserialize ClientHello: There is no version 0",
+            ));
         }
         let has_cert = version_and_has_cert & 0b1;
 
-        if has_cert > 0 {
+        let key = if has_cert > 0 {
             // size of n component of RsaPublicKey
             // Is a u16
 
@@ -187,38 +299,54 @@ mod client_hello {
                 }
             };
 
-            Ok((version, Some(key)))
+            Some(key)
         } else {
-            Ok((version, None))
-        }
+            None
+        };
+
+        let capabilities = match version {
+            1 => Capabilities::NONE,
+            _ => Capabilities::from_bits(reader_error(
+                reader.read_u32::<LittleEndian>(),
+                "Deserialize ClientHello: cannot parse capabilities.",
+            )?),
+        };
+        Ok((version, key, capabilities))
     }
 
     #[test]
     fn test() {
         let n = 55u32; // modulus
         let e = 3u32; // public exponent
-        let d = 27u32; // private exponent
 
         // Construct BigUint from u32
         let n = BigUint::from(n);
         let e = BigUint::from(e);
-        let d = BigUint::from(d);
-
-        // Small primes p and q that multiply to n (not strictly needed to encrypt/decrypt here)
-        let p = BigUint::from(5u32);
-        let q = BigUint::from(11u32);
 
         // Construct public key
         let public_key = RsaPublicKey::new(n, e).unwrap();
 
-        let serialized = serialize(1, public_key.clone());
+        let serialized = serialize(1, public_key.clone(), Capabilities::NONE).unwrap();
         assert_eq!(serialized[0], 0b11);
 
         let mut reader = BufReader::new(Cursor::new(serialized));
-        let (version, public_key2) = deserialize(&mut reader).unwrap();
+        let (version, public_key2, capabilities) = deserialize(&mut reader).unwrap();
 
         assert_eq!(version, 1);
         assert_eq!(public_key, public_key2.unwrap());
+        assert!(capabilities.is_empty());
+
+        // Version 1 has no capabilities to send them with.
+        assert!(serialize_without_cert(1, Capabilities::BATCHING).is_err());
+        let serialized = serialize_without_cert(2, Capabilities::BATCHING).unwrap();
+        assert_eq!(serialized.len(), 5);
+        let mut reader = BufReader::new(Cursor::new(serialized));
+        let (version, key, capabilities) = deserialize(&mut reader).unwrap();
+        assert_eq!(version, 2);
+        assert!(key.is_none());
+        assert_eq!(capabilities, Capabilities::BATCHING);
+
+        assert!(deserialize(&mut BufReader::new(Cursor::new([0u8]))).is_err());
     }
 
     #[test]
@@ -227,11 +355,11 @@ mod client_hello {
         let private_key = RsaPrivateKey::new(&mut rng, config::RSA_KEY_SIZE).unwrap();
         let public_key = RsaPublicKey::from(private_key);
 
-        let serialized = serialize(1, public_key.clone());
+        let serialized = serialize(1, public_key.clone(), Capabilities::NONE).unwrap();
         assert_eq!(serialized[0], 0b11);
 
         let mut reader = BufReader::new(Cursor::new(serialized));
-        let (version, public_key2) = deserialize(&mut reader).unwrap();
+        let (version, public_key2, _) = deserialize(&mut reader).unwrap();
 
         assert_eq!(version, 1);
         assert_eq!(public_key, public_key2.unwrap());
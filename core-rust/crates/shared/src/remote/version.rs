@@ -0,0 +1,104 @@
+// The versions of the protocol and the optional features both sides agree on.
+//
+// A client says hello with the highest version it speaks. A server that does not speak it answers
+// with [crate::remote::message::Message::ServerVersions], the range it speaks, and the client says
+// hello again with the highest version both speak. The capabilities in the hello are what the
+// client supports, the server accepts with the ones both support.
+//
+// Version 1: The first version, without capabilities.
+// Version 2: [crate::remote::message::Message::ClientHello] and
+//            [crate::remote::message::Message::ServerAccept] carry capabilities and
+//            [crate::remote::message::Message::ClientResume] carries the version.
+// Version 3: [crate::remote::message::Message::Ping] and [crate::remote::message::Message::Pong]
+//            heartbeats, see [crate::remote::heartbeat].
+// Version 4: [crate::remote::message::Message::ClientTrigger] and
+//            [crate::remote::message::Message::ClientAddPermissions] carry a request id, which the
+//            server answers with [crate::remote::message::Message::ServerAck] or
+//            [crate::remote::message::Message::ServerNack]. Older clients get a ServerLog instead.
+// Version 5: [crate::datatypes::treebuilder::TreeChange::NodeMoved] moves nodes. Older clients get
+//            the node removed and added again instead.
+
+use serde::{Deserialize, Serialize};
+
+/// A set of optional features of the protocol. Each feature is a bit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities(0);
+    /// Frames may be compressed. Is not used by this version of the crate yet.
+    pub const COMPRESSION: Capabilities = Capabilities(1 << 0);
+    /// Several changes may be sent in a single frame. Is not used by this version of the crate
+    /// yet.
+    pub const BATCHING: Capabilities = Capabilities(1 << 1);
+
+    /// The capabilities this version of the crate supports.
+    pub const fn supported() -> Capabilities {
+        Capabilities::NONE
+    }
+
+    /// Keeps unknown bits, so a newer peer can be told what it asked for.
+    pub const fn from_bits(bits: u32) -> Capabilities {
+        Capabilities(bits)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub const fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub const fn union(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+
+    /// The capabilities both sides support.
+    pub const fn intersection(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+/// The first version with heartbeats. Older peers are never pinged.
+pub const HEARTBEAT_VERSION: u8 = 3;
+
+/// The first version with request ids. Older peers send every request with id 0.
+pub const REQUEST_VERSION: u8 = 4;
+
+/// The first version that can move nodes.
+pub const MOVE_VERSION: u8 = 5;
+
+/// Returns the highest version in both ranges (min, max), if there is one.
+pub fn highest_common(ours: (u8, u8), theirs: (u8, u8)) -> Option<u8> {
+    let highest = ours.1.min(theirs.1);
+    if highest >= ours.0.max(theirs.0) {
+        Some(highest)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::remote::version::{Capabilities, highest_common};
+
+    #[test]
+    fn negotiate() {
+        assert_eq!(highest_common((1, 2), (1, 2)), Some(2));
+        assert_eq!(highest_common((1, 2), (1, 1)), Some(1));
+        assert_eq!(highest_common((2, 5), (1, 3)), Some(3));
+        assert_eq!(highest_common((2, 2), (1, 1)), None);
+
+        let ours = Capabilities::COMPRESSION.union(Capabilities::BATCHING);
+        let theirs = Capabilities::from_bits(Capabilities::BATCHING.bits() | 1 << 31);
+        assert_eq!(ours.intersection(theirs), Capabilities::BATCHING);
+        assert!(ours.contains(Capabilities::COMPRESSION));
+        assert!(!theirs.contains(Capabilities::COMPRESSION));
+        assert!(ours.intersection(Capabilities::NONE).is_empty());
+    }
+}
@@ -0,0 +1,415 @@
+// The key exchange that lets the protocol encrypt its frames itself, with or without TLS below it.
+//
+// Client                                            Server
+// ClientKeyExchange(ephemeral key)            ->
+//                                             <-    ServerKeyExchange(ephemeral key, identity key,
+//                                                   signature of both ephemeral keys)
+//
+// The ephemeral keys are x25519 keys that are only used for this connection. The server signs
+// them with its RSA identity key, which the client knows by its fingerprint. Both sides derive a
+// key for each direction from the shared secret, every frame afterwards is sealed with
+// ChaCha20-Poly1305 by a [SecureChannel]. Then the normal handshake follows, in which a client
+// key only signs the challenge of the server together with the [Binding] of the channel.
+// RSA keys never encrypt anything here, so recorded connections stay secret even if an identity
+// key is stolen later.
+
+use std::{fs, path::Path};
+
+use rand::thread_rng;
+use ring::{
+    aead::{self, Aad, CHACHA20_POLY1305, LessSafeKey, Nonce, UnboundKey},
+    hkdf::{self, HKDF_SHA256, Salt},
+};
+use rsa::{
+    BigUint, Pss, RsaPrivateKey, RsaPublicKey, pkcs8::DecodePrivateKey, traits::PublicKeyParts,
+};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{EphemeralSecret, PublicKey, SharedSecret};
+
+use crate::{errors::Error, remote::message::Message, security::fingerprint::fingerprint};
+
+/// How many bytes sealing adds to a frame.
+pub const TAG_SIZE: usize = 16;
+
+/// Identifies a [SecureChannel]. Signing it together with a challenge proves that the owner of a
+/// key is at the other end of this channel and not just relaying it.
+pub type Binding = [u8; 32];
+
+/// The client side of the key exchange.
+pub struct ClientExchange {
+    secret: EphemeralSecret,
+    public: PublicKey,
+    server: String,
+}
+
+impl ClientExchange {
+    /// Creates the exchange with a server whose identity key has the fingerprint [server], see
+    /// [fingerprint].
+    pub fn new(server: impl Into<String>) -> Self {
+        let secret = EphemeralSecret::random_from_rng(thread_rng());
+        let public = PublicKey::from(&secret);
+        Self {
+            secret,
+            public,
+            server: server.into(),
+        }
+    }
+
+    /// Returns the [Message::ClientKeyExchange] that starts the exchange.
+    pub fn hello(&self) -> Message {
+        Message::ClientKeyExchange(self.public.as_bytes().to_vec())
+    }
+
+    /// Checks the answer of the server and creates the channel for the frames that follow.
+    pub fn finish(self, message: Message) -> Result<SecureChannel, Error> {
+        let (server_key, n, e, signature) = match message {
+            Message::ServerKeyExchange(server_key, n, e, signature) => {
+                (server_key, n, e, signature)
+            }
+            // The server explains why the connection is refused.
+            Message::ServerLog(log) => {
+                return Err(Error::SimpleErrorStr(format!(
+                    "Key exchange: Server refused connection: {log}"
+                )));
+            }
+            message => {
+                return Err(Error::SimpleErrorStr(format!(
+                    "Key exchange: Unexpected message {:?}",
+                    message
+                )));
+            }
+        };
+
+        let identity = Error::from(RsaPublicKey::new(
+            BigUint::from_bytes_le(&n),
+            BigUint::from_bytes_le(&e),
+        ))?;
+        let found = fingerprint(&identity)?;
+        if found != self.server {
+            return Err(Error::SimpleErrorStr(format!(
+                "Key exchange: Server has the identity {found} instead of {}",
+                self.server
+            )));
+        }
+
+        let client_key = *self.public.as_bytes();
+        let server_key = ephemeral_key(&server_key)?;
+        let signed = signed_keys(&client_key, &server_key);
+        if identity
+            .verify(Pss::new::<Sha256>(), &signed, &signature)
+            .is_err()
+        {
+            return Err(Error::SimpleError(
+                "Key exchange: Server signed the keys wrong",
+            ));
+        }
+
+        let shared = self.secret.diffie_hellman(&PublicKey::from(server_key));
+        SecureChannel::new(&shared, &client_key, &server_key, true)
+    }
+}
+
+/// The server side of the key exchange. Signs the exchange with the identity key of the server.
+pub struct ServerExchange {
+    identity: RsaPrivateKey,
+}
+
+impl ServerExchange {
+    pub fn new(identity: RsaPrivateKey) -> Self {
+        Self { identity }
+    }
+
+    /// Loads the identity key from a PEM file in PKCS#8 format.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let pem = match fs::read_to_string(path) {
+            Ok(pem) => pem,
+            Err(err) => {
+                return Err(Error::SimpleErrorStr(format!(
+                    "Key exchange: Couldnt read {}: {:?}",
+                    path.display(),
+                    err
+                )));
+            }
+        };
+        match RsaPrivateKey::from_pkcs8_pem(&pem) {
+            Ok(identity) => Ok(Self::new(identity)),
+            Err(err) => Err(Error::SimpleErrorStr(format!(
+                "Key exchange: Couldnt read a PKCS#8 RSA key from {}: {:?}",
+                path.display(),
+                err
+            ))),
+        }
+    }
+
+    /// The fingerprint clients check the identity of the server with.
+    pub fn fingerprint(&self) -> Result<String, Error> {
+        fingerprint(&self.identity.to_public_key())
+    }
+
+    /// Answers the [Message::ClientKeyExchange] of a client and creates the channel for the
+    /// frames that follow.
+    pub fn answer(&self, message: &Message) -> Result<(Message, SecureChannel), Error> {
+        let client_key = match message {
+            Message::ClientKeyExchange(client_key) => ephemeral_key(client_key)?,
+            message => {
+                return Err(Error::SimpleErrorStr(format!(
+                    "Key exchange: Unexpected message {:?}",
+                    message
+                )));
+            }
+        };
+
+        let secret = EphemeralSecret::random_from_rng(thread_rng());
+        let server_key = *PublicKey::from(&secret).as_bytes();
+        let signed = signed_keys(&client_key, &server_key);
+        let signature = Error::from(self.identity.sign_with_rng(
+            &mut thread_rng(),
+            Pss::new::<Sha256>(),
+            &signed,
+        ))?;
+
+        let shared = secret.diffie_hellman(&PublicKey::from(client_key));
+        let channel = SecureChannel::new(&shared, &client_key, &server_key, false)?;
+        let answer = Message::ServerKeyExchange(
+            server_key.to_vec(),
+            self.identity.n().to_bytes_le(),
+            self.identity.e().to_bytes_le(),
+            signature,
+        );
+        Ok((answer, channel))
+    }
+}
+
+/// Seals and opens the frames of a connection after the key exchange.
+pub struct SecureChannel {
+    sending: Direction,
+    recieving: Direction,
+    binding: Binding,
+}
+
+impl SecureChannel {
+    // Derives the keys of both directions from the exchange. [is_client] decides which one is
+    // used for sending.
+    fn new(
+        shared: &SharedSecret,
+        client_key: &[u8; 32],
+        server_key: &[u8; 32],
+        is_client: bool,
+    ) -> Result<Self, Error> {
+        // An ephemeral key of a small order would make the secret known to everyone.
+        if !shared.was_contributory() {
+            return Err(Error::SimpleError("Key exchange: Ephemeral key is invalid"));
+        }
+
+        let transcript = [client_key.as_slice(), server_key.as_slice()].concat();
+        let secret = Salt::new(HKDF_SHA256, &transcript).extract(shared.as_bytes());
+        let to_server = Direction::new(&secret, b"fscp client to server")?;
+        let to_client = Direction::new(&secret, b"fscp server to client")?;
+
+        let binding = Sha256::new()
+            .chain_update(b"fscp binding")
+            .chain_update(&transcript)
+            .finalize()
+            .into();
+        let (sending, recieving) = match is_client {
+            true => (to_server, to_client),
+            false => (to_client, to_server),
+        };
+        Ok(Self {
+            sending,
+            recieving,
+            binding,
+        })
+    }
+
+    /// Encrypts and authenticates the payload of the next frame that is sent.
+    pub fn seal(&mut self, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut sealed = payload.to_vec();
+        let nonce = self.sending.next_nonce()?;
+        Error::from(
+            self.sending
+                .key
+                .seal_in_place_append_tag(nonce, Aad::empty(), &mut sealed),
+        )?;
+        Ok(sealed)
+    }
+
+    /// Checks and decrypts the payload of the next frame that is recieved.
+    /// Fails for frames that were changed, replayed, reordered or are from another channel.
+    pub fn open(&mut self, sealed: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut payload = sealed.to_vec();
+        let nonce = self.recieving.next_nonce()?;
+        let len = match self
+            .recieving
+            .key
+            .open_in_place(nonce, Aad::empty(), &mut payload)
+        {
+            Ok(opened) => opened.len(),
+            Err(_) => return Err(Error::SimpleError("Secure channel: Frame is not authentic")),
+        };
+        payload.truncate(len);
+        Ok(payload)
+    }
+
+    pub fn binding(&self) -> &Binding {
+        &self.binding
+    }
+}
+
+// The key of one direction of a [SecureChannel]. The nonce of each frame is its number, so no
+// nonce is used twice with a key.
+struct Direction {
+    key: LessSafeKey,
+    frames: u64,
+}
+
+impl Direction {
+    fn new(secret: &hkdf::Prk, label: &'static [u8]) -> Result<Self, Error> {
+        let labels = [label];
+        let key = Error::from(secret.expand(&labels, &CHACHA20_POLY1305))?;
+        Ok(Self {
+            key: LessSafeKey::new(UnboundKey::from(key)),
+            frames: 0,
+        })
+    }
+
+    fn next_nonce(&mut self) -> Result<Nonce, Error> {
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        nonce[4..].copy_from_slice(&self.frames.to_le_bytes());
+        self.frames = match self.frames.checked_add(1) {
+            Some(frames) => frames,
+            None => return Err(Error::SimpleError("Secure channel: Too many frames")),
+        };
+        Ok(Nonce::assume_unique_for_key(nonce))
+    }
+}
+
+fn ephemeral_key(bytes: &[u8]) -> Result<[u8; 32], Error> {
+    match bytes.try_into() {
+        Ok(key) => Ok(key),
+        Err(_) => Err(Error::SimpleErrorStr(format!(
+            "Key exchange: Ephemeral key has {} bytes instead of 32",
+            bytes.len()
+        ))),
+    }
+}
+
+// What the server signs with its identity key.
+fn signed_keys(client_key: &[u8; 32], server_key: &[u8; 32]) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(b"fscp server key exchange")
+        .chain_update(client_key)
+        .chain_update(server_key)
+        .finalize()
+        .into()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{os::unix::net::UnixStream, thread};
+
+    use rand::thread_rng;
+    use rsa::RsaPrivateKey;
+
+    use crate::{
+        remote::{codec::FramedStream, message::Message},
+        security::exchange::{ClientExchange, SecureChannel, ServerExchange},
+    };
+
+    // Large enough to sign with PSS and SHA-256, small enough to keep the tests fast.
+    fn server() -> ServerExchange {
+        ServerExchange::new(RsaPrivateKey::new(&mut thread_rng(), 1024).unwrap())
+    }
+
+    fn channels(server: &ServerExchange) -> (SecureChannel, SecureChannel) {
+        let client = ClientExchange::new(server.fingerprint().unwrap());
+        let (answer, server_channel) = server.answer(&client.hello()).unwrap();
+        (client.finish(answer).unwrap(), server_channel)
+    }
+
+    #[test]
+    fn seal() {
+        let server = server();
+        let (mut client, mut server_channel) = channels(&server);
+        assert_eq!(client.binding(), server_channel.binding());
+
+        let first = client.seal(b"first").unwrap();
+        let second = client.seal(b"first").unwrap();
+        assert_ne!(first, second);
+        assert!(!first.windows(5).any(|bytes| bytes == b"first"));
+
+        // Frames are opened in the order they were sealed, once.
+        assert!(server_channel.open(&second).is_err());
+        let (mut client, mut server_channel) = channels(&server);
+        let first = client.seal(b"first").unwrap();
+        assert_eq!(server_channel.open(&first).unwrap(), b"first");
+        assert!(server_channel.open(&first).is_err());
+
+        let (mut client, mut server_channel) = channels(&server);
+        let mut changed = client.seal(b"first").unwrap();
+        changed[0] ^= 1;
+        assert!(server_channel.open(&changed).is_err());
+
+        // The other direction has its own key.
+        let (mut client, _) = channels(&server);
+        let sealed = client.seal(b"to server").unwrap();
+        assert!(client.open(&sealed).is_err());
+    }
+
+    #[test]
+    fn wrong_server() {
+        let server = server();
+        let client = ClientExchange::new(self::server().fingerprint().unwrap());
+        let (answer, _) = server.answer(&client.hello()).unwrap();
+        assert!(client.finish(answer).is_err());
+
+        // The identity is right, but the ephemeral key was swapped.
+        let client = ClientExchange::new(server.fingerprint().unwrap());
+        let answer = match server.answer(&client.hello()).unwrap() {
+            (Message::ServerKeyExchange(_, n, e, signature), _) => {
+                Message::ServerKeyExchange(vec![9; 32], n, e, signature)
+            }
+            (message, _) => panic!("Expected ServerKeyExchange got {:?}", message),
+        };
+        assert!(client.finish(answer).is_err());
+
+        let client = ClientExchange::new(server.fingerprint().unwrap());
+        let refused = client.finish(Message::ServerLog("Server is full".to_string()));
+        assert!(refused.is_err());
+        assert!(
+            server
+                .answer(&Message::ClientKeyExchange(vec![1; 31]))
+                .is_err()
+        );
+    }
+
+    // The exchange works over any stream, not only TCP.
+    #[test]
+    fn framed_stream() {
+        let (client_socket, server_socket) = UnixStream::pair().unwrap();
+        let server = server();
+        let client = ClientExchange::new(server.fingerprint().unwrap());
+
+        let serving = thread::spawn(move || {
+            let mut stream = FramedStream::new(server_socket);
+            let hello = stream.recv().unwrap().unwrap();
+            let (answer, channel) = server.answer(&hello).unwrap();
+            stream.send(&answer).unwrap();
+            stream.secure(channel);
+
+            match stream.recv().unwrap().unwrap() {
+                Message::ClientHash(hash) => stream.send(&Message::ClientHash(hash + 1)).unwrap(),
+                message => panic!("Expected ClientHash got {:?}", message),
+            }
+        });
+
+        let mut stream = FramedStream::new(client_socket);
+        stream.send(&client.hello()).unwrap();
+        let channel = client.finish(stream.recv().unwrap().unwrap()).unwrap();
+        stream.secure(channel);
+        stream.send(&Message::ClientHash(41)).unwrap();
+        assert!(matches!(stream.recv(), Ok(Some(Message::ClientHash(42)))));
+        serving.join().unwrap();
+    }
+}
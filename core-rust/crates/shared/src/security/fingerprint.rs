@@ -0,0 +1,14 @@
+use rsa::{RsaPublicKey, pkcs8::EncodePublicKey};
+use sha2::{Digest, Sha256};
+
+use crate::errors::Error;
+
+/// Returns the fingerprint of a public key. This is the SHA-256 hash of the DER encoded key as
+/// lowercase hex. It is used to identify clients, for example in the credential store of the
+/// server.
+pub fn fingerprint(key: &RsaPublicKey) -> Result<String, Error> {
+    let der = Error::from(key.to_public_key_der())?;
+    let hash = Sha256::digest(der.as_bytes());
+
+    Ok(hash.iter().map(|byte| format!("{byte:02x}")).collect())
+}
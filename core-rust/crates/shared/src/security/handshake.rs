@@ -0,0 +1,664 @@
+// State machines for both sides of the handshake.
+//
+// Client                                  Server
+// ClientHello(version, Some(key))  ->
+//                                  <-     ServerAuth(nonce encrypted with key)
+// ClientAuth(nonce)                ->
+//                                  <-     ServerAccept(session, validity)
+//
+// A client without a key sends ClientHello(version, None) and is accepted directly.
+// A server that does not speak the version of the hello answers with ServerVersions(min, max)
+// instead, then the client says hello again with the highest version both speak. See
+// [crate::remote::version].
+// A client that lost its connection sends ClientResume(session, hash) instead of ClientHello. The
+// server accepts it with the same session, if it still has it, without authenticating it again.
+// After the key exchange of [crate::security::exchange] both sides are bound to its channel. Then
+// the nonce is sent as it is and the client signs it together with the [Binding] instead, so the
+// key of the client never decrypts anything.
+// Both sides only do the bookkeeping, sending and recieving is done by the caller.
+
+use std::time::{Duration, Instant};
+
+use rand::{RngCore, thread_rng};
+use rsa::{Pkcs1v15Encrypt, Pss, RsaPrivateKey, RsaPublicKey, traits::PublicKeyParts};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::{
+    config,
+    errors::Error,
+    remote::{
+        message::{Message, client_hello_rsa_key},
+        version::{Capabilities, highest_common},
+    },
+    security::exchange::Binding,
+};
+
+enum ServerState {
+    WaitingForHello,
+    WaitingForAuth(RsaPublicKey, Vec<u8>), // key of the client and the nonce sent to it.
+    Accepted(Option<RsaPublicKey>),
+    Failed,
+}
+
+/// The server side of the handshake.
+pub struct ServerHandshake {
+    state: ServerState,
+    session: u64,
+    validity: u16,
+    versions: (u8, u8),
+    capabilities: Capabilities,
+    // What the client said hello with.
+    version: u8,
+    negotiated: Capabilities,
+    binding: Option<Binding>,
+    started: Instant,
+    timeout: Duration,
+}
+
+impl ServerHandshake {
+    /// Creates the handshake for a new client. [session] and [validity] are sent to the client
+    /// when it is accepted. The whole handshake has to be done within [timeout].
+    pub fn new(session: u64, validity: u16, timeout: Duration) -> Self {
+        Self {
+            state: ServerState::WaitingForHello,
+            session,
+            validity,
+            versions: (config::MIN_VERSION, config::CURRENT_VERSION),
+            capabilities: Capabilities::supported(),
+            version: config::CURRENT_VERSION,
+            negotiated: Capabilities::NONE,
+            binding: None,
+            started: Instant::now(),
+            timeout,
+        }
+    }
+
+    /// Sets the lowest and highest version the server speaks. By default this is every version
+    /// this crate speaks.
+    pub fn versions(mut self, min: u8, max: u8) -> Self {
+        self.versions = (min, max);
+        self
+    }
+
+    /// Sets the capabilities the server supports. By default [Capabilities::supported].
+    pub fn capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Binds the handshake to the channel of a key exchange. The client then signs the nonce.
+    pub fn bind(mut self, binding: Binding) -> Self {
+        self.binding = Some(binding);
+        self
+    }
+
+    /// Handles the next message of the client and returns the answer for it.
+    /// After an error the handshake is failed and every further message is an error.
+    pub fn handle(&mut self, message: &Message) -> Result<Message, Error> {
+        let state = std::mem::replace(&mut self.state, ServerState::Failed);
+
+        if self.is_timed_out() {
+            return Err(Error::SimpleError("Handshake: Timed out"));
+        }
+
+        match (state, message) {
+            (ServerState::WaitingForHello, Message::ClientHello(version, _, _))
+                if !(self.versions.0..=self.versions.1).contains(version) =>
+            {
+                // The client may say hello again in a version the server speaks.
+                self.state = ServerState::WaitingForHello;
+                let (min, max) = self.versions;
+                Ok(Message::ServerVersions(min, max, self.capabilities))
+            }
+            (ServerState::WaitingForHello, Message::ClientHello(version, None, capabilities)) => {
+                self.version = *version;
+                self.negotiated = capabilities.intersection(self.capabilities);
+                self.state = ServerState::Accepted(None);
+                Ok(self.accept())
+            }
+            (
+                ServerState::WaitingForHello,
+                Message::ClientHello(version, Some(_), capabilities),
+            ) => {
+                self.version = *version;
+                self.negotiated = capabilities.intersection(self.capabilities);
+                let key = client_hello_rsa_key(message)?;
+
+                let mut nonce = vec![0u8; config::NONCE_SIZE];
+                thread_rng().fill_bytes(&mut nonce);
+                let challenge = match self.binding {
+                    Some(_) => nonce.clone(),
+                    None => Error::from(key.encrypt(&mut thread_rng(), Pkcs1v15Encrypt, &nonce))?,
+                };
+
+                self.state = ServerState::WaitingForAuth(key, nonce);
+                Ok(Message::ServerAuth(challenge))
+            }
+            (ServerState::WaitingForAuth(key, nonce), Message::ClientAuth(response)) => {
+                let proven = match &self.binding {
+                    Some(binding) => key
+                        .verify(
+                            Pss::new::<Sha256>(),
+                            &signed_challenge(binding, &nonce),
+                            response,
+                        )
+                        .is_ok(),
+                    // Compare in constant time, so the timing does not tell how much was right.
+                    None => nonce.ct_eq(response).into(),
+                };
+                if proven {
+                    self.state = ServerState::Accepted(Some(key));
+                    Ok(self.accept())
+                } else {
+                    Err(Error::SimpleError("Handshake: Client sent the wrong nonce"))
+                }
+            }
+            (ServerState::Accepted(_), message) => Err(Error::SimpleErrorStr(format!(
+                "Handshake: Already accepted but got {:?}",
+                message
+            ))),
+            (ServerState::Failed, _) => Err(Error::SimpleError("Handshake: Already failed")),
+            (_, message) => Err(Error::SimpleErrorStr(format!(
+                "Handshake: Unexpected message {:?}",
+                message
+            ))),
+        }
+    }
+
+    /// Returns [true] once the client is accepted.
+    pub fn is_accepted(&self) -> bool {
+        matches!(self.state, ServerState::Accepted(_))
+    }
+
+    /// Returns [true] if the handshake took longer than its timeout.
+    pub fn is_timed_out(&self) -> bool {
+        self.started.elapsed() > self.timeout
+    }
+
+    /// The key the client proved to own. [None] if the client is not accepted or anonymous.
+    pub fn public_key(&self) -> Option<&RsaPublicKey> {
+        match &self.state {
+            ServerState::Accepted(key) => key.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn session(&self) -> u64 {
+        self.session
+    }
+
+    /// The version the client said hello with. Everything sent to it has to be in this version.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// The capabilities both sides support.
+    pub fn negotiated(&self) -> Capabilities {
+        self.negotiated
+    }
+
+    fn accept(&self) -> Message {
+        Message::ServerAccept(self.session, self.validity, self.negotiated)
+    }
+}
+
+enum ClientState {
+    Start,
+    WaitingForAuth,
+    WaitingForAccept,
+    Resuming(u64),      // session
+    Accepted(u64, u16), // session, validity
+    Failed,
+}
+
+/// The client side of the handshake.
+pub struct ClientHandshake {
+    state: ClientState,
+    key: Option<RsaPrivateKey>,
+    versions: (u8, u8),
+    // The version the client says hello with.
+    version: u8,
+    capabilities: Capabilities,
+    negotiated: Capabilities,
+    binding: Option<Binding>,
+    started: Instant,
+    timeout: Duration,
+}
+
+impl ClientHandshake {
+    /// Creates the handshake. With a [key] the client authenticates, without it is anonymous.
+    /// The whole handshake has to be done within [timeout].
+    pub fn new(key: Option<RsaPrivateKey>, timeout: Duration) -> Self {
+        Self {
+            state: ClientState::Start,
+            key,
+            versions: (config::MIN_VERSION, config::CURRENT_VERSION),
+            version: config::CURRENT_VERSION,
+            capabilities: Capabilities::supported(),
+            negotiated: Capabilities::NONE,
+            binding: None,
+            started: Instant::now(),
+            timeout,
+        }
+    }
+
+    /// Sets the lowest and highest version the client speaks. It says hello with the highest.
+    /// By default this is every version this crate speaks.
+    pub fn versions(mut self, min: u8, max: u8) -> Self {
+        self.versions = (min, max);
+        self.version = max;
+        self
+    }
+
+    /// Sets the capabilities the client supports. By default [Capabilities::supported].
+    pub fn capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Binds the handshake to the channel of a key exchange, like [ServerHandshake::bind].
+    pub fn bind(mut self, binding: Binding) -> Self {
+        self.binding = Some(binding);
+        self
+    }
+
+    /// Returns the [Message::ClientHello] that starts the handshake.
+    pub fn hello(&mut self) -> Message {
+        self.state = ClientState::WaitingForAuth;
+        self.started = Instant::now();
+        self.hello_message()
+    }
+
+    fn hello_message(&self) -> Message {
+        let certificate = self
+            .key
+            .as_ref()
+            .map(|key| (key.n().to_bytes_le(), key.e().to_bytes_le()));
+        // Version 1 has no capabilities.
+        let capabilities = match self.version {
+            1 => Capabilities::NONE,
+            _ => self.capabilities,
+        };
+        Message::ClientHello(self.version, certificate, capabilities)
+    }
+
+    /// Returns the [Message::ClientResume] that resumes [session] instead of starting a new one.
+    /// [hash] is the hash of the tree the client has, so the server only sends what it missed.
+    pub fn resume(&mut self, session: u64, hash: u64) -> Message {
+        self.state = ClientState::Resuming(session);
+        self.started = Instant::now();
+        Message::ClientResume(self.version, session, hash)
+    }
+
+    /// Handles the next message of the server. Returns the answer if there is one.
+    /// After an error the handshake is failed and every further message is an error.
+    pub fn handle(&mut self, message: Message) -> Result<Option<Message>, Error> {
+        let state = std::mem::replace(&mut self.state, ClientState::Failed);
+
+        if self.is_timed_out() {
+            return Err(Error::SimpleError("Handshake: Timed out"));
+        }
+
+        match (state, message) {
+            (ClientState::WaitingForAuth, Message::ServerVersions(min, max, _)) => {
+                match highest_common(self.versions, (min, max)) {
+                    // Said hello with this version already, so the server does not mean it.
+                    Some(version) if version != self.version => {
+                        self.version = version;
+                        self.state = ClientState::WaitingForAuth;
+                        Ok(Some(self.hello_message()))
+                    }
+                    _ => Err(Error::SimpleErrorStr(format!(
+                        "Handshake: No common version, server speaks {min} to {max} and client {} to {}",
+                        self.versions.0, self.versions.1
+                    ))),
+                }
+            }
+            (ClientState::WaitingForAuth, Message::ServerAuth(challenge)) => {
+                let key = match &self.key {
+                    Some(key) => key,
+                    None => {
+                        return Err(Error::SimpleError(
+                            "Handshake: Server wants authentication but no key is set",
+                        ));
+                    }
+                };
+                let response = answer_challenge(key, &challenge, self.binding.as_ref())?;
+
+                self.state = ClientState::WaitingForAccept;
+                Ok(Some(Message::ClientAuth(response)))
+            }
+            // A server may accept a client without checking its key.
+            (
+                ClientState::WaitingForAuth | ClientState::WaitingForAccept,
+                Message::ServerAccept(session, validity, capabilities),
+            ) => {
+                self.negotiated = capabilities.intersection(self.capabilities);
+                self.state = ClientState::Accepted(session, validity);
+                Ok(None)
+            }
+            (
+                ClientState::Resuming(resumed),
+                Message::ServerAccept(session, validity, capabilities),
+            ) => {
+                if resumed != session {
+                    return Err(Error::SimpleError(
+                        "Handshake: Server accepted a different session",
+                    ));
+                }
+                self.negotiated = capabilities.intersection(self.capabilities);
+                self.state = ClientState::Accepted(session, validity);
+                Ok(None)
+            }
+            // The server explains why the connection is refused.
+            (_, Message::ServerLog(log)) => Err(Error::SimpleErrorStr(format!(
+                "Handshake: Server refused connection: {log}"
+            ))),
+            (_, message) => Err(Error::SimpleErrorStr(format!(
+                "Handshake: Unexpected message {:?}",
+                message
+            ))),
+        }
+    }
+
+    /// Returns the session and its validity once the server accepted.
+    pub fn accepted(&self) -> Option<(u64, u16)> {
+        match self.state {
+            ClientState::Accepted(session, validity) => Some((session, validity)),
+            _ => None,
+        }
+    }
+
+    /// The version the client speaks with the server. Is the highest both speak once accepted.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// The capabilities both sides support, once accepted.
+    pub fn negotiated(&self) -> Capabilities {
+        self.negotiated
+    }
+
+    /// Returns [true] if the handshake took longer than its timeout.
+    pub fn is_timed_out(&self) -> bool {
+        self.started.elapsed() > self.timeout
+    }
+}
+
+/// Answers the [Message::ServerAuth] challenge with [key]. Without a [binding] the challenge is
+/// the encrypted nonce, which is decrypted. With one the nonce is signed together with it.
+pub fn answer_challenge(
+    key: &RsaPrivateKey,
+    challenge: &[u8],
+    binding: Option<&Binding>,
+) -> Result<Vec<u8>, Error> {
+    match binding {
+        Some(binding) => Error::from(key.sign_with_rng(
+            &mut thread_rng(),
+            Pss::new::<Sha256>(),
+            &signed_challenge(binding, challenge),
+        )),
+        None => Error::from(key.decrypt(Pkcs1v15Encrypt, challenge)),
+    }
+}
+
+// What the client signs to prove it owns its key.
+fn signed_challenge(binding: &Binding, nonce: &[u8]) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(b"fscp client auth")
+        .chain_update(binding)
+        .chain_update(nonce)
+        .finalize()
+        .into()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{thread, time::Duration};
+
+    use rand::thread_rng;
+    use rsa::{Pkcs1v15Encrypt, RsaPrivateKey, traits::PublicKeyParts};
+
+    use crate::{
+        config,
+        remote::{message::Message, version::Capabilities},
+        security::handshake::{ClientHandshake, ServerHandshake},
+    };
+
+    // Small keys keep the tests fast, the handshake does not care about the size.
+    fn make_key() -> RsaPrivateKey {
+        RsaPrivateKey::new(&mut thread_rng(), 512).unwrap()
+    }
+
+    fn server() -> ServerHandshake {
+        ServerHandshake::new(42, 60, Duration::from_secs(10))
+    }
+
+    #[test]
+    fn with_key() {
+        let key = make_key();
+        let mut server = server();
+        let mut client = ClientHandshake::new(Some(key.clone()), Duration::from_secs(10));
+
+        let auth = server.handle(&client.hello()).unwrap();
+        assert!(matches!(auth, Message::ServerAuth(_)));
+
+        let response = client.handle(auth).unwrap().unwrap();
+        let accept = server.handle(&response).unwrap();
+        assert!(server.is_accepted());
+        assert_eq!(server.public_key().unwrap().n(), key.n());
+
+        assert!(client.handle(accept).unwrap().is_none());
+        assert_eq!(client.accepted(), Some((42, 60)));
+    }
+
+    #[test]
+    fn anonymous() {
+        let mut server = server();
+        let mut client = ClientHandshake::new(None, Duration::from_secs(10));
+
+        let accept = server.handle(&client.hello()).unwrap();
+        assert!(server.is_accepted());
+        assert!(server.public_key().is_none());
+
+        client.handle(accept).unwrap();
+        assert_eq!(client.accepted(), Some((42, 60)));
+    }
+
+    // The client claims a key but does not own it.
+    #[test]
+    fn wrong_key() {
+        let claimed = make_key();
+        let owned = make_key();
+        let mut server = server();
+
+        let hello = Message::ClientHello(
+            config::CURRENT_VERSION,
+            Some((claimed.n().to_bytes_le(), claimed.e().to_bytes_le())),
+            Capabilities::NONE,
+        );
+        let encrypted = match server.handle(&hello).unwrap() {
+            Message::ServerAuth(encrypted) => encrypted,
+            message => panic!("Expected ServerAuth got {:?}", message),
+        };
+
+        let guess = owned
+            .decrypt(Pkcs1v15Encrypt, &encrypted)
+            .unwrap_or(vec![0; config::NONCE_SIZE]);
+        assert!(server.handle(&Message::ClientAuth(guess)).is_err());
+        assert!(!server.is_accepted());
+
+        // A client that has no key cannot answer at all.
+        let mut client = ClientHandshake::new(None, Duration::from_secs(10));
+        client.hello();
+        assert!(client.handle(Message::ServerAuth(encrypted)).is_err());
+    }
+
+    #[test]
+    fn replayed_nonce() {
+        let key = make_key();
+        let mut client = ClientHandshake::new(Some(key), Duration::from_secs(10));
+        let hello = client.hello();
+
+        let mut server1 = server();
+        let response = client
+            .handle(server1.handle(&hello).unwrap())
+            .unwrap()
+            .unwrap();
+        server1.handle(&response).unwrap();
+
+        // The same answer is not accepted twice.
+        assert!(server1.handle(&response).is_err());
+
+        // And a new handshake uses a new nonce.
+        let mut server2 = server();
+        server2.handle(&hello).unwrap();
+        assert!(server2.handle(&response).is_err());
+        assert!(!server2.is_accepted());
+    }
+
+    // Resuming is up to the server, the handshake only checks that the session stays the same.
+    #[test]
+    fn resume() {
+        let mut client = ClientHandshake::new(None, Duration::from_secs(10));
+        assert!(matches!(
+            client.resume(42, 1234),
+            Message::ClientResume(config::CURRENT_VERSION, 42, 1234)
+        ));
+        assert!(
+            client
+                .handle(Message::ServerAccept(42, 60, Capabilities::NONE))
+                .unwrap()
+                .is_none()
+        );
+        assert_eq!(client.accepted(), Some((42, 60)));
+
+        let mut client = ClientHandshake::new(None, Duration::from_secs(10));
+        client.resume(42, 1234);
+        assert!(
+            client
+                .handle(Message::ServerAccept(43, 60, Capabilities::NONE))
+                .is_err()
+        );
+
+        let mut client = ClientHandshake::new(None, Duration::from_secs(10));
+        client.resume(42, 1234);
+        let refused = client.handle(Message::ServerLog("Session is unknown".to_string()));
+        assert!(refused.is_err());
+        assert!(client.accepted().is_none());
+
+        // The server does not resume as part of the normal handshake.
+        let mut server = server();
+        assert!(
+            server
+                .handle(&Message::ClientResume(config::CURRENT_VERSION, 42, 1234))
+                .is_err()
+        );
+    }
+
+    // After a key exchange the client signs the nonce for the channel it is on.
+    #[test]
+    fn bound() {
+        // PSS with SHA-256 needs a larger key than the other tests.
+        let key = RsaPrivateKey::new(&mut thread_rng(), 1024).unwrap();
+        let mut server = server().bind([1; 32]);
+        let mut client =
+            ClientHandshake::new(Some(key.clone()), Duration::from_secs(10)).bind([1; 32]);
+
+        let auth = server.handle(&client.hello()).unwrap();
+        let response = client.handle(auth).unwrap().unwrap();
+        let accept = server.handle(&response).unwrap();
+        assert!(server.is_accepted());
+        client.handle(accept).unwrap();
+        assert_eq!(client.accepted(), Some((42, 60)));
+
+        // A signature for another channel, like one a relay has with the client, does not count.
+        let mut relayed = self::server().bind([1; 32]);
+        let mut client = ClientHandshake::new(Some(key), Duration::from_secs(10)).bind([2; 32]);
+        let auth = relayed.handle(&client.hello()).unwrap();
+        let response = client.handle(auth).unwrap().unwrap();
+        assert!(relayed.handle(&response).is_err());
+        assert!(!relayed.is_accepted());
+    }
+
+    // The client says hello again with the highest version both speak.
+    #[test]
+    fn versions() {
+        // A server that only speaks version 1 and a newer client.
+        let mut server = server().versions(1, 1);
+        let mut client = ClientHandshake::new(None, Duration::from_secs(10))
+            .versions(1, 2)
+            .capabilities(Capabilities::BATCHING);
+        let hello = client.hello();
+        assert!(matches!(
+            hello,
+            Message::ClientHello(2, None, Capabilities::BATCHING)
+        ));
+        let versions = server.handle(&hello).unwrap();
+        assert!(matches!(versions, Message::ServerVersions(1, 1, _)));
+        assert!(!server.is_accepted());
+
+        let hello = client.handle(versions).unwrap().unwrap();
+        assert!(matches!(
+            hello,
+            Message::ClientHello(1, None, Capabilities::NONE)
+        ));
+        client.handle(server.handle(&hello).unwrap()).unwrap();
+        assert_eq!(client.accepted(), Some((42, 60)));
+        assert_eq!((server.version(), client.version()), (1, 1));
+        assert!(client.negotiated().is_empty());
+
+        // Both sides keep the capabilities both support.
+        let both = Capabilities::COMPRESSION.union(Capabilities::BATCHING);
+        let mut server = self::server().capabilities(both);
+        let mut client = ClientHandshake::new(None, Duration::from_secs(10))
+            .capabilities(Capabilities::BATCHING);
+        let accept = server.handle(&client.hello()).unwrap();
+        client.handle(accept).unwrap();
+        assert_eq!(server.version(), config::CURRENT_VERSION);
+        assert_eq!(server.negotiated(), Capabilities::BATCHING);
+        assert_eq!(client.negotiated(), Capabilities::BATCHING);
+
+        // An older client is accepted right away.
+        let mut server = self::server();
+        let mut client = ClientHandshake::new(None, Duration::from_secs(10)).versions(1, 1);
+        let accept = server.handle(&client.hello()).unwrap();
+        client.handle(accept).unwrap();
+        assert_eq!(server.version(), 1);
+        assert!(client.accepted().is_some());
+
+        // Without a common version there is nothing to say hello with.
+        let mut server =
+            self::server().versions(config::CURRENT_VERSION + 1, config::CURRENT_VERSION + 2);
+        let mut client = ClientHandshake::new(None, Duration::from_secs(10));
+        let versions = server.handle(&client.hello()).unwrap();
+        assert!(client.handle(versions).is_err());
+    }
+
+    #[test]
+    fn timeout() {
+        let mut server = ServerHandshake::new(42, 60, Duration::from_millis(10));
+        thread::sleep(Duration::from_millis(20));
+        assert!(server.is_timed_out());
+        assert!(
+            server
+                .handle(&Message::ClientHello(
+                    config::CURRENT_VERSION,
+                    None,
+                    Capabilities::NONE
+                ))
+                .is_err()
+        );
+
+        let mut client = ClientHandshake::new(None, Duration::from_millis(10));
+        client.hello();
+        thread::sleep(Duration::from_millis(20));
+        assert!(
+            client
+                .handle(Message::ServerAccept(42, 60, Capabilities::NONE))
+                .is_err()
+        );
+        assert!(client.accepted().is_none());
+    }
+}
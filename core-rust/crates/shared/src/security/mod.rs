@@ -1 +1,4 @@
+pub mod exchange;
+pub mod fingerprint;
+pub mod handshake;
 pub mod permissions;
@@ -1,4 +1,8 @@
-use std::collections::HashSet;
+use std::{collections::HashSet, fmt::Display, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::errors::Error;
 
 /// Models the Permissions of each node.
 /// Permissions are **transitive**. If node **n** has Permissions **P** all its childrens
@@ -8,7 +12,7 @@ use std::collections::HashSet;
 /// User : Everything other than Admin and Groups
 /// Groups: Only its group. If node has Group a and node Group a and b access is **Granted**.
 /// Public: Only public nodes.
-#[derive(Clone)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum Permissions {
     Admin,
     User(Option<Vec<String>>), // option of possible groups.
@@ -25,6 +29,10 @@ impl Permissions {
             (Permissions::Public, _) => true, // Public can be accesed by all
             (Permissions::User(None), Permissions::User(_)) => true, // Anyone with user
             // permissions can acces a user node without any groups.
+            // A node without groups is a user node any user can access, like one with None.
+            (Permissions::User(Some(groups)), Permissions::User(Some(_))) if groups.is_empty() => {
+                true
+            }
             (Permissions::User(Some(groups)), Permissions::User(Some(possible_groups))) => {
                 let mut set: HashSet<&String> = HashSet::new();
                 // Generate a map of the groups that the accesor has
@@ -39,6 +47,70 @@ impl Permissions {
             (Permissions::User(_), Permissions::Public) => false,
         }
     }
+
+    /// Combines two permissions into ones that can access everything either of them can.
+    /// Groups only add access, so a user with groups can access everything [Permissions::User]
+    /// without groups can.
+    pub fn merge(&self, other: &Permissions) -> Permissions {
+        match (self, other) {
+            (Permissions::Admin, _) | (_, Permissions::Admin) => Permissions::Admin,
+            (Permissions::Public, p) | (p, Permissions::Public) => p.clone(),
+            (Permissions::User(None), Permissions::User(groups))
+            | (Permissions::User(groups), Permissions::User(None)) => {
+                Permissions::User(groups.clone())
+            }
+            (Permissions::User(Some(groups)), Permissions::User(Some(other_groups))) => {
+                let mut merged = groups.clone();
+                for group in other_groups {
+                    if !merged.contains(group) {
+                        merged.push(group.clone());
+                    }
+                }
+                Permissions::User(Some(merged))
+            }
+        }
+    }
+}
+
+/// Formats the permissions as `admin`, `public`, `user` or `user group1,group2`.
+impl Display for Permissions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Permissions::Admin => write!(f, "admin"),
+            Permissions::Public => write!(f, "public"),
+            Permissions::User(None) => write!(f, "user"),
+            Permissions::User(Some(groups)) => write!(f, "user {}", groups.join(",")),
+        }
+    }
+}
+
+/// Parses the format of [Display].
+impl FromStr for Permissions {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (kind, groups) = match s.split_once(char::is_whitespace) {
+            Some((kind, groups)) => (kind, Some(groups.trim())),
+            None => (s, None),
+        };
+
+        match (kind, groups) {
+            ("admin", None) => Ok(Permissions::Admin),
+            ("public", None) => Ok(Permissions::Public),
+            ("user", None) => Ok(Permissions::User(None)),
+            ("user", Some(groups)) => Ok(Permissions::User(Some(
+                groups
+                    .split(',')
+                    .map(|group| group.trim().to_string())
+                    .filter(|group| !group.is_empty())
+                    .collect(),
+            ))),
+            _ => Err(Error::SimpleErrorStr(format!(
+                "Permissions: Cannot parse {s}"
+            ))),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -65,4 +137,49 @@ mod permissions_tests {
             )
         );
     }
+
+    #[test]
+    fn merge_and_parse() {
+        let groups = |groups: &[&str]| {
+            Permissions::User(Some(groups.iter().map(|g| g.to_string()).collect()))
+        };
+
+        assert_eq!(
+            Permissions::Public.merge(&Permissions::User(None)),
+            Permissions::User(None)
+        );
+        assert_eq!(
+            groups(&["a"]).merge(&Permissions::Admin),
+            Permissions::Admin
+        );
+        assert_eq!(
+            groups(&["a", "b"]).merge(&groups(&["b", "c"])),
+            groups(&["a", "b", "c"])
+        );
+
+        // The merged permissions keep the access of both to nodes with and without groups.
+        let merged = Permissions::User(None).merge(&groups(&["a"]));
+        for node in [Permissions::User(None), groups(&[]), groups(&["a", "b"])] {
+            assert!(node.can_be_accessed(&merged), "{node}");
+        }
+        assert!(!groups(&["b"]).can_be_accessed(&merged));
+
+        for permissions in [
+            Permissions::Admin,
+            Permissions::Public,
+            Permissions::User(None),
+            groups(&["a", "b"]),
+        ] {
+            assert_eq!(
+                permissions.to_string().parse::<Permissions>().unwrap(),
+                permissions
+            );
+        }
+        assert_eq!(
+            " user a, b ".parse::<Permissions>().unwrap(),
+            groups(&["a", "b"])
+        );
+        assert!("root".parse::<Permissions>().is_err());
+        assert!("admin all".parse::<Permissions>().is_err());
+    }
 }
@@ -1,5 +1,3 @@
-use std::rc::Rc;
-
 use server::server_interface::ServerInterface;
 use shared::{
     datatypes::{Data, nodes::Node},